@@ -3,38 +3,52 @@
 
 #import bevy_pbr::mesh_functions
 
+//Packed by pack_position and pack_face in material.rs
 struct Vertex {
-    @location(0) position: vec3<f32>,
-    @location(1) normals: vec2<u32>,
-    @location(2) uvs: vec2<u32>,
-    @location(3) index: u32,
+    @location(0) position: u32,
+    @location(1) face: u32,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(1) normals: vec2<u32>,
+    @location(1) direction: u32,
     @location(2) uvs: vec2<f32>,
     @location(3) index: u32,
+    @location(4) light: f32,
+    @location(5) occlusion: f32,
 };
 
 @vertex
 fn vertex(vertex: Vertex) -> VertexOutput {
     var out: VertexOutput;
-    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
-    out.uvs = vec2<f32>(vertex.uvs);
-    if (vertex.uvs.x == u32(0)) {
+    //Sixteenths of a block from the chunk's corner
+    let position = vec3<f32>(
+        f32(vertex.position & 511u),
+        f32((vertex.position >> 9u) & 511u),
+        f32((vertex.position >> 18u) & 511u),
+    ) / 16.0;
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(position, 1.0));
+    out.direction = (vertex.position >> 27u) & 7u;
+
+    //Sixteenths of a block, the texture repeats every block
+    let uvs = vec2<u32>(vertex.face & 511u, (vertex.face >> 9u) & 511u);
+    out.uvs = vec2<f32>(uvs) / 16.0;
+    if (uvs.x == u32(0)) {
         out.uvs.x = 0.001;
     } else {
         out.uvs.x -= 0.001;
     }
-    if (vertex.uvs.y == u32(0)) {
+    if (uvs.y == u32(0)) {
         out.uvs.y = 0.001;
     } else {
         out.uvs.y -= 0.001;
     }
 
-    out.index = vertex.index;
-    out.normals = vertex.normals;
+    out.index = vertex.face >> 26u;
+    //Each level of light missing darkens by a fifth, the brighter of sky and block light wins
+    let level = max((vertex.face >> 18u) & 15u, (vertex.face >> 22u) & 15u);
+    out.light = pow(0.8, 15.0 - f32(level));
+    out.occlusion = 0.55 + 0.15 * f32(vertex.position >> 30u);
     return out;
 }
 
@@ -55,6 +69,7 @@ fn fragment(input: VertexOutput) -> @location(0) vec4<f32> {
 
     //return vec4<f32>(0.5,0.5,0.5,0.5);
 
-    //return vec4<f32>(f32(input.normals[1]) / 3.0);
-    return textureSample(array_texture, texture_sampler, input.uvs, i32(input.index));
+    //return vec4<f32>(f32(input.direction) / 5.0);
+    let color = textureSample(array_texture, texture_sampler, input.uvs, i32(input.index));
+    return vec4<f32>(color.rgb * input.light * input.occlusion, color.a);
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use std::sync::{Arc, RwLock};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use logic_voxels::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// A chunk at the origin and all 26 chunks around it, filled and lit the same way so the borders are meshed like in game
+// The ChunkComps own the chunks, the neighbor links are only weak
+struct World {
+    chunks: Vec<ChunkComp>,
+}
+
+impl World {
+    fn new(registry: &BlockRegistry, mut fill: impl FnMut(&mut StdRng) -> (Block, BlockState)) -> Self {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut chunks = Vec::default();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let mut chunk = Chunk::default();
+                    chunk.pos = IVec3::new(x, y, z);
+                    for x in 0..CHUNK_SIZE {
+                        for y in 0..CHUNK_SIZE {
+                            for z in 0..CHUNK_SIZE {
+                                let (block, state) = fill(&mut rng);
+                                chunk.set_block_with_state(x, y, z, block, state);
+                            }
+                        }
+                    }
+                    chunks.push(ChunkComp::new(Arc::new(RwLock::new(chunk))));
+                }
+            }
+        }
+        for chunk in chunks.iter() {
+            let pos = chunk.read_chunk().pos;
+            chunk.connect_neighbors(pos, |pos| chunks.iter().find(|other| other.read_chunk().pos == pos));
+        }
+
+        let mut world = LinkedChunks::default();
+        for chunk in chunks.iter() {
+            world.add_chunk(chunk.shared());
+        }
+        let positions = chunks.iter().map(|chunk| chunk.read_chunk().pos).collect::<Vec<_>>();
+        for pos in positions.iter() {
+            light_chunk(&mut world, registry, *pos);
+        }
+        for pos in positions.iter() {
+            link_chunk_light(&mut world, registry, *pos);
+        }
+        World { chunks }
+    }
+
+    fn center(&self) -> std::sync::RwLockReadGuard<Chunk> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.read_chunk().pos == IVec3::ZERO)
+            .unwrap()
+            .read_chunk()
+    }
+}
+
+fn worlds(registry: &BlockRegistry) -> Vec<(&'static str, World)> {
+    let [metal, dirt, glass, water] = ["metal", "dirt", "glass", "water"].map(|name| registry.expect(name));
+    let solid = [metal, dirt, glass];
+    vec![
+        //A few blocks floating in the air
+        (
+            "sparse",
+            World::new(registry, |rng| {
+                if rng.gen_bool(0.05) {
+                    (solid[rng.gen_range(0..solid.len())], BlockState::default())
+                } else {
+                    (Block::AIR, BlockState::default())
+                }
+            }),
+        ),
+        //Underground, solid with a few caves
+        (
+            "dense",
+            World::new(registry, |rng| {
+                if rng.gen_bool(0.97) {
+                    (metal, BlockState::default())
+                } else {
+                    (Block::AIR, BlockState::default())
+                }
+            }),
+        ),
+        //Half full of every kind of block, fluids at every level, the worst case for merging
+        (
+            "noisy",
+            World::new(registry, |rng| {
+                if rng.gen_bool(0.5) {
+                    return (Block::AIR, BlockState::default());
+                }
+                match rng.gen_range(0..4) {
+                    0 => (
+                        water,
+                        BlockState::default()
+                            .with_flowing(rng.gen_bool(0.5))
+                            .with_level(rng.gen_range(0..=BlockState::MAX_LEVEL)),
+                    ),
+                    kind => (solid[kind - 1], BlockState::default()),
+                }
+            }),
+        ),
+    ]
+}
+
+fn meshing(c: &mut Criterion) {
+    let registry = BlockRegistry::default();
+    let worlds = worlds(&registry);
+    let mut group = c.benchmark_group("meshing");
+    for (name, world) in worlds.iter() {
+        let chunk = world.center();
+        //Only worth comparing if both draw the same thing
+        assert_eq!(
+            create_reference_mesh_description(&chunk, &registry).coverage(),
+            create_mesh_description(&chunk, &registry).coverage(),
+            "{} chunk meshes differ",
+            name
+        );
+        group.bench_with_input(BenchmarkId::new("reference", name), &chunk, |b, chunk| {
+            b.iter(|| create_reference_mesh_description(chunk, &registry))
+        });
+        group.bench_with_input(BenchmarkId::new("bitmask", name), &chunk, |b, chunk| {
+            b.iter(|| create_mesh_description(chunk, &registry))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, meshing);
+criterion_main!(benches);
@@ -48,6 +48,7 @@ fn main() {
         .add_plugin(ClientChunkPlugin)
         .add_plugin(PhysicsPlugin)
         .add_plugins(DefaultPlugins)
+        .add_plugin(BlockRegistryPlugin)
         //TODO move
         .add_plugin(MaterialPlugin::<CustomMaterial>::default())
         .add_plugin(WorldInspectorPlugin::default())
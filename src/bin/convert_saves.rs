@@ -0,0 +1,17 @@
+use std::path::Path;
+
+use logic_voxels::region::RegionStorage;
+
+// Imports worlds saved as one file per chunk into region files
+// Usage: cargo run --bin convert_saves [saves directory]
+fn main() {
+    let directory = std::env::args().nth(1).unwrap_or_else(|| "saves".to_string());
+    let mut regions = RegionStorage::new(&directory);
+    match regions.import_chunk_files(Path::new(&directory)) {
+        Ok(imported) => println!("Imported {} chunk files from {}", imported, directory),
+        Err(err) => {
+            eprintln!("Failed to import chunk files from {}: {}", directory, err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use bevy::log::LogSettings;
+use bevy_rapier3d::prelude::*;
 
 use logic_voxels::{server_chunks::ServerChunkPlugin, *};
 use renet_visualizer::RenetServerVisualizer;
@@ -18,6 +19,7 @@ fn main() {
             ..Default::default()
         })
         .add_plugins(DefaultPlugins)
+        .add_plugin(BlockRegistryPlugin)
         .add_plugin(WorldInspectorPlugin::default())
         // Cpu limiting (I wish I had a better way to make a headless bevy app low power but I can't find one)
         // Poor headless bevy
@@ -33,6 +35,8 @@ fn main() {
         .init_resource::<CurrentServerMessages>()
         .add_stage_after(CoreStage::PreUpdate, ReadMessages, SystemStage::parallel())
         .add_system_to_stage(ReadMessages, server_recieve_messages)
+        //Falling blocks are simulated on the server, single player gets rapier from the PhysicsPlugin instead
+        .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .add_plugin(ServerChunkPlugin)
         .add_system(server_ping_test)
         .run();
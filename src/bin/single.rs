@@ -57,6 +57,7 @@ fn main() {
         .add_system_set(SystemSet::on_update(ClientState::Connecting).with_system(client_connection_ready))
         .add_plugin(ClientChunkPlugin)
         .add_plugins(DefaultPlugins)
+        .add_plugin(BlockRegistryPlugin)
         .add_plugin(PhysicsPlugin)
         //TODO move
         .add_plugin(MaterialPlugin::<CustomMaterial>::default())
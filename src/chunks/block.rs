@@ -1,41 +1,138 @@
 use crate::prelude::*;
 
-#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub enum Block {
-    #[default]
-    Air,
-    Grass,
-    Dirt,
-    Metal,
-    Glass,
-    Machine,
-}
+// A block is just an id into the BlockRegistry, every property of the block lives in the registry
+// Ids are only stable while the game is running, saves store block names instead
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Block(u16);
 
 impl Block {
-    pub fn is_filled(&self) -> bool {
-        !matches!(self, Block::Air)
-    }
-
-    pub fn get_face_index(&self, direction: Direction) -> u32 {
-        match self {
-            Block::Air => 0,
-            Block::Grass => match direction {
-                Direction::Front | Direction::Back | Direction::Left | Direction::Right => 1,
-                Direction::Top => 0,
-                Direction::Bottom => 2,
-            },
-            Block::Dirt => 2,
-            Block::Metal => match direction {
-                Direction::Front | Direction::Back | Direction::Left | Direction::Right => 3,
-                Direction::Top => 5,
-                Direction::Bottom => 6,
-            },
-            Block::Glass => 4,
-            Block::Machine => match direction {
-                Direction::Front | Direction::Back | Direction::Left | Direction::Right => 7,
-                Direction::Top => 5,
-                Direction::Bottom => 6,
-            },
-        }
+    //The registry always puts air first
+    pub const AIR: Block = Block(0);
+
+    pub fn from_id(id: u16) -> Self {
+        Block(id)
+    }
+
+    pub fn id(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_air(&self) -> bool {
+        *self == Block::AIR
+    }
+}
+
+// Small per block value stored next to the block id, what the bits mean depends on the block
+// Layout of the shared fields:
+// bits 0-2 facing (Direction index), bits 3-6 power level, bits 7-11 tick timer, bits 12-13 gate delay,
+// bits 14-19 which faces a controller is powering, bits 20-22 fluid level, bit 23 flowing fluid,
+// everything above is free for block specific data
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlockState(u32);
+
+impl BlockState {
+    const FACING: (u32, u32) = (0, 3);
+    const POWER: (u32, u32) = (3, 4);
+    const TIMER: (u32, u32) = (7, 5);
+    const DELAY: (u32, u32) = (12, 2);
+    const OUTPUTS: u32 = 14;
+    const LEVEL: (u32, u32) = (20, 3);
+    const FLOWING: u32 = 23;
+    pub const MAX_POWER: u8 = 15;
+    pub const MAX_TIMER: u32 = 31;
+    pub const MAX_DELAY: u32 = 4;
+    pub const MAX_LEVEL: u8 = 7;
+
+    pub fn from_bits(bits: u32) -> Self {
+        BlockState(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    //Reads `width` bits starting at `offset`
+    pub fn field(&self, offset: u32, width: u32) -> u32 {
+        (self.0 >> offset) & ((1 << width) - 1)
+    }
+
+    pub fn with_field(self, offset: u32, width: u32, value: u32) -> Self {
+        let mask = ((1 << width) - 1) << offset;
+        BlockState((self.0 & !mask) | ((value << offset) & mask))
+    }
+
+    pub fn facing(&self) -> Direction {
+        let (offset, width) = Self::FACING;
+        //Out of range values can only come from corrupt data, treat them as unrotated
+        Direction::ALL
+            .get(self.field(offset, width) as usize)
+            .copied()
+            .unwrap_or(Direction::Front)
+    }
+
+    pub fn with_facing(self, facing: Direction) -> Self {
+        let (offset, width) = Self::FACING;
+        self.with_field(offset, width, facing as u32)
+    }
+
+    pub fn power(&self) -> u8 {
+        let (offset, width) = Self::POWER;
+        self.field(offset, width) as u8
+    }
+
+    pub fn with_power(self, power: u8) -> Self {
+        let (offset, width) = Self::POWER;
+        self.with_field(offset, width, power.min(Self::MAX_POWER) as u32)
+    }
+
+    //Ticks left until a block like a pressed button changes again
+    pub fn timer(&self) -> u32 {
+        let (offset, width) = Self::TIMER;
+        self.field(offset, width)
+    }
+
+    pub fn with_timer(self, ticks: u32) -> Self {
+        let (offset, width) = Self::TIMER;
+        self.with_field(offset, width, ticks.min(Self::MAX_TIMER))
+    }
+
+    //Ticks a gate waits before its output follows its inputs, stored minus one so the default state is one tick
+    pub fn delay(&self) -> u32 {
+        let (offset, width) = Self::DELAY;
+        self.field(offset, width) + 1
+    }
+
+    pub fn with_delay(self, ticks: u32) -> Self {
+        let (offset, width) = Self::DELAY;
+        self.with_field(offset, width, ticks.clamp(1, Self::MAX_DELAY) - 1)
+    }
+
+    //Faces a controller block drives at full power, indexed by world direction
+    pub fn output(&self, face: Direction) -> bool {
+        self.field(Self::OUTPUTS + face as u32, 1) == 1
+    }
+
+    pub fn with_output(self, face: Direction, on: bool) -> Self {
+        self.with_field(Self::OUTPUTS + face as u32, 1, on as u32)
+    }
+
+    //How full a fluid block is, stored as MAX_LEVEL minus the level so the default state is full
+    pub fn level(&self) -> u8 {
+        let (offset, width) = Self::LEVEL;
+        Self::MAX_LEVEL - self.field(offset, width) as u8
+    }
+
+    pub fn with_level(self, level: u8) -> Self {
+        let (offset, width) = Self::LEVEL;
+        self.with_field(offset, width, (Self::MAX_LEVEL - level.min(Self::MAX_LEVEL)) as u32)
+    }
+
+    //Flowing fluid needs a source to keep it there, the default state is a source
+    pub fn flowing(&self) -> bool {
+        self.field(Self::FLOWING, 1) == 1
+    }
+
+    pub fn with_flowing(self, flowing: bool) -> Self {
+        self.with_field(Self::FLOWING, 1, flowing as u32)
     }
 }
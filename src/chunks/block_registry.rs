@@ -0,0 +1,442 @@
+use std::sync::Arc;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+
+use crate::prelude::*;
+
+// Blocks are defined in assets/default.blocks.ron instead of in code
+// The file is compiled in so the registry exists before the asset server has loaded anything,
+// the copy on disk is then loaded as an asset and merged in whenever it changes
+const DEFAULT_BLOCKS: &str = include_str!("../../assets/default.blocks.ron");
+const DEFAULT_BLOCKS_PATH: &str = "default.blocks.ron";
+
+pub struct BlockRegistryPlugin;
+
+impl Plugin for BlockRegistryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<BlockDefinitions>()
+            .init_asset_loader::<BlockDefinitionsLoader>()
+            .init_resource::<BlockRegistry>()
+            .add_startup_system(load_block_definitions)
+            .add_system(reload_block_registry);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum FaceTextures {
+    All(u32),
+    Sides {
+        side: u32,
+        top: u32,
+        bottom: u32,
+    },
+    Each {
+        front: u32,
+        back: u32,
+        left: u32,
+        right: u32,
+        top: u32,
+        bottom: u32,
+    },
+}
+
+impl FaceTextures {
+    pub fn get(&self, direction: Direction) -> u32 {
+        match *self {
+            FaceTextures::All(texture) => texture,
+            FaceTextures::Sides { side, top, bottom } => match direction {
+                Direction::Front | Direction::Back | Direction::Left | Direction::Right => side,
+                Direction::Top => top,
+                Direction::Bottom => bottom,
+            },
+            FaceTextures::Each {
+                front,
+                back,
+                left,
+                right,
+                top,
+                bottom,
+            } => match direction {
+                Direction::Front => front,
+                Direction::Back => back,
+                Direction::Left => left,
+                Direction::Right => right,
+                Direction::Top => top,
+                Direction::Bottom => bottom,
+            },
+        }
+    }
+}
+
+// One box of a block model in sixteenths of a block, from its lowest corner to its highest
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ModelBox {
+    pub from: [u8; 3],
+    pub to: [u8; 3],
+    //Layers of the chunk array texture for this box, the block's own textures if missing
+    #[serde(default)]
+    pub textures: Option<FaceTextures>,
+}
+
+impl ModelBox {
+    //Corners in blocks after turning the box with the block, unrotated models face Front
+    pub fn rotated(&self, facing: Direction) -> (Vec3, Vec3) {
+        let center = Vec3::splat(0.5);
+        let [from, to] = [self.from, self.to].map(|corner| {
+            let corner = Vec3::new(corner[0] as f32, corner[1] as f32, corner[2] as f32) / 16.0;
+            //Quarter turns only, rounding to sixteenths takes out the float error
+            ((facing.rotation() * (corner - center) + center) * 16.0).round() / 16.0
+        });
+        (from.min(to), from.max(to))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockDefinition {
+    pub name: String,
+    //Layers of the chunk array texture
+    pub textures: FaceTextures,
+    //Used instead of textures while the block has power, like lit wires
+    #[serde(default)]
+    pub powered_textures: Option<FaceTextures>,
+    //Solid blocks get colliders
+    #[serde(default = "default_true")]
+    pub solid: bool,
+    //Transparent blocks don't hide the faces of blocks behind them
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    //Rotatable blocks turn their front texture to the facing in their state
+    #[serde(default)]
+    pub rotatable: bool,
+    //Name of the machine placing this block spawns on the server
+    #[serde(default)]
+    pub machine: Option<String>,
+    //What part the block plays in signal circuits
+    #[serde(default)]
+    pub logic: Option<LogicKind>,
+    //Fluids spread on their own, they should also be not solid and transparent
+    #[serde(default)]
+    pub fluid: Option<Fluid>,
+    //Block light the block gives off, up to MAX_LIGHT
+    #[serde(default)]
+    pub light: u8,
+    //Anchors hold up whatever is built on them, other blocks fall once nothing connects them to one
+    #[serde(default)]
+    pub anchor: bool,
+    //Boxes drawn instead of a full cube, like slabs and stairs, rotatable models turn with their facing
+    //Models never hide the faces of their neighbors and are meshed a block at a time
+    #[serde(default)]
+    pub model: Vec<ModelBox>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_hardness() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "3c8f4e0b-52b1-4d4f-9a61-0f3b7f2ad8c4"]
+pub struct BlockDefinitions {
+    pub blocks: Vec<BlockDefinition>,
+}
+
+#[derive(Default)]
+pub struct BlockDefinitionsLoader;
+
+impl AssetLoader for BlockDefinitionsLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let definitions: BlockDefinitions = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(definitions));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["blocks.ron"]
+    }
+}
+
+// Cheap to clone so mesh generation tasks can take their own copy
+// Ids are indices into the definitions, once a name has an id it keeps it until the game closes
+#[derive(Clone)]
+pub struct BlockRegistry {
+    definitions: Arc<Vec<BlockDefinition>>,
+    ids: Arc<HashMap<String, Block>>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        let definitions: BlockDefinitions =
+            ron::from_str(DEFAULT_BLOCKS).expect("Built in block definitions should parse");
+        BlockRegistry::from_definitions(definitions.blocks).expect("Built in block definitions should be valid")
+    }
+}
+
+impl BlockRegistry {
+    pub fn from_definitions(definitions: Vec<BlockDefinition>) -> Result<Self, String> {
+        match definitions.first() {
+            Some(air) if air.name == "air" => {}
+            _ => return Err("the first block must be air".to_string()),
+        }
+        if definitions.len() > u16::MAX as usize {
+            return Err(format!("too many blocks, {} is the limit", u16::MAX));
+        }
+        let mut ids = HashMap::default();
+        for (id, definition) in definitions.iter().enumerate() {
+            if ids.insert(definition.name.clone(), Block::from_id(id as u16)).is_some() {
+                return Err(format!("block {:?} is defined twice", definition.name));
+            }
+            for model_box in definition.model.iter() {
+                if (0..3).any(|axis| model_box.from[axis] >= model_box.to[axis] || model_box.to[axis] > 16) {
+                    return Err(format!(
+                        "block {:?} has a model box that is empty or bigger than a block",
+                        definition.name
+                    ));
+                }
+            }
+            //Layers past the limit would spill out of the packed vertex and draw some other texture
+            let textures = std::iter::once(definition.textures)
+                .chain(definition.powered_textures)
+                .chain(definition.model.iter().filter_map(|model_box| model_box.textures));
+            for textures in textures {
+                if let Some(layer) = Direction::ALL.map(|dir| textures.get(dir)).into_iter().max() {
+                    if layer >= MAX_TEXTURE_LAYERS {
+                        return Err(format!(
+                            "block {:?} uses texture layer {}, layers go up to {}",
+                            definition.name,
+                            layer,
+                            MAX_TEXTURE_LAYERS - 1
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(BlockRegistry {
+            definitions: Arc::new(definitions),
+            ids: Arc::new(ids),
+        })
+    }
+
+    // New definitions replace old ones with the same name and unknown names are added to the end,
+    // blocks missing from the new definitions keep their old definition since chunks may still hold them
+    pub fn merge(&self, definitions: Vec<BlockDefinition>) -> Result<Self, String> {
+        let mut merged = self.definitions.as_ref().clone();
+        for definition in definitions {
+            match self.ids.get(&definition.name) {
+                Some(block) => merged[block.id() as usize] = definition,
+                None => merged.push(definition),
+            }
+        }
+        BlockRegistry::from_definitions(merged)
+    }
+
+    pub fn get(&self, block: Block) -> &BlockDefinition {
+        //Unknown ids can only come from a peer with a different registry, treat them as air
+        self.definitions
+            .get(block.id() as usize)
+            .unwrap_or(&self.definitions[Block::AIR.id() as usize])
+    }
+
+    pub fn id(&self, name: &str) -> Option<Block> {
+        self.ids.get(name).copied()
+    }
+
+    // For blocks the game itself relies on, names are never removed so this can only fail
+    // if the built in definitions are missing the block
+    pub fn expect(&self, name: &str) -> Block {
+        self.id(name)
+            .unwrap_or_else(|| panic!("Block {:?} is missing from the block definitions", name))
+    }
+
+    pub fn name(&self, block: Block) -> &str {
+        &self.get(block).name
+    }
+
+    pub fn contains(&self, block: Block) -> bool {
+        (block.id() as usize) < self.definitions.len()
+    }
+
+    //In id order, what clients translate the server's ids with
+    pub fn names(&self) -> Vec<String> {
+        self.definitions
+            .iter()
+            .map(|definition| definition.name.clone())
+            .collect()
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = Block> {
+        (0..self.definitions.len() as u16).map(Block::from_id)
+    }
+
+    pub fn face_texture(&self, block: Block, state: BlockState, direction: Direction) -> u32 {
+        let definition = self.get(block);
+        let face = if definition.rotatable {
+            direction.relative_to(state.facing())
+        } else {
+            direction
+        };
+        match definition.powered_textures {
+            Some(powered) if state.power() > 0 => powered.get(face),
+            _ => definition.textures.get(face),
+        }
+    }
+
+    pub fn logic(&self, block: Block) -> Option<LogicKind> {
+        self.get(block).logic
+    }
+
+    pub fn fluid(&self, block: Block) -> Option<Fluid> {
+        self.get(block).fluid
+    }
+
+    pub fn light(&self, block: Block) -> u8 {
+        self.get(block).light.min(MAX_LIGHT)
+    }
+
+    //Fraction of the block a fluid fills, 1.0 for everything else
+    pub fn fluid_height(&self, block: Block, state: BlockState) -> f32 {
+        match self.fluid(block) {
+            Some(_) if state.flowing() && state.level() == BlockState::MAX_LEVEL => 1.0,
+            Some(_) => state.level() as f32 / (BlockState::MAX_LEVEL + 1) as f32,
+            None => 1.0,
+        }
+    }
+
+    //Machines can't come along in a falling body, so blocks with one hold their structure up too
+    pub fn is_anchor(&self, block: Block) -> bool {
+        let definition = self.get(block);
+        definition.anchor || definition.machine.is_some()
+    }
+
+    pub fn is_solid(&self, block: Block) -> bool {
+        !block.is_air() && self.get(block).solid
+    }
+
+    //Empty for blocks that are full cubes
+    pub fn model(&self, block: Block) -> &[ModelBox] {
+        &self.get(block).model
+    }
+
+    //Whether the block hides the faces of its neighbors
+    pub fn is_opaque(&self, block: Block) -> bool {
+        let definition = self.get(block);
+        !block.is_air() && !definition.transparent && definition.model.is_empty()
+    }
+}
+
+pub struct BlockDefinitionsHandle(pub Handle<BlockDefinitions>);
+
+fn load_block_definitions(mut commands: Commands, server: Res<AssetServer>) {
+    commands.insert_resource(BlockDefinitionsHandle(server.load(DEFAULT_BLOCKS_PATH)));
+}
+
+fn reload_block_registry(
+    mut events: EventReader<AssetEvent<BlockDefinitions>>,
+    definitions: Res<Assets<BlockDefinitions>>,
+    mut registry: ResMut<BlockRegistry>,
+    chunks: Query<&ChunkComp>,
+) {
+    for event in events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        let definitions = match definitions.get(handle) {
+            Some(definitions) => definitions,
+            None => continue,
+        };
+        match registry.merge(definitions.blocks.clone()) {
+            Ok(merged) => {
+                info!("Loaded {} block definitions", definitions.blocks.len());
+                *registry = merged;
+                //Textures or transparency might have changed so every mesh is stale
+                for chunk in &chunks {
+                    chunk.write_dirty(true);
+                }
+            }
+            Err(err) => error!("Ignoring invalid block definitions: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The built in blocks with one more on the end
+    fn with_block(definition: &str) -> Result<BlockRegistry, String> {
+        let mut definitions: BlockDefinitions = ron::from_str(DEFAULT_BLOCKS).unwrap();
+        definitions.blocks.push(ron::from_str(definition).unwrap());
+        BlockRegistry::from_definitions(definitions.blocks)
+    }
+
+    #[test]
+    fn texture_layers_must_fit_in_a_vertex() {
+        let last = MAX_TEXTURE_LAYERS - 1;
+        assert!(with_block(&format!("(name: \"ok\", textures: All({}))", last)).is_ok());
+        for definition in [
+            format!("(name: \"bad\", textures: All({}))", MAX_TEXTURE_LAYERS),
+            format!(
+                "(name: \"bad\", textures: All(0), powered_textures: Some(Sides(side: 0, top: {}, bottom: 0)))",
+                MAX_TEXTURE_LAYERS
+            ),
+            format!(
+                "(name: \"bad\", textures: All(0), model: [(from: (0, 0, 0), to: (16, 8, 16), textures: Some(All({})))])",
+                MAX_TEXTURE_LAYERS
+            ),
+        ] {
+            assert!(with_block(&definition).err().unwrap().contains("texture layer"));
+        }
+    }
+
+    #[test]
+    fn model_boxes_must_fit_in_a_block() {
+        let model = |model_box: &str| format!("(name: \"model\", textures: All(0), model: [{}])", model_box);
+        assert!(with_block(&model("(from: (0, 0, 0), to: (16, 16, 16))")).is_ok());
+        for model_box in [
+            "(from: (0, 8, 0), to: (16, 8, 16))",
+            "(from: (4, 0, 0), to: (2, 16, 16))",
+            "(from: (0, 0, 0), to: (16, 17, 16))",
+        ] {
+            assert!(with_block(&model(model_box)).err().unwrap().contains("model box"));
+        }
+    }
+
+    #[test]
+    fn stairs_step_turns_with_their_facing() {
+        let step = ModelBox {
+            from: [8, 8, 0],
+            to: [16, 16, 16],
+            textures: None,
+        };
+        for (facing, from, to) in [
+            (Direction::Front, [0.5, 0.5, 0.0], [1.0, 1.0, 1.0]),
+            (Direction::Back, [0.0, 0.5, 0.0], [0.5, 1.0, 1.0]),
+            (Direction::Left, [0.0, 0.5, 0.5], [1.0, 1.0, 1.0]),
+            (Direction::Right, [0.0, 0.5, 0.0], [1.0, 1.0, 0.5]),
+            (Direction::Top, [0.0, 0.5, 0.0], [0.5, 1.0, 1.0]),
+            (Direction::Bottom, [0.5, 0.0, 0.0], [1.0, 0.5, 1.0]),
+        ] {
+            assert_eq!(
+                step.rotated(facing),
+                (Vec3::from(from), Vec3::from(to)),
+                "facing {:?}",
+                facing
+            );
+        }
+    }
+}
@@ -8,74 +8,6 @@ use crate::prelude::*;
 pub struct ChunkComp {
     chunk: Arc<RwLock<Chunk>>,
     associated_entities: HashMap<IVec3, Entity>,
-    buffered_writes: Vec<BufferedWrite>,
-}
-
-#[derive(Component, Clone, Serialize, Deserialize)]
-pub struct BlockController {
-    block: Block,
-}
-
-#[derive(Component, Clone, Serialize, Deserialize)]
-pub enum MachineType {
-    Furnace,
-}
-
-#[derive(Component, Clone, Serialize, Deserialize)]
-pub struct Furnace {
-    heat: f32,
-}
-
-#[derive(Bundle, Serialize, Deserialize)]
-pub struct FurnaceBundle {
-    machine: MachineType,
-    furnace: Furnace,
-    controller: BlockController,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct SerializedMachines {
-    furnaces: Vec<FurnaceBundle>,
-}
-
-impl MachineType {
-    fn spawn_machine(&self, commands: &mut Commands) -> Entity {
-        match self {
-            MachineType::Furnace => commands
-                .spawn_bundle(FurnaceBundle {
-                    machine: self.clone(),
-                    furnace: Furnace { heat: 100.0 },
-                    controller: BlockController { block: Block::Machine },
-                })
-                .id(),
-        }
-    }
-    fn serialize_all_machines(
-        //Usually from a chunks associated entities
-        entities: Vec<Entity>,
-        //All the types in the bundle
-        //TODO it would be nice to not need a query for literally every machine type but hmm
-        furnace_query: &Query<(&MachineType, &Furnace, &BlockController)>,
-    ) -> SerializedMachines {
-        let mut to_return = SerializedMachines {
-            furnaces: Vec::default(),
-        };
-        for entity in entities {
-            if let Ok((machine, furnace, controller)) = furnace_query.get(entity) {
-                to_return.furnaces.push(FurnaceBundle {
-                    machine: machine.clone(),
-                    furnace: furnace.clone(),
-                    controller: controller.clone(),
-                });
-            }
-        }
-        to_return
-    }
-}
-
-pub struct BufferedWrite {
-    offset: IVec3,
-    to_write: Block,
 }
 
 impl ChunkComp {
@@ -83,34 +15,75 @@ impl ChunkComp {
         ChunkComp {
             chunk,
             associated_entities: HashMap::default(),
-            buffered_writes: Vec::default(),
         }
     }
     //These functions prevent deadlocks, in reality all that matters is writes finish so a pub read, private write would be nice
     //TODO send to all clients if server write?
-    pub fn write_block(&self, index: IVec3, block: Block) {
+    pub fn write_block(&self, index: IVec3, block: Block, registry: &BlockRegistry) {
         //There's really no point in bounds checking this index, a logic error trying to write the wrong block should panic
         //Maybe one day there will be a use for a varient that returns a recoverable error
-        self.write_block_xyz(index.x as usize, index.y as usize, index.z as usize, block);
+        self.write_block_xyz(index.x as usize, index.y as usize, index.z as usize, block, registry);
     }
 
-    pub fn buffered_write(&mut self, index: IVec3, block: Block) {
-        self.buffered_writes.push(BufferedWrite {
-            offset: index,
-            to_write: block,
-        });
+    //Entities that belong to a block in this chunk, like machines, keyed by the block offset
+    pub fn associate_entity(&mut self, offset: IVec3, entity: Entity) -> Option<Entity> {
+        self.associated_entities.insert(offset, entity)
     }
 
-    pub fn apply_buffered_writes(&mut self) {
-        for write in self.buffered_writes.iter() {
-            self.write_block(write.offset, write.to_write);
-        }
-        self.buffered_writes.clear();
+    pub fn remove_associated_entity(&mut self, offset: IVec3) -> Option<Entity> {
+        self.associated_entities.remove(&offset)
+    }
+
+    pub fn associated_entity(&self, offset: IVec3) -> Option<Entity> {
+        self.associated_entities.get(&offset).copied()
     }
 
-    pub fn write_block_xyz(&self, x: usize, y: usize, z: usize, block: Block) {
+    pub fn has_associated_entity(&self, offset: IVec3) -> bool {
+        self.associated_entities.contains_key(&offset)
+    }
+
+    pub fn associated_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.associated_entities.values().copied()
+    }
+
+    pub fn write_block_with_state(&self, index: IVec3, block: Block, state: BlockState, registry: &BlockRegistry) {
+        self.write_block_with_state_xyz(
+            index.x as usize,
+            index.y as usize,
+            index.z as usize,
+            block,
+            state,
+            registry,
+        );
+    }
+
+    pub fn write_block_xyz(&self, x: usize, y: usize, z: usize, block: Block, registry: &BlockRegistry) {
+        self.write_block_with_state_xyz(x, y, z, block, BlockState::default(), registry);
+    }
+
+    //Lit chunks update their light and their neighbors' as part of the write
+    pub fn write_block_with_state_xyz(
+        &self,
+        x: usize,
+        y: usize,
+        z: usize,
+        block: Block,
+        state: BlockState,
+        registry: &BlockRegistry,
+    ) {
         //let _span = info_span!("Write Block", name = "Write Block").entered();
-        self.chunk.write().unwrap().cubes[x][y][z] = block;
+        let old = self.chunk.read().unwrap().block(x, y, z);
+        self.chunk.write().unwrap().set_block_with_state(x, y, z, block, state);
+        let (lit, chunk_pos) = {
+            let chunk = self.chunk.read().unwrap();
+            (chunk.is_lit(), chunk.pos)
+        };
+        if lit && changes_light(registry, old, block) {
+            let mut world = LinkedChunks::default();
+            world.add_chunk(self.chunk.clone());
+            let pos = chunk_pos * CHUNK_SIZE as i32 + IVec3::new(x as i32, y as i32, z as i32);
+            update_light(&mut world, registry, pos);
+        }
         //Really only need to dirty if block is different but eh
         if !self.chunk.read().unwrap().dirty {
             self.write_dirty(true);
@@ -151,37 +124,27 @@ impl ChunkComp {
         self.chunk.write().unwrap().neighbors[dir] = neighbor.as_neighbor();
     }
 
-    //Times for updating dirt with 16 chunksize and 20 world size, all chunks updated (8000)
-    //~16-18 ms to pre-read neighbors
-    //~20-22 ms to read as you go
-    //There probably is a way to only do one loop here while also getting and holding the read lock but borrow checker angry
-    #[allow(clippy::needless_range_loop)]
-    pub fn apply_function_to_blocks<F>(&mut self, mut function: F)
-    where
-        F: FnMut(&Block, [Option<Block>; 6]) -> Option<Block>,
-    {
-        let chunk = self.read_chunk();
-        let mut neighbors = [[[[None; 6]; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE];
-        for x in 0..CHUNK_SIZE {
-            for y in 0..CHUNK_SIZE {
-                for z in 0..CHUNK_SIZE {
-                    //TODO this can be better, a lot of repeated neighbor getting
-                    //let neighbors = self.read_chunk().get_block_neighbors(x, y, z);
-                    neighbors[x][y][z] = chunk.get_block_neighbors(x, y, z);
-                }
+    //Links this chunk and every loaded neighbor both ways, neighbors are dirtied because their borders changed
+    pub fn connect_neighbors<'a>(&self, pos: IVec3, mut get_neighbor: impl FnMut(IVec3) -> Option<&'a ChunkComp>) {
+        for dir in Direction::ALL {
+            if let Some(neighbor) = get_neighbor(pos + dir.offset()) {
+                self.set_neighbor(dir, neighbor);
+                neighbor.set_neighbor(dir.opposite(), self);
+                neighbor.write_dirty(true);
             }
         }
-        drop(chunk);
-        for x in 0..CHUNK_SIZE {
-            for y in 0..CHUNK_SIZE {
-                for z in 0..CHUNK_SIZE {
-                    if let Some(block) = function(&self.read_block_xyz(x, y, z), neighbors[x][y][z]) {
-                        //TODO perf check buffered versus normal write
-                        //self.write_block(IVec3::new(x as i32, y as i32, z as i32), block)
-                        self.buffered_write(IVec3::new(x as i32, y as i32, z as i32), block)
-                    }
-                }
+    }
+
+    //Call before dropping a chunk so neighbors don't keep pointing at it and remesh their now exposed borders
+    pub fn disconnect_neighbors(&self) {
+        for dir in Direction::ALL {
+            let neighbor = self.chunk.read().unwrap().neighbors[dir].upgrade();
+            if let Some(neighbor) = neighbor {
+                let mut neighbor = neighbor.write().unwrap();
+                neighbor.neighbors[dir.opposite()] = Weak::new();
+                neighbor.dirty = true;
             }
+            self.chunk.write().unwrap().neighbors[dir] = Weak::new();
         }
     }
 
@@ -191,12 +154,23 @@ impl ChunkComp {
 
     pub fn read_block_xyz(&self, x: usize, y: usize, z: usize) -> Block {
         //returns a copy of the block
-        self.chunk.read().unwrap().cubes[x][y][z]
+        self.chunk.read().unwrap().block(x, y, z)
     }
 
     pub fn read_block(&self, index: IVec3) -> Block {
         //returns a copy of the block
-        self.chunk.read().unwrap().cubes[index.x as usize][index.y as usize][index.z as usize]
+        self.read_block_xyz(index.x as usize, index.y as usize, index.z as usize)
+    }
+
+    pub fn schedule_tick(&self, index: IVec3, delay: u64) {
+        self.chunk.write().unwrap().ticks_mut().schedule(index, delay);
+    }
+
+    pub fn read_state(&self, index: IVec3) -> BlockState {
+        self.chunk
+            .read()
+            .unwrap()
+            .state(index.x as usize, index.y as usize, index.z as usize)
     }
 
     pub fn read_dirty(&self) -> bool {
@@ -206,16 +180,27 @@ impl ChunkComp {
     pub fn read_chunk(&self) -> std::sync::RwLockReadGuard<Chunk> {
         self.chunk.read().unwrap()
     }
+
+    //For walking the neighbor links with LinkedChunks
+    pub fn shared(&self) -> Arc<RwLock<Chunk>> {
+        self.chunk.clone()
+    }
 }
 
-type ChunkData = [[[Block; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE];
 pub type CompressedChunk = Vec<u8>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Chunk {
     pub pos: IVec3,
-    pub cubes: ChunkData,
+    //Private so everything has to go through the accessors, the storage format is free to change
+    blocks: BlockStorage,
     pub dirty: bool,
+    //Only the server runs ticks, it saves them itself so they aren't sent to clients
+    #[serde(skip)]
+    ticks: TickQueue,
+    //None until the client lights the chunk, see light.rs
+    #[serde(skip)]
+    light: Option<ChunkLight>,
     //Cant be pub because then you could write them and cause weird deadlocks
     #[serde(skip)]
     neighbors: [Weak<RwLock<Chunk>>; 6],
@@ -225,8 +210,10 @@ impl Default for Chunk {
     fn default() -> Chunk {
         Chunk {
             pos: IVec3::ZERO,
-            cubes: [[[Block::Air; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE],
+            blocks: BlockStorage::default(),
             dirty: false,
+            ticks: TickQueue::default(),
+            light: None,
             neighbors: [
                 Weak::new(),
                 Weak::new(),
@@ -246,11 +233,103 @@ impl Chunk {
     }
 
     pub fn compress(&self) -> CompressedChunk {
-        let message = bincode::serialize(self).unwrap();
+        //Stale palette entries would just be wasted bytes on disk and over the network
+        let message = if self.blocks.is_uniform() {
+            bincode::serialize(self).unwrap()
+        } else {
+            let mut compacted = self.clone();
+            compacted.blocks.compact();
+            bincode::serialize(&compacted).unwrap()
+        };
         //Lib doesn't document max compression value but the linux man for the same underlying lib says 12 is max
         compress(&message, Some(CompressionMode::HIGHCOMPRESSION(12)), true).unwrap()
     }
 
+    pub fn from_storage(pos: IVec3, blocks: BlockStorage, ticks: TickQueue) -> Self {
+        Chunk {
+            pos,
+            blocks,
+            ticks,
+            ..default()
+        }
+    }
+
+    pub fn block(&self, x: usize, y: usize, z: usize) -> Block {
+        self.blocks.get(x, y, z).0
+    }
+
+    pub fn state(&self, x: usize, y: usize, z: usize) -> BlockState {
+        self.blocks.get(x, y, z).1
+    }
+
+    pub fn block_with_state(&self, x: usize, y: usize, z: usize) -> (Block, BlockState) {
+        self.blocks.get(x, y, z)
+    }
+
+    //Placing a block resets its state
+    pub fn set_block(&mut self, x: usize, y: usize, z: usize, block: Block) {
+        self.blocks.set(x, y, z, (block, BlockState::default()));
+    }
+
+    pub fn set_block_with_state(&mut self, x: usize, y: usize, z: usize, block: Block, state: BlockState) {
+        self.blocks.set(x, y, z, (block, state));
+    }
+
+    pub fn is_lit(&self) -> bool {
+        self.light.is_some()
+    }
+
+    //Chunks that were never lit are fully lit
+    pub fn light(&self, x: usize, y: usize, z: usize) -> Light {
+        self.light.as_ref().map_or(Light::FULL, |light| light.get(x, y, z))
+    }
+
+    //Does nothing until the chunk has been lit with clear_light
+    pub fn set_light(&mut self, x: usize, y: usize, z: usize, light: Light) {
+        if let Some(chunk_light) = &mut self.light {
+            chunk_light.set(x, y, z, light);
+        }
+    }
+
+    pub fn clear_light(&mut self) {
+        self.light = Some(ChunkLight::default());
+    }
+
+    pub fn storage(&self) -> &BlockStorage {
+        &self.blocks
+    }
+
+    //Swaps every block for another one, like when the ids came from a registry with a different order
+    pub fn map_blocks(&mut self, mut function: impl FnMut(Block) -> Block) {
+        self.blocks = self.blocks.map_palette(|(block, state)| (function(block), state));
+    }
+
+    //The chunk offset chunks away, found by walking the links in the same order read_cell does
+    pub fn linked(&self, offset: IVec3) -> Option<Arc<RwLock<Chunk>>> {
+        let mut steps = [
+            (offset.x, Direction::Front, Direction::Back),
+            (offset.z, Direction::Left, Direction::Right),
+            (offset.y, Direction::Top, Direction::Bottom),
+        ]
+        .into_iter()
+        .filter(|(step, _, _)| *step != 0)
+        .map(|(step, positive, negative)| if step > 0 { positive } else { negative });
+        let first = self.neighbor(steps.next()?)?;
+        steps.try_fold(first, |chunk, dir| chunk.read().unwrap().neighbor(dir))
+    }
+
+    pub fn ticks(&self) -> &TickQueue {
+        &self.ticks
+    }
+
+    pub fn ticks_mut(&mut self) -> &mut TickQueue {
+        &mut self.ticks
+    }
+
+    pub fn neighbor(&self, dir: Direction) -> Option<Arc<RwLock<Chunk>>> {
+        self.neighbors[dir].upgrade()
+    }
+
     //FIXME this is so janky
     pub fn world_to_chunk(pos: Vec3) -> (IVec3, IVec3) {
         let mut i_pos = pos.as_ivec3();
@@ -290,41 +369,45 @@ impl Chunk {
     }
 
     pub fn get_block(&self, x: isize, y: isize, z: isize) -> Option<Block> {
-        if Self::index_inbounds(x) && Self::index_inbounds(y) && Self::index_inbounds(z) {
-            Some(self.cubes[x as usize][y as usize][z as usize])
-        } else if x < 0 {
-            assert!(Self::index_inbounds(y) && Self::index_inbounds(z));
-            self.neighbors[Direction::Back]
-                .upgrade()
-                .map(|back| back.read().unwrap().cubes[CHUNK_SIZE - 1][y as usize][z as usize])
-        } else if x >= CHUNK_SIZE as isize {
-            assert!(Self::index_inbounds(y) && Self::index_inbounds(z));
-            self.neighbors[Direction::Front]
-                .upgrade()
-                .map(|front| front.read().unwrap().cubes[0][y as usize][z as usize])
+        self.read_cell(x, y, z, &Chunk::block)
+    }
+
+    pub fn get_block_with_state(&self, x: isize, y: isize, z: isize) -> Option<(Block, BlockState)> {
+        self.read_cell(x, y, z, &Chunk::block_with_state)
+    }
+
+    pub fn get_light(&self, x: isize, y: isize, z: isize) -> Option<Light> {
+        self.read_cell(x, y, z, &Chunk::light)
+    }
+
+    //Reads a block in this chunk or past its border through the neighbors, diagonals go through more than one
+    //None if a chunk on the way isn't loaded
+    fn read_cell<T, F: Fn(&Chunk, usize, usize, usize) -> T>(
+        &self,
+        x: isize,
+        y: isize,
+        z: isize,
+        read: &F,
+    ) -> Option<T> {
+        let size = CHUNK_SIZE as isize;
+        let (dir, x, y, z) = if x < 0 {
+            (Direction::Back, x + size, y, z)
+        } else if x >= size {
+            (Direction::Front, x - size, y, z)
         } else if z < 0 {
-            assert!(Self::index_inbounds(x) && Self::index_inbounds(y));
-            self.neighbors[Direction::Right]
-                .upgrade()
-                .map(|front| front.read().unwrap().cubes[x as usize][y as usize][CHUNK_SIZE - 1])
-        } else if z >= CHUNK_SIZE as isize {
-            assert!(Self::index_inbounds(x) && Self::index_inbounds(y));
-            self.neighbors[Direction::Left]
-                .upgrade()
-                .map(|back| back.read().unwrap().cubes[x as usize][y as usize][0])
+            (Direction::Right, x, y, z + size)
+        } else if z >= size {
+            (Direction::Left, x, y, z - size)
         } else if y < 0 {
-            assert!(Self::index_inbounds(x) && Self::index_inbounds(z));
-            self.neighbors[Direction::Bottom]
-                .upgrade()
-                .map(|bottom| bottom.read().unwrap().cubes[x as usize][CHUNK_SIZE - 1][z as usize])
-        } else if y >= CHUNK_SIZE as isize {
-            assert!(Self::index_inbounds(x) && Self::index_inbounds(z));
-            self.neighbors[Direction::Top]
-                .upgrade()
-                .map(|top| top.read().unwrap().cubes[x as usize][0][z as usize])
+            (Direction::Bottom, x, y + size, z)
+        } else if y >= size {
+            (Direction::Top, x, y - size, z)
         } else {
-            None
-        }
+            return Some(read(self, x as usize, y as usize, z as usize));
+        };
+        self.neighbors[dir]
+            .upgrade()
+            .and_then(|neighbor| neighbor.read().unwrap().read_cell(x, y, z, read))
     }
 
     pub fn get_block_neighbors(&self, x: usize, y: usize, z: usize) -> [Option<Block>; 6] {
@@ -340,7 +423,16 @@ impl Chunk {
         block_neighbors
     }
 
-    fn index_inbounds(index: isize) -> bool {
-        index >= 0 && index < CHUNK_SIZE as isize
+    //Light of the block in front of each face
+    pub fn get_light_neighbors(&self, x: usize, y: usize, z: usize) -> [Option<Light>; 6] {
+        let (x, y, z) = (x as isize, y as isize, z as isize);
+        let mut light_neighbors = [None; 6];
+        light_neighbors[Direction::Front] = self.get_light(x + 1, y, z);
+        light_neighbors[Direction::Back] = self.get_light(x - 1, y, z);
+        light_neighbors[Direction::Left] = self.get_light(x, y, z + 1);
+        light_neighbors[Direction::Right] = self.get_light(x, y, z - 1);
+        light_neighbors[Direction::Top] = self.get_light(x, y + 1, z);
+        light_neighbors[Direction::Bottom] = self.get_light(x, y - 1, z);
+        light_neighbors
     }
 }
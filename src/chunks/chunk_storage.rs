@@ -0,0 +1,279 @@
+use std::convert::Infallible;
+
+use crate::prelude::*;
+
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+// Palette compressed block storage for a single chunk
+// Most chunks are all air or only a handful of block types so storing a full array per chunk wastes a lot of memory
+// Generic so the save format can store raw ids and remap them without touching the packed indices
+// Chunks store each block together with its state so blocks that only differ in state get separate palette entries
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BlockStorage<T = (Block, BlockState)> {
+    //Every block in the chunk is the same, no per block data at all
+    Uniform(T),
+    Paletted(PalettedStorage<T>),
+}
+
+// Indices into the palette are packed into u64 words, bits is always a power of 2 so entries never straddle words
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PalettedStorage<T = (Block, BlockState)> {
+    palette: Vec<T>,
+    bits: u8,
+    data: Vec<u64>,
+}
+
+impl<T: Copy + PartialEq + Default> Default for BlockStorage<T> {
+    fn default() -> Self {
+        BlockStorage::Uniform(T::default())
+    }
+}
+
+impl<T: Copy + PartialEq> BlockStorage<T> {
+    pub fn get(&self, x: usize, y: usize, z: usize) -> T {
+        match self {
+            BlockStorage::Uniform(block) => *block,
+            BlockStorage::Paletted(storage) => storage.get(Self::index(x, y, z)),
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: T) {
+        let index = Self::index(x, y, z);
+        match self {
+            BlockStorage::Uniform(current) => {
+                if *current != block {
+                    let mut storage = PalettedStorage::new(*current);
+                    storage.set(index, block);
+                    *self = BlockStorage::Paletted(storage);
+                }
+            }
+            BlockStorage::Paletted(storage) => storage.set(index, block),
+        }
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        matches!(self, BlockStorage::Uniform(_))
+    }
+
+    //Every block type that might be in the chunk, paletted storage can have stale entries until the next compact
+    pub fn palette(&self) -> &[T] {
+        match self {
+            BlockStorage::Uniform(block) => std::slice::from_ref(block),
+            BlockStorage::Paletted(storage) => &storage.palette,
+        }
+    }
+
+    //Drops unused palette entries and collapses back to uniform storage if possible
+    pub fn compact(&mut self) {
+        if let BlockStorage::Paletted(storage) = self {
+            storage.compact();
+            if storage.palette.len() == 1 {
+                *self = BlockStorage::Uniform(storage.palette[0]);
+            }
+        }
+    }
+
+    pub fn map_palette<U>(&self, mut function: impl FnMut(T) -> U) -> BlockStorage<U> {
+        match self.try_map_palette(|block| Ok::<U, Infallible>(function(block))) {
+            Ok(storage) => storage,
+            Err(never) => match never {},
+        }
+    }
+
+    //Converts every palette entry, the packed indices are reused as is
+    pub fn try_map_palette<U, E>(&self, mut function: impl FnMut(T) -> Result<U, E>) -> Result<BlockStorage<U>, E> {
+        Ok(match self {
+            BlockStorage::Uniform(block) => BlockStorage::Uniform(function(*block)?),
+            BlockStorage::Paletted(storage) => BlockStorage::Paletted(PalettedStorage {
+                palette: storage
+                    .palette
+                    .iter()
+                    .map(|block| function(*block))
+                    .collect::<Result<Vec<U>, E>>()?,
+                bits: storage.bits,
+                data: storage.data.clone(),
+            }),
+        })
+    }
+
+    fn index(x: usize, y: usize, z: usize) -> usize {
+        debug_assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE);
+        (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+    }
+}
+
+impl<T: Copy + PartialEq> PalettedStorage<T> {
+    //Starts with the whole chunk filled by one block, the common case when converting from uniform
+    fn new(fill: T) -> Self {
+        PalettedStorage {
+            palette: vec![fill],
+            bits: 1,
+            data: vec![0; Self::words_needed(1)],
+        }
+    }
+
+    fn words_needed(bits: u8) -> usize {
+        CHUNK_VOLUME / (64 / bits as usize)
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    fn get_index(&self, index: usize) -> usize {
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) * self.bits as usize;
+        ((self.data[index / per_word] >> shift) & self.mask()) as usize
+    }
+
+    fn set_index(&mut self, index: usize, value: usize) {
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) * self.bits as usize;
+        let mask = self.mask();
+        let word = &mut self.data[index / per_word];
+        *word = (*word & !(mask << shift)) | ((value as u64 & mask) << shift);
+    }
+
+    fn get(&self, index: usize) -> T {
+        self.palette[self.get_index(index)]
+    }
+
+    fn set(&mut self, index: usize, block: T) {
+        let palette_index = match self.palette.iter().position(|entry| *entry == block) {
+            Some(palette_index) => palette_index,
+            None => {
+                if self.palette.len() >= 1 << self.bits {
+                    //Try to reclaim stale entries before paying for wider indices
+                    self.compact();
+                    if self.palette.len() >= 1 << self.bits {
+                        self.resize(self.bits * 2);
+                    }
+                }
+                self.palette.push(block);
+                self.palette.len() - 1
+            }
+        };
+        self.set_index(index, palette_index);
+    }
+
+    fn resize(&mut self, bits: u8) {
+        let mut resized = PalettedStorage {
+            palette: Vec::default(),
+            bits,
+            data: vec![0; Self::words_needed(bits)],
+        };
+        for index in 0..CHUNK_VOLUME {
+            resized.set_index(index, self.get_index(index));
+        }
+        resized.palette = std::mem::take(&mut self.palette);
+        *self = resized;
+    }
+
+    fn compact(&mut self) {
+        let mut used = vec![false; self.palette.len()];
+        for index in 0..CHUNK_VOLUME {
+            used[self.get_index(index)] = true;
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::default();
+        for (old_index, block) in self.palette.iter().enumerate() {
+            if used[old_index] {
+                remap[old_index] = palette.len();
+                palette.push(*block);
+            }
+        }
+        for index in 0..CHUNK_VOLUME {
+            let old = self.get_index(index);
+            self.set_index(index, remap[old]);
+        }
+        self.palette = palette;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn positions() -> impl Iterator<Item = (usize, usize, usize)> {
+        (0..CHUNK_VOLUME).map(|index| {
+            (
+                index / CHUNK_SIZE / CHUNK_SIZE,
+                index / CHUNK_SIZE % CHUNK_SIZE,
+                index % CHUNK_SIZE,
+            )
+        })
+    }
+
+    #[test]
+    fn paletted_storage_reads_back_what_was_set() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut storage = BlockStorage::<u16>::default();
+        let mut expected = vec![0; CHUNK_VOLUME];
+        //Enough kinds of block to widen the indices a few times
+        for (index, (x, y, z)) in positions().enumerate() {
+            let block = rng.gen_range(0..100);
+            storage.set(x, y, z, block);
+            expected[index] = block;
+        }
+        for (index, (x, y, z)) in positions().enumerate() {
+            assert_eq!(storage.get(x, y, z), expected[index]);
+        }
+        match &storage {
+            BlockStorage::Paletted(paletted) => assert_eq!(paletted.bits, 8),
+            BlockStorage::Uniform(_) => panic!("storage with 100 blocks can't be uniform"),
+        }
+    }
+
+    #[test]
+    fn compact_drops_stale_entries() {
+        let mut storage = BlockStorage::<u16>::default();
+        storage.set(1, 2, 3, 7);
+        storage.set(4, 5, 6, 8);
+        storage.set(1, 2, 3, 0);
+        assert_eq!(storage.palette(), &[0, 7, 8]);
+        storage.compact();
+        assert_eq!(storage.palette(), &[0, 8]);
+        assert_eq!(storage.get(4, 5, 6), 8);
+        assert_eq!(storage.get(1, 2, 3), 0);
+        storage.set(4, 5, 6, 0);
+        storage.compact();
+        assert!(storage.is_uniform());
+        assert_eq!(storage.get(4, 5, 6), 0);
+    }
+
+    #[test]
+    fn full_palette_reuses_stale_entries_before_widening() {
+        let mut storage = BlockStorage::<u16>::default();
+        storage.set(0, 0, 0, 1);
+        //Takes the last slot of 1 bit indices, the entry for 1 is now stale
+        storage.set(0, 0, 0, 0);
+        storage.set(0, 0, 1, 2);
+        match &storage {
+            BlockStorage::Paletted(paletted) => {
+                assert_eq!(paletted.bits, 1);
+                assert_eq!(paletted.palette, vec![0, 2]);
+            }
+            BlockStorage::Uniform(_) => panic!("storage with two blocks can't be uniform"),
+        }
+        assert_eq!(storage.get(0, 0, 1), 2);
+    }
+
+    #[test]
+    fn map_palette_keeps_every_block_in_place() {
+        let mut storage = BlockStorage::<u16>::default();
+        storage.set(1, 2, 3, 7);
+        storage.set(4, 5, 6, 8);
+        let mapped = storage.map_palette(|block| (block, block * 2));
+        for (x, y, z) in positions() {
+            let block = storage.get(x, y, z);
+            assert_eq!(mapped.get(x, y, z), (block, block * 2));
+        }
+        let failed = storage.try_map_palette(|block| if block == 8 { Err(block) } else { Ok(block) });
+        assert_eq!(failed.err(), Some(8));
+    }
+}
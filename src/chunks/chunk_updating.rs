@@ -1,54 +1,114 @@
-use bevy::render::primitives::Aabb;
+use bevy::{
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::FloatOrd,
+};
+use bevy_rapier3d::prelude::Collider;
+use futures_lite::future;
 
 use crate::prelude::*;
 
+// A remesh of a chunk running on another thread, started by update_dirty_chunks
+#[derive(Component)]
+pub struct RemeshTask(Task<(ChunkMeshes, Option<Collider>)>);
+
+// Dirty chunks are copied and remeshed on the task pool, the nearest to the camera first
+// A chunk that changes again while its remesh is running gets a new one and the old result is thrown away
 pub fn update_dirty_chunks(
     mut commands: Commands,
-    mut chunks: Query<(Entity, &ChunkComp, &mut Handle<Mesh>)>,
+    mut chunks: Query<(
+        Entity,
+        &ChunkComp,
+        &mut Handle<Mesh>,
+        &TransparentMesh,
+        &ChunkLod,
+        Option<&mut RemeshTask>,
+    )>,
+    mut transparent_meshes: Query<&mut Handle<Mesh>, Without<ChunkComp>>,
+    camera: Query<&Transform, With<Camera3d>>,
     mut meshes: ResMut<Assets<Mesh>>,
+    registry: Res<BlockRegistry>,
 ) {
-    //TODO all of this can be done in parallel except for adding mesh to assets
-    //FIXME for now I'm just going to cap the number of chunk updates per frame
-    let mut updates = 0;
-    for (entity, chunk, mut mesh) in &mut chunks {
+    for (entity, chunk, mut mesh, transparent, _, task) in &mut chunks {
+        let mut task = match task {
+            Some(task) => task,
+            None => continue,
+        };
+        let (chunk_meshes, collider) = match future::block_on(future::poll_once(&mut task.0)) {
+            Some(result) => result,
+            None => continue,
+        };
+        commands.entity(entity).remove::<RemeshTask>();
         if chunk.read_dirty() {
-            let data = create_chunk_mesh(&chunk.read_chunk());
-            *mesh = meshes.add(data.0);
-            add_collider(&mut commands, entity, data.1);
-
-            //Remove because it needs to be recalculated by bevy
-            commands.entity(entity).remove::<Aabb>();
-            updates += 1;
-            chunk.write_dirty(false);
+            continue;
         }
-        if updates > MAX_CHUNK_UPDATES_PER_FRAME {
-            return;
+        *mesh = meshes.add(chunk_meshes.opaque);
+        if let Ok(mut transparent_mesh) = transparent_meshes.get_mut(transparent.0) {
+            *transparent_mesh = meshes.add(chunk_meshes.transparent);
         }
+        set_collider(&mut commands, entity, collider);
+        commands.entity(entity).insert(chunk_meshes.visibility);
     }
-}
 
-pub fn apply_buffered_chunk_writes(mut chunks: Query<&mut ChunkComp>) {
-    chunks.par_for_each_mut(5, |mut chunk| {
-        chunk.apply_buffered_writes();
-    });
-}
+    let camera = match camera.get_single() {
+        Ok(camera) => camera.translation,
+        Err(_) => return,
+    };
+    let mut dirty = chunks
+        .iter()
+        .filter(|(_, chunk, _, _, _, _)| chunk.read_dirty())
+        .map(|(entity, chunk, _, _, lod, _)| {
+            let center = (chunk.read_chunk().pos * CHUNK_SIZE as i32).as_vec3() + Vec3::splat(CHUNK_SIZE as f32 / 2.0);
+            (entity, chunk, *lod, center.distance_squared(camera))
+        })
+        .collect::<Vec<_>>();
+    dirty.sort_by_key(|(_, _, _, distance)| FloatOrd(*distance));
 
-pub fn update_dirt_sys(mut chunks: Query<&mut ChunkComp>, input: Res<Input<KeyCode>>) {
-    if input.just_pressed(KeyCode::P) {
-        chunks.par_for_each_mut(5, |mut chunk| {
-            //let _span = info_span!("Dirt update", name = "Dirt Update").entered();
-            chunk.apply_function_to_blocks(update_dirt);
+    let thread_pool = AsyncComputeTaskPool::get();
+    for (entity, chunk, lod, _) in dirty.into_iter().take(MAX_CHUNK_UPDATES_PER_FRAME) {
+        let padded = PaddedChunk::new(&chunk.read_chunk());
+        chunk.write_dirty(false);
+        let registry = registry.clone();
+        let task = thread_pool.spawn(async move {
+            let _span = info_span!("Chunk Remesh Task", name = "Chunk Remesh Task").entered();
+            let padded = padded.downsampled(lod, &registry);
+            let (chunk_meshes, description) = create_padded_chunk_mesh(&padded, &registry);
+            let collider = if lod.has_collider() {
+                create_collider(description)
+            } else {
+                None
+            };
+            (chunk_meshes, collider)
         });
+        //Replacing a remesh that is still running drops it, which cancels it
+        commands.entity(entity).insert(RemeshTask(task));
     }
 }
 
-pub fn update_dirt(block: &Block, neighbors: [Option<Block>; 6]) -> Option<Block> {
-    if matches!(block, Block::Grass) {
-        if let Some(top) = neighbors[Direction::Top] {
-            if !matches!(top, Block::Air) {
-                return Some(Block::Dirt);
-            }
+//Grass covered by anything turns back into dirt
+pub fn grass_decay(context: &mut RuleContext) {
+    if let Some(top) = context.neighbor(Direction::Top) {
+        if !top.is_air() {
+            let dirt = context.registry.expect("dirt");
+            context.set_block(context.pos, dirt);
         }
     }
-    None
+}
+
+//Uncovered grass spreads to uncovered dirt up to a block away in any direction, even into the next chunk
+pub fn grass_spread(context: &mut RuleContext) {
+    if context.neighbor(Direction::Top).map_or(true, |top| !top.is_air()) {
+        return;
+    }
+    let target = context.pos
+        + IVec3::new(
+            context.random(3) as i32 - 1,
+            context.random(3) as i32 - 1,
+            context.random(3) as i32 - 1,
+        );
+    let dirt = context.registry.expect("dirt");
+    let uncovered = context.get(target + IVec3::Y).map_or(false, |(top, _)| top.is_air());
+    if context.get(target).map(|(block, _)| block) == Some(dirt) && uncovered {
+        let grass = context.block;
+        context.set_block(target, grass);
+    }
 }
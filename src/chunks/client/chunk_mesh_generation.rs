@@ -1,211 +1,474 @@
 use crate::client::material::*;
+use crate::client::padded_chunk::*;
 use crate::prelude::*;
-use bevy::render::{
-    mesh::{Indices, VertexAttributeValues},
-    render_resource::PrimitiveTopology,
-};
+use bevy::render::{mesh::Indices, primitives::Aabb, render_resource::PrimitiveTopology};
+use bevy::utils::HashMap;
 
-//FIXME remove clone
-#[derive(Default, Clone)]
+#[derive(Default)]
 pub struct MeshDescription {
     pub verts: Vec<Vec3>,
-    true_normals: Vec<Vec3>,
-    normals: Vec<[u8; 2]>,
-    uvs: Vec<[u8; 2]>,
+    directions: Vec<Direction>,
+    //In sixteenths of a block so model faces show just their part of the texture
+    uvs: Vec<[u16; 2]>,
     texture_indices: Vec<u32>,
+    lights: Vec<Light>,
+    occlusion: Vec<u8>,
     pub vert_indicies: Vec<usize>,
+    //Faces of transparent blocks, they go in their own mesh
+    pub transparent_indicies: Vec<usize>,
+    //Only faces of solid blocks, what the collider is built from
+    pub collider_indicies: Vec<usize>,
 }
 
-pub fn create_chunk_mesh(chunk: &Chunk) -> (Mesh, MeshDescription) {
-    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-    let mut description = MeshDescription::default();
+impl MeshDescription {
+    // Every unit square of every face and what is drawn on it, keyed by the face's normal and the square
+    // Where the square is along the normal is in sixteenths of a block so fluid surfaces fit
+    // Two meshers that cover the same squares look the same however they split the faces into quads
+    pub fn coverage(&self) -> HashMap<([i32; 3], [i32; 3]), (u32, Light, [u8; 4])> {
+        let mut coverage = HashMap::default();
+        for face in (0..self.verts.len()).step_by(4) {
+            let verts = &self.verts[face..face + 4];
+            let min = verts.iter().copied().reduce(Vec3::min).unwrap();
+            let max = verts.iter().copied().reduce(Vec3::max).unwrap();
+            let normal = self.directions[face].offset().as_vec3();
+            let look = (
+                self.texture_indices[face],
+                self.lights[face],
+                [0, 1, 2, 3].map(|corner| self.occlusion[face + corner]),
+            );
+            //Fluid faces are short so their last square only covers part of a block
+            let squares = [0, 1, 2].map(|axis| {
+                if normal[axis] != 0.0 {
+                    let plane = (min[axis] * 16.0).round() as i32;
+                    plane..plane + 1
+                } else {
+                    min[axis].floor() as i32..max[axis].ceil() as i32
+                }
+            });
+            for x in squares[0].clone() {
+                for y in squares[1].clone() {
+                    for z in squares[2].clone() {
+                        coverage.insert((normal.as_ivec3().to_array(), [x, y, z]), look);
+                    }
+                }
+            }
+        }
+        coverage
+    }
+}
 
-    create_mesh_faces(chunk, &mut description);
-    //FIXME I hate this clone
-    let to_return = description.clone();
+//Just the faces, for building colliders where nothing is rendered
+pub fn create_mesh_description(chunk: &Chunk, registry: &BlockRegistry) -> MeshDescription {
+    create_padded_mesh_description(&PaddedChunk::new(chunk), registry)
+}
 
-    mesh.insert_attribute(
-        Mesh::ATTRIBUTE_POSITION,
-        description
-            .verts
-            .iter()
-            .map(|vec| vec.to_array())
-            .collect::<Vec<[f32; 3]>>(),
-    );
+// Faces of opaque blocks and of transparent ones are drawn in separate passes, only the transparent pass blends
+pub struct ChunkMeshes {
+    pub opaque: Mesh,
+    pub transparent: Mesh,
+    //Worked out while meshing since the chunk has already been copied out
+    pub visibility: ChunkVisibility,
+}
+
+impl ChunkMeshes {
+    //Bevy can't work out the bounds of packed positions, every chunk mesh fits inside its chunk
+    pub fn aabb() -> Aabb {
+        Aabb::from_min_max(Vec3::ZERO, Vec3::splat(CHUNK_SIZE as f32))
+    }
+}
+
+pub fn create_chunk_mesh(chunk: &Chunk, registry: &BlockRegistry) -> (ChunkMeshes, MeshDescription) {
+    create_padded_chunk_mesh(&PaddedChunk::new(chunk), registry)
+}
+
+//For meshing off the main thread, the chunk is copied with PaddedChunk::new before the mesh is built
+pub fn create_padded_chunk_mesh(padded: &PaddedChunk, registry: &BlockRegistry) -> (ChunkMeshes, MeshDescription) {
+    let description = create_padded_mesh_description(padded, registry);
+    let meshes = ChunkMeshes {
+        opaque: create_mesh(&description, &description.vert_indicies),
+        transparent: create_mesh(&description, &description.transparent_indicies),
+        visibility: ChunkVisibility::new(padded, registry),
+    };
+    (meshes, description)
+}
+
+//A mesh of just the faces the indices point at, the vertices they use are copied over once each
+fn create_mesh(description: &MeshDescription, indices: &[usize]) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    let mut remapped = HashMap::default();
+    let mut used = Vec::default();
+    let indices = indices
+        .iter()
+        .map(|index| {
+            *remapped.entry(*index).or_insert_with(|| {
+                used.push(*index);
+                used.len() as u32 - 1
+            })
+        })
+        .collect::<Vec<u32>>();
 
-    // TODO figure out how to make bevy let me not add this
-    // Currently just sending the positions again
     mesh.insert_attribute(
-        Mesh::ATTRIBUTE_NORMAL,
-        description
-            .true_normals
-            .iter()
-            .map(|normal| normal.to_array())
-            .collect::<Vec<[f32; 3]>>(),
+        ATTRIBUTE_PACKED_POSITION,
+        used.iter()
+            .map(|index| {
+                pack_position(
+                    description.verts[*index],
+                    description.directions[*index],
+                    description.occlusion[*index],
+                )
+            })
+            .collect::<Vec<u32>>(),
     );
-
-    mesh.set_indices(Some(Indices::U32(
-        description
-            .vert_indicies
-            .iter()
-            .map(|usized| *usized as u32)
+    mesh.insert_attribute(
+        ATTRIBUTE_PACKED_FACE,
+        used.iter()
+            .map(|index| {
+                pack_face(
+                    description.uvs[*index],
+                    description.lights[*index],
+                    description.texture_indices[*index],
+                )
+            })
             .collect::<Vec<u32>>(),
-    )));
+    );
+    mesh.set_indices(Some(Indices::U32(indices)));
 
-    mesh.insert_attribute(CUSTOM_NORMAL, VertexAttributeValues::Uint8x2(description.normals));
+    mesh
+}
 
-    mesh.insert_attribute(CUSTOM_UV, VertexAttributeValues::Uint8x2(description.uvs));
+//Bits 1 to CHUNK_SIZE of a column, the cells in the chunk itself, bit 0 and the last bit are the padding
+const CHUNK_BITS: u32 = ((1 << CHUNK_SIZE) - 1) << 1;
 
-    mesh.insert_attribute(ATTRIBUTE_TEXTURE_INDEX, description.texture_indices);
+//What a face looks like and where it starts, in sixteenths of a block up its block, only faces that look the same are
+//merged
+type Look = (Block, BlockState, Light, [u8; 4], u8);
 
-    (mesh, to_return)
+// The visible faces of one slice of the chunk facing one way, bit v of faces[u] is the face at u, v on the sheet
+// laid out the way create_greedy_face expects. Faces are also split up by how they look, so merging is just bit math
+#[derive(Default)]
+struct Slice {
+    faces: [u16; CHUNK_SIZE],
+    looks: Vec<Look>,
+    //Index into looks of every face
+    look_of: [[u8; CHUNK_SIZE]; CHUNK_SIZE],
+    //Faces of each look in the same layout as faces
+    look_faces: Vec<[u16; CHUNK_SIZE]>,
 }
 
-// A single slide of a chunk, direction agnostic, used for greedy meshing
-#[derive(Default, Copy, Clone)]
-pub struct Sheet {
-    blocks: [[Block; CHUNK_SIZE]; CHUNK_SIZE],
-}
+impl Slice {
+    fn add(&mut self, u: usize, v: usize, look: Look) {
+        //At most CHUNK_SIZE^2 faces so the index always fits
+        let index = match self.looks.iter().position(|other| *other == look) {
+            Some(index) => index,
+            None => {
+                self.looks.push(look);
+                self.look_faces.push([0; CHUNK_SIZE]);
+                self.looks.len() - 1
+            }
+        };
+        self.look_of[u][v] = index as u8;
+        self.look_faces[index][u] |= 1 << v;
+        self.faces[u] |= 1 << v;
+    }
 
-//Gathers the slices and runs the greedy algorithm
-fn create_mesh_faces(chunk: &Chunk, mesh_description: &mut MeshDescription) {
-    let mut top_slices = [Sheet::default(); CHUNK_SIZE];
-    let mut bottom_slices = [Sheet::default(); CHUNK_SIZE];
-    let mut left_slices = [Sheet::default(); CHUNK_SIZE];
-    let mut right_slices = [Sheet::default(); CHUNK_SIZE];
-    let mut front_slices = [Sheet::default(); CHUNK_SIZE];
-    let mut back_slices = [Sheet::default(); CHUNK_SIZE];
-    for x in 0..CHUNK_SIZE as isize {
-        for y in 0..CHUNK_SIZE as isize {
-            for z in 0..CHUNK_SIZE as isize {
-                let current_block = chunk.get_block(x, y, z).unwrap();
-                let [front_block, back_block, left_block, right_block, top_block, bottom_block] =
-                    chunk.get_block_neighbors(x as usize, y as usize, z as usize);
-
-                if current_block.is_filled() && (left_block.is_none() || !left_block.unwrap().is_filled()) {
-                    left_slices[z as usize].blocks[x as usize][y as usize] = current_block;
+    // Merges faces the same way walking the sheet did, lowest u then lowest v first, widening along u and then
+    // growing along v while every face in the width is still there and looks the same
+    //u and w index several arrays at once, like in greedy
+    #[allow(clippy::needless_range_loop)]
+    fn merge(&mut self, dir: Direction, registry: &BlockRegistry, desc: &mut MeshDescription, z: usize) {
+        for u in 0..CHUNK_SIZE {
+            while self.faces[u] != 0 {
+                let v = self.faces[u].trailing_zeros() as usize;
+                let look = self.look_of[u][v] as usize;
+                let look_faces = &mut self.look_faces[look];
+                let mut width = 1;
+                while u + width < CHUNK_SIZE && (look_faces[u + width] >> v) & 1 == 1 {
+                    width += 1;
                 }
-                if current_block.is_filled() && (right_block.is_none() || !right_block.unwrap().is_filled()) {
-                    right_slices[z as usize].blocks[x as usize][y as usize] = current_block;
+                let (block, state, light, occlusion, floor) = self.looks[look];
+                //Faces that start part way up their block can't be stacked, the gap would be covered over
+                let height = if floor > 0 {
+                    1
+                } else {
+                    (u..u + width)
+                        .map(|w| (look_faces[w] >> v).trailing_ones() as usize)
+                        .min()
+                        .unwrap()
+                };
+                let merged = (((1u32 << height) - 1) << v) as u16;
+                for w in u..u + width {
+                    look_faces[w] &= !merged;
+                    self.faces[w] &= !merged;
                 }
-                if current_block.is_filled() && (front_block.is_none() || !front_block.unwrap().is_filled()) {
-                    front_slices[x as usize].blocks[z as usize][y as usize] = current_block;
+                create_greedy_face(
+                    block, state, light, occlusion, floor, dir, registry, u, v, z, width, height, desc,
+                );
+            }
+        }
+    }
+}
+
+// Finds faces a column of blocks at a time, a face shows where a block isn't air and the next block isn't opaque,
+// which for a whole column is a shift and a mask. Only the faces that show are looked at one by one
+fn create_padded_mesh_description(padded: &PaddedChunk, registry: &BlockRegistry) -> MeshDescription {
+    let mut description = MeshDescription::default();
+    //Fast path for empty chunks, nothing to mesh
+    if padded.is_empty() {
+        return description;
+    }
+    //Columns along x, y and z, bit i + 1 of a column is the block at i along it
+    //They are indexed by the other two coordinates, also shifted by 1, in x y z order
+    let mut filled = [[[0u32; PADDED_SIZE]; PADDED_SIZE]; 3];
+    let mut opaque = [[[0u32; PADDED_SIZE]; PADDED_SIZE]; 3];
+    let mut models = Vec::default();
+    let padded_range = -1..CHUNK_SIZE as i32 + 1;
+    for x in padded_range.clone() {
+        for y in padded_range.clone() {
+            for z in padded_range.clone() {
+                let pos = IVec3::new(x, y, z);
+                let block = padded.block(pos);
+                if block.is_air() {
+                    continue;
                 }
-                if current_block.is_filled() && (back_block.is_none() || !back_block.unwrap().is_filled()) {
-                    back_slices[x as usize].blocks[z as usize][y as usize] = current_block;
+                //Models aren't cubes so they have no faces to find here, they are meshed on their own after
+                if !registry.model(block).is_empty() {
+                    if pos.min_element() >= 0 && pos.max_element() < CHUNK_SIZE as i32 {
+                        models.push(pos);
+                    }
+                    continue;
                 }
-                if current_block.is_filled() && (top_block.is_none() || !top_block.unwrap().is_filled()) {
-                    top_slices[y as usize].blocks[x as usize][z as usize] = current_block;
+                let (i, j, k) = ((x + 1) as usize, (y + 1) as usize, (z + 1) as usize);
+                let columns = [(j, k, i), (i, k, j), (i, j, k)];
+                for (axis, (a, b, bit)) in columns.into_iter().enumerate() {
+                    filled[axis][a][b] |= 1 << bit;
+                    if registry.is_opaque(block) {
+                        opaque[axis][a][b] |= 1 << bit;
+                    }
                 }
-                if current_block.is_filled() && (bottom_block.is_none() || !bottom_block.unwrap().is_filled()) {
-                    bottom_slices[y as usize].blocks[x as usize][z as usize] = current_block;
+            }
+        }
+    }
+
+    let mut slices = (0..Direction::ALL.len() * CHUNK_SIZE)
+        .map(|_| Slice::default())
+        .collect::<Vec<_>>();
+    for dir in Direction::ALL {
+        let (axis, positive) = match dir {
+            Direction::Front => (0, true),
+            Direction::Back => (0, false),
+            Direction::Top => (1, true),
+            Direction::Bottom => (1, false),
+            Direction::Left => (2, true),
+            Direction::Right => (2, false),
+        };
+        for a in 0..CHUNK_SIZE {
+            for b in 0..CHUNK_SIZE {
+                let column = filled[axis][a + 1][b + 1] & CHUNK_BITS;
+                let next = opaque[axis][a + 1][b + 1];
+                let next = if positive { next >> 1 } else { next << 1 };
+                let mut faces = column & !next;
+                while faces != 0 {
+                    let i = faces.trailing_zeros() as i32 - 1;
+                    faces &= faces - 1;
+                    let (a, b) = (a as i32, b as i32);
+                    let pos = match axis {
+                        0 => IVec3::new(i, a, b),
+                        1 => IVec3::new(a, i, b),
+                        _ => IVec3::new(a, b, i),
+                    };
+                    if let Some(look) = face_look(padded, registry, pos, dir) {
+                        let (z, u, v) = match dir {
+                            Direction::Front | Direction::Back => (pos.x, pos.z, pos.y),
+                            Direction::Left | Direction::Right => (pos.z, pos.x, pos.y),
+                            Direction::Top | Direction::Bottom => (pos.y, pos.x, pos.z),
+                        };
+                        slices[dir as usize * CHUNK_SIZE + z as usize].add(u as usize, v as usize, look);
+                    }
                 }
             }
         }
     }
-    for index in 0..CHUNK_SIZE as isize {
-        greedy(
-            &back_slices[index as usize],
+
+    //Same order the slices were always meshed in so the mesh comes out the same
+    for z in 0..CHUNK_SIZE {
+        for dir in [
             Direction::Back,
-            mesh_description,
-            index as usize,
-        );
-        greedy(
-            &front_slices[index as usize],
             Direction::Front,
-            mesh_description,
-            index as usize,
-        );
-        greedy(
-            &left_slices[index as usize],
             Direction::Left,
-            mesh_description,
-            index as usize,
-        );
-        greedy(
-            &right_slices[index as usize],
             Direction::Right,
-            mesh_description,
-            index as usize,
-        );
-        greedy(
-            &top_slices[index as usize],
             Direction::Top,
-            mesh_description,
-            index as usize,
-        );
-        greedy(
-            &bottom_slices[index as usize],
             Direction::Bottom,
-            mesh_description,
-            index as usize,
-        );
+        ] {
+            slices[dir as usize * CHUNK_SIZE + z].merge(dir, registry, &mut description, z);
+        }
+    }
+    for pos in models {
+        create_model_faces(padded, registry, pos, &mut description);
     }
+    description
 }
 
-//I think it makes the code clearer as is, clippy wants to make the iterations over finished
-//But there are 2 different uses for every index so doing the iterator over finished and also enumerating it
-//Seems to break a single concept into 2 for no great reason
-#[allow(clippy::needless_range_loop)]
-fn greedy(sheet: &Sheet, dir: Direction, desc: &mut MeshDescription, z: usize) {
-    let mut finished = [[false; CHUNK_SIZE]; CHUNK_SIZE];
-
-    for x in 0..CHUNK_SIZE {
-        for y in 0..CHUNK_SIZE {
-            finished[x][y] = !sheet.blocks[x][y].is_filled();
+// Every face of every box of a model block, faces on the edge of the block are hidden by opaque neighbors like
+// a cube's are, the rest always show
+fn create_model_faces(padded: &PaddedChunk, registry: &BlockRegistry, pos: IVec3, desc: &mut MeshDescription) {
+    let (block, state) = padded.block_with_state(pos);
+    let facing = if registry.get(block).rotatable {
+        state.facing()
+    } else {
+        Direction::Front
+    };
+    for model_box in registry.model(block) {
+        let (from, to) = model_box.rotated(facing);
+        for dir in Direction::ALL {
+            let on_edge = match dir {
+                Direction::Front => to.x == 1.0,
+                Direction::Back => from.x == 0.0,
+                Direction::Left => to.z == 1.0,
+                Direction::Right => from.z == 0.0,
+                Direction::Top => to.y == 1.0,
+                Direction::Bottom => from.y == 0.0,
+            };
+            if on_edge && registry.is_opaque(padded.block(pos + dir.offset())) {
+                continue;
+            }
+            //Faces inside the block are lit by the block itself, models let light in
+            let light = if on_edge {
+                padded.light(pos + dir.offset())
+            } else {
+                padded.light(pos)
+            };
+            let texture = match model_box.textures {
+                Some(textures) => textures.get(dir.relative_to(facing)),
+                None => registry.face_texture(block, state, dir),
+            };
+            create_model_face(block, texture, light, dir, registry, pos.as_vec3(), from, to, desc);
         }
     }
+}
 
-    //gross
-    while finished.iter().flatten().any(|x| x == &false) {
-        for x in 0..CHUNK_SIZE {
-            for y in 0..CHUNK_SIZE {
-                if !finished[x][y] {
-                    //Starting point, walk x to get width
-                    let start = sheet.blocks[x][y];
-                    let mut width = 1;
-                    for w in x + 1..CHUNK_SIZE {
-                        if start == sheet.blocks[w][y] && !finished[w][y] {
-                            width += 1;
-                        } else {
-                            break;
-                        }
-                    }
-                    //Now walk y to get height
-                    let mut height = 1;
-                    for h in y + 1..CHUNK_SIZE {
-                        let mut all_same = true;
-                        for w in x..x + width {
-                            if start != sheet.blocks[w][h] || finished[w][h] {
-                                all_same = false;
-                                break;
-                            }
-                        }
-                        if all_same {
-                            height += 1;
-                        } else {
-                            break;
-                        }
-                    }
+// One face of a box in the block at origin, from and to are in blocks inside it
+// The corners and uvs are the ones a full face of a cube gets, scaled down to the box, so the face shows the part of
+// the texture the same part of a cube would
+fn create_model_face(
+    block: Block,
+    texture: u32,
+    light: Light,
+    dir: Direction,
+    registry: &BlockRegistry,
+    origin: Vec3,
+    from: Vec3,
+    to: Vec3,
+    mesh_description: &mut MeshDescription,
+) {
+    //Corners of a full cube face, in the order create_greedy_face puts them
+    let corners = match dir {
+        Direction::Front => [[1, 0, 1], [1, 0, 0], [1, 1, 0], [1, 1, 1]],
+        Direction::Back => [[0, 0, 0], [0, 0, 1], [0, 1, 1], [0, 1, 0]],
+        Direction::Left => [[0, 0, 1], [1, 0, 1], [1, 1, 1], [0, 1, 1]],
+        Direction::Right => [[1, 0, 0], [0, 0, 0], [0, 1, 0], [1, 1, 0]],
+        Direction::Top => [[0, 1, 1], [1, 1, 1], [1, 1, 0], [0, 1, 0]],
+        Direction::Bottom => [[0, 0, 0], [1, 0, 0], [1, 0, 1], [0, 0, 1]],
+    };
+    let verts = corners.map(|corner| from + (to - from) * IVec3::from(corner).as_vec3());
+    let uvs = verts.map(|vert| {
+        let uv = match dir {
+            Direction::Front => Vec2::new(1.0 - vert.z, 1.0 - vert.y),
+            Direction::Back => Vec2::new(vert.z, 1.0 - vert.y),
+            Direction::Left => Vec2::new(vert.x, 1.0 - vert.y),
+            Direction::Right => Vec2::new(1.0 - vert.x, 1.0 - vert.y),
+            Direction::Top => Vec2::new(vert.x, vert.z),
+            Direction::Bottom => Vec2::new(vert.x, 1.0 - vert.z),
+        };
+        (uv * 16.0).round().to_array().map(|axis| axis as u16)
+    });
 
-                    //Time to make the rect and mark finished
-                    create_greedy_face(start, dir, x, y, z, width, height, desc);
+    let vert_start = mesh_description.verts.len();
+    mesh_description.verts.extend(verts.map(|vert| origin + vert));
+    mesh_description.directions.extend_from_slice(&[dir; 4]);
+    mesh_description.uvs.extend_from_slice(&uvs);
+    mesh_description.texture_indices.extend_from_slice(&[texture; 4]);
+    mesh_description.lights.extend_from_slice(&[light; 4]);
+    //Models aren't shaded by their neighbors
+    mesh_description.occlusion.extend_from_slice(&[3; 4]);
 
-                    for u in x..x + width {
-                        for v in y..y + height {
-                            finished[u][v] = true;
-                        }
-                    }
-                }
-            }
+    let indices = [
+        vert_start,
+        vert_start + 1,
+        vert_start + 2,
+        vert_start,
+        vert_start + 2,
+        vert_start + 3,
+    ];
+    if registry.get(block).transparent {
+        mesh_description.transparent_indicies.extend_from_slice(&indices);
+    } else {
+        mesh_description.vert_indicies.extend_from_slice(&indices);
+    }
+    if registry.is_solid(block) {
+        mesh_description.collider_indicies.extend_from_slice(&indices);
+    }
+}
+
+//None for faces between two of the same transparent block, a lake or a glass wall is only its surface
+fn face_look(padded: &PaddedChunk, registry: &BlockRegistry, pos: IVec3, dir: Direction) -> Option<Look> {
+    let (block, state) = padded.block_with_state(pos);
+    let state = filled_state(registry, block, state, Some(padded.block(pos + IVec3::Y)));
+    let front = pos + dir.offset();
+    let mut floor = 0;
+    if padded.block(front) == block && !registry.is_opaque(block) {
+        if matches!(dir, Direction::Top | Direction::Bottom) {
+            return None;
         }
+        let (_, front_state) = padded.block_with_state(front);
+        let front_state = filled_state(registry, block, front_state, Some(padded.block(front + IVec3::Y)));
+        floor = fluid_floor(registry, block, state, front_state)?;
+    }
+    //Faces are lit by the block in front of them
+    let light = padded.light(front);
+    let occlusion = face_occlusion(pos, dir, |pos| registry.is_opaque(padded.block(pos)));
+    Some((block, state, light, occlusion, floor))
+}
+
+//Fluid under more of the same fluid fills its block so it meets the fluid above
+pub(super) fn filled_state(
+    registry: &BlockRegistry,
+    block: Block,
+    state: BlockState,
+    above: Option<Block>,
+) -> BlockState {
+    if above == Some(block) && registry.fluid(block).is_some() {
+        state.with_flowing(true).with_level(BlockState::MAX_LEVEL)
+    } else {
+        state
     }
 }
 
+// Where the side face of a block against more of the same block starts, in sixteenths of a block
+// Only fluids come in different heights, where a fluid steps down the higher block shows its side above the lower one
+// None if the neighbor reaches as high, like for two of any other transparent block
+pub(super) fn fluid_floor(
+    registry: &BlockRegistry,
+    block: Block,
+    state: BlockState,
+    neighbor: BlockState,
+) -> Option<u8> {
+    registry.fluid(block)?;
+    let (height, neighbor) = (
+        registry.fluid_height(block, state),
+        registry.fluid_height(block, neighbor),
+    );
+    (neighbor < height).then(|| (neighbor * 16.0).round() as u8)
+}
+
 // Creates a single face on the mesh
-fn create_greedy_face(
+pub(super) fn create_greedy_face(
     block: Block,
+    state: BlockState,
+    light: Light,
+    occlusion: [u8; 4],
+    floor: u8,
     dir: Direction,
+    registry: &BlockRegistry,
     x: usize,
     y: usize,
     z: usize,
@@ -216,36 +479,42 @@ fn create_greedy_face(
     let width = width as f32;
     let height = height as f32;
     let (x, y, z) = (x as f32, y as f32, z as f32 + 1.0);
+    //Only fluids are shorter than a block, greedy meshing never stacks a short fluid on another block of the same state
+    //so lowering the top edge of the face is enough
+    let drop = 1.0 - registry.fluid_height(block, state);
+    let top = height + y - drop;
+    //Only side faces start part way up, the y of a top or bottom face is along the sheet
+    let bottom = y + floor as f32 / 16.0;
     let new_verts = match dir {
         Direction::Front => [
-            Vec3::new(z, y, width + x),
-            Vec3::new(z, y, x),
-            Vec3::new(z, height + y, x),
-            Vec3::new(z, height + y, width + x),
+            Vec3::new(z, bottom, width + x),
+            Vec3::new(z, bottom, x),
+            Vec3::new(z, top, x),
+            Vec3::new(z, top, width + x),
         ],
         Direction::Back => [
-            Vec3::new(z - 1.0, y, x),
-            Vec3::new(z - 1.0, y, width + x),
-            Vec3::new(z - 1.0, height + y, width + x),
-            Vec3::new(z - 1.0, height + y, x),
+            Vec3::new(z - 1.0, bottom, x),
+            Vec3::new(z - 1.0, bottom, width + x),
+            Vec3::new(z - 1.0, top, width + x),
+            Vec3::new(z - 1.0, top, x),
         ],
         Direction::Left => [
-            Vec3::new(x, y, z),
-            Vec3::new(width + x, y, z),
-            Vec3::new(width + x, height + y, z),
-            Vec3::new(x, height + y, z),
+            Vec3::new(x, bottom, z),
+            Vec3::new(width + x, bottom, z),
+            Vec3::new(width + x, top, z),
+            Vec3::new(x, top, z),
         ],
         Direction::Right => [
-            Vec3::new(width + x, y, z - 1.0),
-            Vec3::new(x, y, z - 1.0),
-            Vec3::new(x, height + y, z - 1.0),
-            Vec3::new(width + x, height + y, z - 1.0),
+            Vec3::new(width + x, bottom, z - 1.0),
+            Vec3::new(x, bottom, z - 1.0),
+            Vec3::new(x, top, z - 1.0),
+            Vec3::new(width + x, top, z - 1.0),
         ],
         Direction::Top => [
-            Vec3::new(x, z, height + y),
-            Vec3::new(width + x, z, height + y),
-            Vec3::new(width + x, z, y),
-            Vec3::new(x, z, y),
+            Vec3::new(x, z - drop, height + y),
+            Vec3::new(width + x, z - drop, height + y),
+            Vec3::new(width + x, z - drop, y),
+            Vec3::new(x, z - drop, y),
         ],
         Direction::Bottom => [
             Vec3::new(x, z - 1.0, y),
@@ -255,86 +524,181 @@ fn create_greedy_face(
         ],
     };
 
-    let new_uvs = [[0, height as u8], [width as u8, height as u8], [width as u8, 0], [0, 0]];
+    let (u, v) = (width as u16 * 16, height as u16 * 16 - floor as u16);
+    let new_uvs = [[0, v], [u, v], [u, 0], [0, 0]];
 
-    let new_texture_indices = [block.get_face_index(dir); 4];
-    let new_normals = match dir {
-        Direction::Front => [
-            Vec3::new(1.0, 0.0, 0.0),
-            Vec3::new(1.0, 0.0, 0.0),
-            Vec3::new(1.0, 0.0, 0.0),
-            Vec3::new(1.0, 0.0, 0.0),
-        ],
-        Direction::Back => [
-            Vec3::new(-1.0, 0.0, 0.0),
-            Vec3::new(-1.0, 0.0, 0.0),
-            Vec3::new(-1.0, 0.0, 0.0),
-            Vec3::new(-1.0, 0.0, 0.0),
-        ],
-        Direction::Left => [
-            Vec3::new(0.0, 0.0, 1.0),
-            Vec3::new(0.0, 0.0, 1.0),
-            Vec3::new(0.0, 0.0, 1.0),
-            Vec3::new(0.0, 0.0, 1.0),
-        ],
-        Direction::Right => [
-            Vec3::new(0.0, 0.0, -1.0),
-            Vec3::new(0.0, 0.0, -1.0),
-            Vec3::new(0.0, 0.0, -1.0),
-            Vec3::new(0.0, 0.0, -1.0),
-        ],
-        Direction::Top => [
-            Vec3::new(0.0, 1.0, 0.0),
-            Vec3::new(0.0, 1.0, 0.0),
-            Vec3::new(0.0, 1.0, 0.0),
-            Vec3::new(0.0, 1.0, 0.0),
-        ],
-        Direction::Bottom => [
-            Vec3::new(0.0, -1.0, 0.0),
-            Vec3::new(0.0, -1.0, 0.0),
-            Vec3::new(0.0, -1.0, 0.0),
-            Vec3::new(0.0, -1.0, 0.0),
-        ],
+    let new_texture_indices = [registry.face_texture(block, state, dir); 4];
+    let new_lights = [light; 4];
+    //Which sheet corner each vertex above is on
+    let corners = match dir {
+        Direction::Front | Direction::Right => [1, 0, 3, 2],
+        Direction::Back | Direction::Left | Direction::Bottom => [0, 1, 2, 3],
+        Direction::Top => [3, 2, 1, 0],
     };
-
-    //let rotation = dir.get_face_rotation();
-    ////FIXME normals aren't right maybe?
-    //new_normals.iter_mut().for_each(|vec| *vec = rotation * *vec);
-
-    let normals = new_normals
-        .iter()
-        .map(|norm| {
-            if norm.x > 0.5 {
-                [0, 1]
-            } else if norm.x < 0.5 {
-                [0, 2]
-            } else if norm.y > 0.5 {
-                [0, 3]
-            } else if norm.y < 0.5 {
-                [0, 4]
-            } else if norm.z > 0.5 {
-                [0, 5]
-            } else if norm.z < 0.5 {
-                [0, 6]
-            } else {
-                [0, 0]
-            }
-        })
-        .collect::<Vec<[u8; 2]>>();
-
+    let new_occlusion = corners.map(|corner| occlusion[corner]);
     let vert_start = mesh_description.verts.len();
     mesh_description.verts.extend_from_slice(&new_verts);
-    mesh_description.normals.extend_from_slice(&normals);
-    //info!("{:?}", new_normals);
-    mesh_description.true_normals.extend_from_slice(&new_normals);
+    mesh_description.directions.extend_from_slice(&[dir; 4]);
     mesh_description.uvs.extend_from_slice(&new_uvs);
 
     mesh_description.texture_indices.extend_from_slice(&new_texture_indices);
+    mesh_description.lights.extend_from_slice(&new_lights);
+    mesh_description.occlusion.extend_from_slice(&new_occlusion);
 
-    mesh_description
-        .vert_indicies
-        .extend_from_slice(&[vert_start, vert_start + 1, vert_start + 2]);
-    mesh_description
-        .vert_indicies
-        .extend_from_slice(&[vert_start, vert_start + 2, vert_start + 3]);
+    //Split the quad along the brighter diagonal, otherwise the occlusion is interpolated lopsided across it
+    let indices = if new_occlusion[0] + new_occlusion[2] >= new_occlusion[1] + new_occlusion[3] {
+        [
+            vert_start,
+            vert_start + 1,
+            vert_start + 2,
+            vert_start,
+            vert_start + 2,
+            vert_start + 3,
+        ]
+    } else {
+        [
+            vert_start + 1,
+            vert_start + 2,
+            vert_start + 3,
+            vert_start + 1,
+            vert_start + 3,
+            vert_start,
+        ]
+    };
+    if registry.is_opaque(block) {
+        mesh_description.vert_indicies.extend_from_slice(&indices);
+    } else {
+        mesh_description.transparent_indicies.extend_from_slice(&indices);
+    }
+    if registry.is_solid(block) {
+        mesh_description.collider_indicies.extend_from_slice(&indices);
+    }
+}
+
+//Axes a sheet's x and y run along for faces pointing in dir
+fn sheet_axes(dir: Direction) -> (IVec3, IVec3) {
+    match dir {
+        Direction::Front | Direction::Back => (IVec3::Z, IVec3::Y),
+        Direction::Left | Direction::Right => (IVec3::X, IVec3::Y),
+        Direction::Top | Direction::Bottom => (IVec3::X, IVec3::Z),
+    }
+}
+
+// How open each corner of a face is from the opaque blocks around the block it faces, 3 is open and 0 is fully shaded
+// Corners go around the sheet from its lowest corner, (-x, -y), (+x, -y), (+x, +y), (-x, +y)
+pub(super) fn face_occlusion(pos: IVec3, dir: Direction, occludes: impl Fn(IVec3) -> bool) -> [u8; 4] {
+    let (u, v) = sheet_axes(dir);
+    let facing = pos + dir.offset();
+    [(-1, -1), (1, -1), (1, 1), (-1, 1)].map(|(step_u, step_v)| {
+        let (u, v) = (u * step_u, v * step_v);
+        let (side_u, side_v) = (occludes(facing + u), occludes(facing + v));
+        //Two sides already shade the corner all the way whatever is in it
+        if side_u && side_v {
+            0
+        } else {
+            3 - side_u as u8 - side_v as u8 - occludes(facing + u + v) as u8
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn bitmask_mesher_covers_the_same_faces() {
+        let registry = BlockRegistry::default();
+        let blocks = ["metal", "glass", "water", "lava"].map(|name| registry.expect(name));
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut chunk = Chunk::default();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    if rng.gen_bool(0.5) {
+                        let state = BlockState::default().with_level(rng.gen_range(0..=BlockState::MAX_LEVEL));
+                        chunk.set_block_with_state(x, y, z, blocks[rng.gen_range(0..blocks.len())], state);
+                    }
+                }
+            }
+        }
+        let reference = create_reference_mesh_description(&chunk, &registry);
+        let bitmask = create_mesh_description(&chunk, &registry);
+        assert!(!reference.verts.is_empty());
+        assert_eq!(reference.coverage(), bitmask.coverage());
+    }
+
+    #[test]
+    fn downsampled_slab_is_not_copied_across_its_cube() {
+        let registry = BlockRegistry::default();
+        let mut chunk = Chunk::default();
+        chunk.set_block_with_state(1, 1, 1, registry.expect("metal_slab"), BlockState::default());
+        let full = create_padded_mesh_description(&PaddedChunk::new(&chunk), &registry);
+        let coarse = PaddedChunk::new(&chunk).downsampled(ChunkLod(8), &registry);
+        let coarse = create_padded_mesh_description(&coarse, &registry);
+        assert_eq!(full.verts.len(), 6 * 4);
+        assert!(coarse.verts.is_empty());
+    }
+
+    #[test]
+    fn slab_hides_only_faces_it_shares_with_opaque_blocks() {
+        let registry = BlockRegistry::default();
+        let metal = registry.expect("metal");
+        let mut chunk = Chunk::default();
+        chunk.set_block_with_state(4, 3, 4, metal, BlockState::default());
+        chunk.set_block_with_state(4, 4, 4, registry.expect("metal_slab"), BlockState::default());
+        chunk.set_block_with_state(4, 5, 4, metal, BlockState::default());
+        let coverage = create_mesh_description(&chunk, &registry).coverage();
+        //Squares along the normal are in sixteenths, the slab fills y 64 to 72
+        let shows = |normal: IVec3, square: [i32; 3]| coverage.contains_key(&(normal.to_array(), square));
+        //Resting on metal hides its bottom, its top is half a block under the metal above so it still shows
+        assert!(!shows(-IVec3::Y, [4, 64, 4]));
+        assert!(shows(IVec3::Y, [4, 72, 4]));
+        for (normal, square) in [
+            (IVec3::X, [80, 4, 4]),
+            (-IVec3::X, [64, 4, 4]),
+            (IVec3::Z, [4, 4, 80]),
+            (-IVec3::Z, [4, 4, 64]),
+        ] {
+            assert!(shows(normal, square), "side {}", normal);
+        }
+        //Models aren't opaque so the metal faces touching the slab show
+        assert!(shows(IVec3::Y, [4, 64, 4]));
+        assert!(shows(-IVec3::Y, [4, 80, 4]));
+    }
+
+    #[test]
+    fn fluid_steps_show_the_side_of_the_higher_block() {
+        let registry = BlockRegistry::default();
+        let water = registry.expect("water");
+        let mut chunk = Chunk::default();
+        //A source is 7/8 high and flowing water at level 5 is 5/8 high
+        chunk.set_block_with_state(4, 4, 4, water, BlockState::default());
+        let flowing = BlockState::default().with_flowing(true).with_level(5);
+        chunk.set_block_with_state(5, 4, 4, water, flowing);
+        let description = create_mesh_description(&chunk, &registry);
+        let coverage = description.coverage();
+        assert_eq!(
+            coverage,
+            create_reference_mesh_description(&chunk, &registry).coverage()
+        );
+        assert!(coverage.contains_key(&([1, 0, 0], [80, 4, 4])));
+        //The lower block is covered all the way up by the source
+        assert!(!coverage.contains_key(&([-1, 0, 0], [80, 4, 4])));
+
+        let step = (0..description.verts.len())
+            .step_by(4)
+            .find(|face| description.directions[*face] == Direction::Front && description.verts[*face].x == 5.0)
+            .unwrap();
+        let heights = description.verts[step..step + 4].iter().map(|vert| vert.y);
+        assert_eq!(heights.clone().reduce(f32::min), Some(4.625));
+        assert_eq!(heights.reduce(f32::max), Some(4.875));
+
+        //Level with each other nothing shows between them
+        chunk.set_block_with_state(5, 4, 4, water, BlockState::default());
+        let coverage = create_mesh_description(&chunk, &registry).coverage();
+        assert!(!coverage.contains_key(&([1, 0, 0], [80, 4, 4])));
+        assert!(!coverage.contains_key(&([-1, 0, 0], [80, 4, 4])));
+    }
 }
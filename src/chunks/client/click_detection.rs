@@ -1,3 +1,6 @@
+use bevy::input::mouse::MouseWheel;
+
+use crate::client::client_chunks::ServerBlockIds;
 use crate::prelude::*;
 
 pub struct ClickEvent {
@@ -12,6 +15,7 @@ pub(crate) fn click_to_break(
     comps: Query<&ChunkComp>,
     mut click_reader: EventReader<ClickEvent>,
     mut client: ResMut<RenetClient>,
+    registry: Res<BlockRegistry>,
 ) {
     for ev in click_reader.iter() {
         if ev.button == MouseButton::Left {
@@ -19,32 +23,133 @@ pub(crate) fn click_to_break(
             if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
                 ClientMessage::BreakBlock(ev.world_pos).send(&mut client);
                 let chunk = comps.get(*chunk).unwrap();
-                chunk.write_block(offset, Block::Air);
+                chunk.write_block(offset, Block::AIR, &registry);
             }
         }
     }
 }
 
+// The block placed on right click
+pub struct SelectedBlock(pub Block);
+
+//A startup system because the registry plugin is added after the chunk plugins
+pub(crate) fn init_selected_block(mut commands: Commands, registry: Res<BlockRegistry>) {
+    commands.insert_resource(SelectedBlock(registry.expect("machine")));
+}
+
+//Number keys pick the nth block in the registry, the scroll wheel cycles through all of them
+pub(crate) fn select_block(
+    keys: Res<Input<KeyCode>>,
+    mut scroll: EventReader<MouseWheel>,
+    registry: Res<BlockRegistry>,
+    mut selected: ResMut<SelectedBlock>,
+) {
+    let number_keys = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+    //Air can't be placed so it is skipped
+    let placeable = registry
+        .blocks()
+        .filter(|block| !block.is_air())
+        .collect::<Vec<Block>>();
+    let current = placeable.iter().position(|block| *block == selected.0).unwrap_or(0);
+
+    let mut new_selection = number_keys
+        .iter()
+        .position(|key| keys.just_pressed(*key))
+        .filter(|index| *index < placeable.len());
+    for ev in scroll.iter() {
+        let step = if ev.y > 0.0 { placeable.len() - 1 } else { 1 };
+        new_selection = Some((new_selection.unwrap_or(current) + step) % placeable.len());
+    }
+
+    if let Some(index) = new_selection {
+        selected.0 = placeable[index];
+        info!("Selected {}", registry.name(selected.0));
+    }
+}
+
 pub(crate) fn click_to_place(
     loaded_chunks: Res<LoadedChunks>,
     comps: Query<&ChunkComp>,
     mut click_reader: EventReader<ClickEvent>,
     mut client: ResMut<RenetClient>,
+    selected: Res<SelectedBlock>,
+    registry: Res<BlockRegistry>,
+    ids: Res<ServerBlockIds>,
+    camera: Query<&Transform, With<Camera3d>>,
 ) {
     for ev in click_reader.iter() {
         if ev.button == MouseButton::Right {
+            //Levers, buttons, gates and controllers are used instead of built on, the server sends back the result
+            let (chunk_pos, offset) = Chunk::i_world_to_chunk(ev.world_pos);
+            if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
+                let clicked = comps.get(*chunk).unwrap().read_block(offset);
+                match registry.logic(clicked) {
+                    Some(LogicKind::Controller) => {
+                        let _ = ClientMessage::OpenProgram(ev.world_pos).send(&mut client);
+                        continue;
+                    }
+                    Some(logic) if logic.is_interactable() => {
+                        let _ = ClientMessage::InteractBlock(ev.world_pos).send(&mut client);
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
             let (chunk_pos, offset) = Chunk::i_world_to_chunk(ev.prev_pos);
             if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
                 let chunk = comps.get(*chunk).unwrap();
-                if chunk.read_block(offset) == Block::Air {
-                    ClientMessage::PlaceBlock(ev.prev_pos, Block::Machine).send(&mut client);
-                    chunk.write_block(offset, Block::Machine);
+                if chunk.read_block(offset).is_air() {
+                    let mut state = BlockState::default();
+                    if registry.get(selected.0).rotatable {
+                        let mut facing = placement_facing(camera.single().forward());
+                        //Gates output away from the player so circuits are built outwards
+                        if matches!(registry.logic(selected.0), Some(LogicKind::Gate(_))) {
+                            facing = facing.opposite();
+                        }
+                        state = state.with_facing(facing);
+                    }
+                    let block = match ids.server(selected.0) {
+                        Some(block) => block,
+                        None => {
+                            warn!("The server doesn't have {}, not placing it", registry.name(selected.0));
+                            continue;
+                        }
+                    };
+                    ClientMessage::PlaceBlock(ev.prev_pos, block, state).send(&mut client);
+                    chunk.write_block_with_state(offset, selected.0, state, &registry);
                 }
             }
         }
     }
 }
 
+//Rotatable blocks are placed with their front towards the player
+fn placement_facing(forward: Vec3) -> Direction {
+    let towards_player = -forward;
+    if towards_player.x.abs() > towards_player.z.abs() {
+        if towards_player.x > 0.0 {
+            Direction::Front
+        } else {
+            Direction::Back
+        }
+    } else if towards_player.z > 0.0 {
+        Direction::Left
+    } else {
+        Direction::Right
+    }
+}
+
 pub(crate) fn click_detection(
     mouse: Res<Input<MouseButton>>,
     transform: Query<&Transform, With<Camera3d>>,
@@ -72,7 +177,7 @@ pub(crate) fn click_detection(
             if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
                 let chunk = comps.get(*chunk).unwrap();
 
-                if chunk.read_block(offset) != Block::Air {
+                if !chunk.read_block(offset).is_air() {
                     //Rewind for placement
                     let block_pos = current - Vec3::ONE / 2.0 - inc;
                     let prev_pos = block_pos.round().as_ivec3();
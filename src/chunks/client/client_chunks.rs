@@ -2,11 +2,13 @@ use std::sync::{Arc, RwLock};
 
 use bevy::{
     tasks::{AsyncComputeTaskPool, Task},
-    utils::{FloatOrd, HashMap},
+    utils::{FloatOrd, HashMap, HashSet},
 };
 use futures_lite::future;
 
 use crate::client::click_detection::*;
+use crate::client::falling_blocks::*;
+use crate::client::program_editor::*;
 use crate::prelude::*;
 
 pub struct ClientChunkPlugin;
@@ -14,67 +16,261 @@ pub struct ClientChunkPlugin;
 impl Plugin for ClientChunkPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<LoadedChunks>()
+            .init_resource::<RequestedChunks>()
+            .init_resource::<ProgramEditor>()
+            .init_resource::<OcclusionCulling>()
+            .init_resource::<ServerBlockIds>()
+            .add_startup_system(init_selected_block)
             .add_event::<ClickEvent>()
             .add_system(spawn_chunk_meshes)
             .add_system_set(
                 SystemSet::on_enter(ClientState::Gameplay)
-                    .with_system(initial_chunk_requests)
                     //TODO run on image loaded
                     .with_system(create_array_texture),
             )
+            .add_system_set(
+                SystemSet::on_update(ClientState::Gameplay)
+                    .with_system(request_chunks_in_view)
+                    .with_system(unload_distant_chunks)
+                    .with_system(update_chunk_lods),
+            )
+            .add_system(
+                receive_block_names
+                    .before(load_chunks_from_server)
+                    .before(apply_block_updates)
+                    .before(sync_falling_blocks),
+            )
             .add_system(load_chunks_from_server)
-            .add_system(update_dirt_sys)
+            .add_system(apply_block_updates)
             .add_system(update_dirty_chunks)
-            .add_system_to_stage(CoreStage::PostUpdate, apply_buffered_chunk_writes)
             .add_startup_system_to_stage(StartupStage::PreStartup, client::material::load_chunk_texture)
-            .add_system_set(SystemSet::on_update(ClientState::Gameplay).with_system(click_detection))
+            .add_system_set(
+                SystemSet::on_update(ClientState::Gameplay)
+                    .with_system(click_detection)
+                    .with_system(select_block)
+                    .with_system(receive_programs)
+                    .with_system(program_editor_window)
+                    .with_system(cull_hidden_chunks)
+                    .with_system(occlusion_culling_window)
+                    .with_system(sync_falling_blocks),
+            )
             .add_system(click_to_break.with_run_criteria(run_if_client_connected))
             .add_system(click_to_place.with_run_criteria(run_if_client_connected));
     }
 }
 
+// Carries the detail the chunk is meshed at, picked by how far it was from the camera when it arrived
 #[derive(Component)]
-pub struct CreateChunkTask(Task<(Chunk, Mesh, MeshDescription)>);
+pub struct CreateChunkTask(Task<(Arc<RwLock<Chunk>>, ChunkMeshes, MeshDescription)>, ChunkLod);
+
+// The child entity drawing a chunk's transparent faces
+#[derive(Component)]
+pub struct TransparentMesh(pub Entity);
+
+// Block ids are indices into each side's own registry, they only agree when both loaded the same definitions
+// Maps the server's ids to ours and back, until the server's names arrive ids are passed through as they are
+#[derive(Default)]
+pub struct ServerBlockIds {
+    //What the server sent, kept to rebuild the maps when our own definitions are reloaded
+    names: Vec<String>,
+    to_local: Vec<Block>,
+    to_server: HashMap<Block, Block>,
+}
+
+impl ServerBlockIds {
+    fn new(names: Vec<String>, registry: &BlockRegistry) -> Self {
+        let mut to_server = HashMap::default();
+        let to_local = names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| match registry.id(name) {
+                Some(local) => {
+                    to_server.insert(local, Block::from_id(id as u16));
+                    local
+                }
+                None => {
+                    warn!("The server has a block {:?} we don't, it's shown as air", name);
+                    Block::AIR
+                }
+            })
+            .collect();
+        ServerBlockIds {
+            names,
+            to_local,
+            to_server,
+        }
+    }
+
+    pub fn local(&self, block: Block) -> Block {
+        if self.names.is_empty() {
+            return block;
+        }
+        self.to_local.get(block.id() as usize).copied().unwrap_or(Block::AIR)
+    }
+
+    //None for blocks the server doesn't have
+    pub fn server(&self, block: Block) -> Option<Block> {
+        if self.names.is_empty() {
+            return Some(block);
+        }
+        self.to_server.get(&block).copied()
+    }
+}
 
-pub fn load_chunks_from_server(mut commands: Commands, messages: Res<CurrentClientBlockMessages>) {
+pub fn receive_block_names(
+    messages: Res<CurrentClientBlockMessages>,
+    registry: Res<BlockRegistry>,
+    mut ids: ResMut<ServerBlockIds>,
+) {
+    let names = messages
+        .iter()
+        .filter_map(|message| match message {
+            ServerBlockMessage::BlockNames(names) => Some(names),
+            _ => None,
+        })
+        .last();
+    if let Some(names) = names {
+        *ids = ServerBlockIds::new(names.clone(), &registry);
+    } else if registry.is_changed() && !ids.names.is_empty() {
+        let names = std::mem::take(&mut ids.names);
+        *ids = ServerBlockIds::new(names, &registry);
+    }
+}
+
+pub fn load_chunks_from_server(
+    mut commands: Commands,
+    messages: Res<CurrentClientBlockMessages>,
+    registry: Res<BlockRegistry>,
+    ids: Res<ServerBlockIds>,
+    camera: Query<&Transform, With<Camera3d>>,
+) {
+    let camera_chunk = camera
+        .get_single()
+        .map_or(IVec3::ZERO, |camera| Chunk::world_to_chunk(camera.translation).0);
     for message in messages.iter() {
         if let ServerBlockMessage::Chunk(chunk) = message {
-            let chunk_data = Chunk::from_compressed(chunk);
+            let mut chunk_data = Chunk::from_compressed(chunk);
+            chunk_data.map_blocks(|block| ids.local(block));
+            let lod = ChunkLod::for_distance(chunk_data.pos, camera_chunk);
+            let registry = registry.clone();
             let thread_pool = AsyncComputeTaskPool::get();
             let task = thread_pool.spawn(async move {
                 let _span = info_span!("Chunk Generation Task", name = "Chunk Generation Task").entered();
-                let (mesh, desc) = create_chunk_mesh(&chunk_data);
-                (chunk_data, mesh, desc)
+                let chunk_pos = chunk_data.pos;
+                let chunk = Arc::new(RwLock::new(chunk_data));
+                //Lit on its own here and linked up with the light around it once it's spawned
+                let mut world = LinkedChunks::default();
+                world.add_chunk(chunk.clone());
+                light_chunk(&mut world, &registry, chunk_pos);
+                let padded = PaddedChunk::new(&chunk.read().unwrap()).downsampled(lod, &registry);
+                let (meshes, desc) = create_padded_chunk_mesh(&padded, &registry);
+                (chunk, meshes, desc)
             });
-            commands.spawn().insert(CreateChunkTask(task));
+            commands.spawn().insert(CreateChunkTask(task, lod));
         }
     }
 }
 
-pub fn initial_chunk_requests(mut client: ResMut<RenetClient>) {
-    info!("Init Chunks");
-    if client.is_connected() {
-        let chunks_to_spawn = (WORLD_SIZE / 2) as i32 + 1;
+pub fn apply_block_updates(
+    messages: Res<CurrentClientBlockMessages>,
+    loaded_chunks: Res<LoadedChunks>,
+    requested: Res<RequestedChunks>,
+    comps: Query<&ChunkComp>,
+    registry: Res<BlockRegistry>,
+    ids: Res<ServerBlockIds>,
+    //Updates for chunks that are still on their way or being meshed
+    mut waiting: Local<Vec<BlockChange>>,
+) {
+    for message in messages.iter() {
+        if let ServerBlockMessage::BlockUpdates(updates) = message {
+            waiting.extend(
+                updates
+                    .iter()
+                    .map(|(pos, block, state)| (*pos, ids.local(*block), *state)),
+            );
+        }
+    }
+    waiting.retain(|(pos, block, state)| {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(*pos);
+        match loaded_chunks.ent_map.get(&chunk_pos).map(|chunk| comps.get(*chunk)) {
+            Some(Ok(chunk)) => {
+                chunk.write_block_with_state(offset, *block, *state, &registry);
+                false
+            }
+            //Only keep waiting if the chunk is actually coming
+            _ => requested.contains(&chunk_pos) || loaded_chunks.ent_map.contains_key(&chunk_pos),
+        }
+    });
+}
+
+//Chunks that have been asked for but haven't arrived yet so they aren't requested every frame
+#[derive(Default, Deref, DerefMut)]
+pub struct RequestedChunks(HashSet<IVec3>);
 
-        let mut request = Vec::default();
-        for x in -chunks_to_spawn..chunks_to_spawn {
-            for y in -chunks_to_spawn..chunks_to_spawn {
-                for z in -chunks_to_spawn..chunks_to_spawn {
-                    request.push(IVec3::new(x, y, z));
+pub fn request_chunks_in_view(
+    mut client: ResMut<RenetClient>,
+    camera: Query<&Transform, With<Camera3d>>,
+    loaded_chunks: Res<LoadedChunks>,
+    mut requested: ResMut<RequestedChunks>,
+) {
+    let (camera_chunk, _) = Chunk::world_to_chunk(camera.single().translation);
+
+    let mut request = Vec::default();
+    for x in -VIEW_DISTANCE..=VIEW_DISTANCE {
+        for y in -VIEW_DISTANCE..=VIEW_DISTANCE {
+            for z in -VIEW_DISTANCE..=VIEW_DISTANCE {
+                let pos = camera_chunk + IVec3::new(x, y, z);
+                if !loaded_chunks.ent_map.contains_key(&pos) && !requested.contains(&pos) {
+                    request.push(pos);
                 }
             }
         }
+    }
+
+    request.sort_by_key(|pos| FloatOrd(Vec3::distance(camera_chunk.as_vec3(), pos.as_vec3())));
+
+    for pos in request.into_iter().take(MAX_CHUNK_REQUESTS_PER_FRAME) {
+        if ClientMessage::RequestChunk(pos).send(&mut client).is_err() {
+            //Channel is full, try again next frame
+            break;
+        }
+        info!("Requesting Chunk {:?}", pos);
+        requested.insert(pos);
+    }
+}
+
+pub fn unload_distant_chunks(
+    mut commands: Commands,
+    mut client: ResMut<RenetClient>,
+    camera: Query<&Transform, With<Camera3d>>,
+    chunks: Query<&ChunkComp>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    mut requested: ResMut<RequestedChunks>,
+    mut unsent_unloads: Local<Vec<IVec3>>,
+) {
+    let (camera_chunk, _) = Chunk::world_to_chunk(camera.single().translation);
 
-        //TODO closest to player
-        request.sort_by_key(|pos| FloatOrd(Vec3::distance(Vec3::ZERO, pos.as_vec3())));
+    let to_unload = loaded_chunks
+        .ent_map
+        .keys()
+        .filter(|pos| (**pos - camera_chunk).abs().max_element() > UNLOAD_DISTANCE)
+        .copied()
+        .collect::<Vec<IVec3>>();
 
-        request.iter().for_each(|request| {
-            info!("Requesting Chunk {:?}", request);
-            ClientMessage::RequestChunk(*request).send(&mut client);
-        });
-    } else {
-        error!("Not connected to a server!");
+    for pos in to_unload {
+        let ent = loaded_chunks.ent_map.remove(&pos).unwrap();
+        //Might not have a comp yet if it was spawned this frame, then nothing has been linked to it either
+        if let Ok(chunk) = chunks.get(ent) {
+            chunk.disconnect_neighbors();
+        }
+        //Takes the mesh and collider with it
+        commands.entity(ent).despawn_recursive();
+        requested.remove(&pos);
+        unsent_unloads.push(pos);
     }
+
+    //The server keeps the chunk alive and keeps sending edits until it hears about this
+    unsent_unloads.retain(|pos| ClientMessage::UnloadChunk(*pos).send(&mut client).is_err());
 }
 
 pub fn spawn_chunk_meshes(
@@ -86,109 +282,71 @@ pub fn spawn_chunk_meshes(
     mut meshes: ResMut<Assets<Mesh>>,
     texture: Res<client::material::ChunkTexture>,
     mut loaded_chunks: ResMut<LoadedChunks>,
+    mut requested: ResMut<RequestedChunks>,
+    registry: Res<BlockRegistry>,
 ) {
     let mut spawned_this_frame = HashMap::default();
     let mut updates = 0;
     for (ent, mut task) in &mut tasks {
-        if let Some((chunk, mesh, mesh_data)) = future::block_on(future::poll_once(&mut task.0)) {
-            let chunk_pos = chunk.pos;
-            let pos = CHUNK_SIZE as i32 * chunk.pos;
+        if let Some((arc, chunk_meshes, mesh_data)) = future::block_on(future::poll_once(&mut task.0)) {
+            let lod = task.1;
+            let chunk_pos = arc.read().unwrap().pos;
+            let pos = CHUNK_SIZE as i32 * chunk_pos;
+
+            //Unloaded while it was being meshed, spawned now the server would never send it edits
+            if !requested.contains(&chunk_pos) && !loaded_chunks.ent_map.contains_key(&chunk_pos) {
+                commands.entity(ent).despawn();
+                continue;
+            }
 
-            let arc = Arc::new(RwLock::new(chunk));
             //Check doesn't already exists!
             if let Some(chunk) = loaded_chunks.ent_map.remove(&chunk_pos) {
                 commands.entity(chunk).despawn_recursive();
             }
             loaded_chunks.ent_map.insert(chunk_pos, ent);
+            let mut world = LinkedChunks::default();
+            world.add_chunk(arc.clone());
             let arc = ChunkComp::new(arc);
 
-            fn connect_neighbor(
-                pos: IVec3,
-                dir: Direction,
-                loaded_chunks: &ResMut<LoadedChunks>,
-                chunks: &Query<&ChunkComp>,
-                comp: &ChunkComp,
-                spawned_this_frame: &HashMap<Entity, ChunkComp>,
-            ) {
-                if loaded_chunks.ent_map.contains_key(&pos) {
-                    //Set this chunks top neighbor
-                    //Set the top neighbors bottom to this chunk
-                    if let Ok(neighbor) = chunks.get(loaded_chunks.ent_map[&pos]) {
-                        comp.set_neighbor(dir, neighbor);
-                        neighbor.set_neighbor(dir.opposite(), comp);
-                        //Dirty neighbors because I have changed
-                        neighbor.write_dirty(true);
-                    } else {
-                        //Spawned this frame
-                        let neighbor = &spawned_this_frame[&loaded_chunks.ent_map[&pos]];
-                        comp.set_neighbor(dir, neighbor);
-                        neighbor.set_neighbor(dir.opposite(), comp);
-                        neighbor.write_dirty(true);
-                    }
-                }
-            }
-
-            connect_neighbor(
-                chunk_pos + IVec3::Y,
-                Direction::Top,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-            connect_neighbor(
-                chunk_pos - IVec3::Y,
-                Direction::Bottom,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-
-            connect_neighbor(
-                chunk_pos + IVec3::X,
-                Direction::Front,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-            connect_neighbor(
-                chunk_pos - IVec3::X,
-                Direction::Back,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-
-            connect_neighbor(
-                chunk_pos + IVec3::Z,
-                Direction::Left,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-            connect_neighbor(
-                chunk_pos - IVec3::Z,
-                Direction::Right,
-                &loaded_chunks,
-                &chunks,
-                &arc,
-                &spawned_this_frame,
-            );
-            commands.entity(ent).insert_bundle(MaterialMeshBundle {
-                mesh: meshes.add(mesh),
-                //mesh: meshes.add(shape::Box::default().into()),
-                material: materials.add(CustomMaterial {
-                    textures: texture.0.clone(),
-                }),
-                transform: Transform::from_xyz(pos.x as f32, pos.y as f32, pos.z as f32),
-
-                ..default()
+            requested.remove(&chunk_pos);
+            arc.connect_neighbors(chunk_pos, |pos| {
+                let neighbor = loaded_chunks.ent_map.get(&pos)?;
+                chunks.get(*neighbor).ok().or_else(|| spawned_this_frame.get(neighbor))
             });
-            add_collider(&mut commands, ent, mesh_data);
+            link_chunk_light(&mut world, &registry, chunk_pos);
+            let transparent = commands
+                .spawn_bundle(MaterialMeshBundle {
+                    mesh: meshes.add(chunk_meshes.transparent),
+                    material: materials.add(CustomMaterial {
+                        textures: texture.0.clone(),
+                        transparent: true,
+                    }),
+                    ..default()
+                })
+                .insert(ChunkMeshes::aabb())
+                .insert(Name::new("Transparent Chunk Mesh"))
+                .id();
+            commands
+                .entity(ent)
+                .insert_bundle(MaterialMeshBundle {
+                    mesh: meshes.add(chunk_meshes.opaque),
+                    //mesh: meshes.add(shape::Box::default().into()),
+                    material: materials.add(CustomMaterial {
+                        textures: texture.0.clone(),
+                        transparent: false,
+                    }),
+                    transform: Transform::from_xyz(pos.x as f32, pos.y as f32, pos.z as f32),
+
+                    ..default()
+                })
+                .insert(ChunkMeshes::aabb())
+                .insert(TransparentMesh(transparent))
+                .insert(chunk_meshes.visibility)
+                .insert(lod)
+                .add_child(transparent);
+            if lod.has_collider() {
+                add_collider(&mut commands, ent, mesh_data);
+            }
             spawned_this_frame.insert(ent, arc);
 
             commands.entity(ent).remove::<CreateChunkTask>();
@@ -202,3 +360,27 @@ pub fn spawn_chunk_meshes(
         commands.entity(ent).insert(comp);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_ids_are_matched_by_name() {
+        let registry = BlockRegistry::default();
+        let (metal, glass) = (registry.expect("metal"), registry.expect("glass"));
+        let passed_through = ServerBlockIds::default();
+        assert_eq!(passed_through.local(metal), metal);
+        assert_eq!(passed_through.server(metal), Some(metal));
+
+        let names = ["air", "glass", "only_on_server", "metal"].map(String::from).to_vec();
+        let ids = ServerBlockIds::new(names, &registry);
+        assert_eq!(ids.local(Block::from_id(1)), glass);
+        assert_eq!(ids.local(Block::from_id(3)), metal);
+        assert_eq!(ids.server(metal), Some(Block::from_id(3)));
+        //Blocks only one side has are air coming in and never sent
+        assert_eq!(ids.local(Block::from_id(2)), Block::AIR);
+        assert_eq!(ids.local(Block::from_id(200)), Block::AIR);
+        assert_eq!(ids.server(registry.expect("sand")), None);
+    }
+}
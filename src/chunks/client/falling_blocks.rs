@@ -0,0 +1,102 @@
+use bevy::utils::HashMap;
+
+use crate::client::client_chunks::ServerBlockIds;
+use crate::prelude::*;
+
+//Falling bodies not heard about for this long have landed, the block updates put their blocks back in the chunks
+const FALLING_BODY_TIMEOUT_SECONDS: f64 = 0.5;
+
+// Client copy of a body the server is simulating, only moved to wherever the server says it is
+#[derive(Component)]
+pub struct FallingBodyVisual {
+    id: u64,
+    last_seen: f64,
+}
+
+pub fn sync_falling_blocks(
+    mut commands: Commands,
+    messages: Res<CurrentClientMessages>,
+    block_messages: Res<CurrentClientBlockMessages>,
+    mut visuals: Query<(Entity, &mut FallingBodyVisual, &mut Transform)>,
+    registry: Res<BlockRegistry>,
+    ids: Res<ServerBlockIds>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<CustomMaterial>>,
+    texture: Res<client::material::ChunkTexture>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    for message in block_messages.iter() {
+        if let ServerBlockMessage::FallingBody(id, translation, blocks) = message {
+            let blocks = blocks
+                .iter()
+                .map(|(offset, block, state)| (*offset, ids.local(*block), *state))
+                .collect::<Vec<_>>();
+            commands
+                .spawn_bundle(SpatialBundle::from_transform(Transform::from_translation(*translation)))
+                .insert(FallingBodyVisual {
+                    id: *id,
+                    last_seen: now,
+                })
+                .insert(Name::new("Falling Body"))
+                .with_children(|parent| {
+                    //Meshed like chunks holding just the body's blocks so they look the same as they did in the grid
+                    for (chunk_pos, chunk) in body_chunks(&blocks) {
+                        let chunk_meshes = create_chunk_mesh(&chunk, &registry).0;
+                        for (mesh, transparent) in [(chunk_meshes.opaque, false), (chunk_meshes.transparent, true)] {
+                            parent
+                                .spawn_bundle(MaterialMeshBundle {
+                                    mesh: meshes.add(mesh),
+                                    material: materials.add(CustomMaterial {
+                                        textures: texture.0.clone(),
+                                        transparent,
+                                    }),
+                                    transform: Transform::from_translation((chunk_pos * CHUNK_SIZE as i32).as_vec3()),
+                                    ..default()
+                                })
+                                .insert(ChunkMeshes::aabb());
+                        }
+                    }
+                });
+        }
+    }
+
+    //Bodies spawned above only exist next frame, their first snapshots are the same as where they started
+    let mut visuals = visuals
+        .iter_mut()
+        .map(|(entity, visual, transform)| (visual.id, (entity, visual, transform)))
+        .collect::<HashMap<_, _>>();
+    for message in messages.iter() {
+        let snapshots = match message {
+            ServerMessage::FallingBodies(snapshots) => snapshots,
+            _ => continue,
+        };
+        for snapshot in snapshots {
+            if let Some((_, visual, transform)) = visuals.get_mut(&snapshot.id) {
+                visual.last_seen = now;
+                **transform = Transform::from_translation(snapshot.translation).with_rotation(snapshot.rotation);
+            }
+        }
+    }
+    for (entity, visual, _) in visuals.values() {
+        if now - visual.last_seen > FALLING_BODY_TIMEOUT_SECONDS {
+            commands.entity(*entity).despawn_recursive();
+        }
+    }
+}
+
+//Bodies can be bigger than a chunk, their blocks are split up by which chunk sized piece of the body they are in
+fn body_chunks(blocks: &[BlockChange]) -> HashMap<IVec3, Chunk> {
+    let mut chunks: HashMap<IVec3, Chunk> = HashMap::default();
+    for (offset, block, state) in blocks {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(*offset);
+        chunks.entry(chunk_pos).or_default().set_block_with_state(
+            offset.x as usize,
+            offset.y as usize,
+            offset.z as usize,
+            *block,
+            *state,
+        );
+    }
+    chunks
+}
@@ -0,0 +1,65 @@
+use crate::prelude::*;
+
+// How many blocks along each side are meshed as one, far chunks are drawn coarser so more of them can be loaded
+// Only chunks drawn at full detail get a collider, nothing is close enough to touch the others
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLod(pub usize);
+
+impl ChunkLod {
+    pub const FULL: ChunkLod = ChunkLod(1);
+
+    //Detail halves at each of LOD_DISTANCES, counted in chunks like VIEW_DISTANCE
+    pub fn for_distance(chunk_pos: IVec3, camera_chunk: IVec3) -> ChunkLod {
+        let distance = (chunk_pos - camera_chunk).abs().max_element();
+        ChunkLod(1 << LOD_DISTANCES.iter().filter(|start| distance >= **start).count())
+    }
+
+    pub fn has_collider(self) -> bool {
+        self == ChunkLod::FULL
+    }
+}
+
+// Chunks the camera moved closer to or further from are marked dirty to be remeshed at their new detail
+pub fn update_chunk_lods(camera: Query<&Transform, With<Camera3d>>, mut chunks: Query<(&ChunkComp, &mut ChunkLod)>) {
+    let camera_chunk = match camera.get_single() {
+        Ok(camera) => Chunk::world_to_chunk(camera.translation).0,
+        Err(_) => return,
+    };
+    for (chunk, mut lod) in &mut chunks {
+        let wanted = ChunkLod::for_distance(chunk.read_chunk().pos, camera_chunk);
+        if *lod != wanted {
+            *lod = wanted;
+            chunk.write_dirty(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detail_halves_at_each_lod_distance() {
+        let camera = IVec3::new(3, -2, 7);
+        let lod = |offset: IVec3| ChunkLod::for_distance(camera + offset, camera).0;
+        assert_eq!(lod(IVec3::ZERO), 1);
+        assert_eq!(lod(IVec3::new(0, LOD_DISTANCES[0] - 1, 0)), 1);
+        assert_eq!(lod(IVec3::new(0, 0, LOD_DISTANCES[0])), 2);
+        assert_eq!(lod(IVec3::new(-LOD_DISTANCES[0], 1, 1)), 2);
+        //The furthest axis picks the detail
+        assert_eq!(lod(IVec3::new(1, -LOD_DISTANCES[1], 1)), 4);
+        assert_eq!(lod(IVec3::new(LOD_DISTANCES[2], 0, 0)), 8);
+        assert_eq!(lod(IVec3::new(-40, 0, 0)), 8);
+        assert!(ChunkLod::for_distance(camera, camera).has_collider());
+        assert!(!ChunkLod(2).has_collider());
+    }
+
+    #[test]
+    fn lod_distances_fit_the_view_distance() {
+        //Lower detail has to start inside the loaded chunks or it never shows
+        assert!(LOD_DISTANCES[0] <= VIEW_DISTANCE);
+        //The chunks around the camera's are close enough to walk into and need their colliders
+        assert!(LOD_DISTANCES[0] >= 2);
+        assert!(LOD_DISTANCES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
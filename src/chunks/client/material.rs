@@ -6,11 +6,13 @@ use bevy::{
     render::{
         mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
         render_resource::{
-            AsBindGroup, CompareFunction, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
-            VertexFormat,
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, VertexFormat,
         },
     },
 };
+
+use crate::prelude::{Direction, Light};
+
 pub struct ChunkTexture(pub Handle<Image>);
 
 pub fn load_chunk_texture(mut commands: Commands, server: Res<AssetServer>) {
@@ -35,13 +37,42 @@ pub fn create_array_texture(
     image.reinterpret_stacked_2d_as_array(array_layers);
 }
 
-pub const CUSTOM_UV: MeshVertexAttribute = MeshVertexAttribute::new("CustomUV", 52894552143, VertexFormat::Uint8x2);
+// Every chunk vertex is two u32s, decoded again in custom_material.wgsl
+// Bevy's mesh pipeline won't specialize without a position and a normal, so the packed streams take their ids
+// Position word: x, y and z in sixteenths of a block at 9 bits each, then the face direction and corner occlusion
+pub const ATTRIBUTE_PACKED_POSITION: MeshVertexAttribute =
+    MeshVertexAttribute::new("PackedPosition", 0, VertexFormat::Uint32);
+// Face word: the corner's uv in sixteenths of a block at 9 bits each, sky and block light, then the texture layer
+pub const ATTRIBUTE_PACKED_FACE: MeshVertexAttribute = MeshVertexAttribute::new("PackedFace", 1, VertexFormat::Uint32);
 
-pub const CUSTOM_NORMAL: MeshVertexAttribute =
-    MeshVertexAttribute::new("CutsomNormal", 1374029579328, VertexFormat::Uint8x2);
+const POSITION_BITS: u32 = 9;
+const UV_BITS: u32 = 9;
+//Layers past this don't fit in what's left of the face word
+pub const MAX_TEXTURE_LAYERS: u32 = 1 << 6;
 
-pub const ATTRIBUTE_TEXTURE_INDEX: MeshVertexAttribute =
-    MeshVertexAttribute::new("TextureIndex", 15092354854, VertexFormat::Uint32);
+//Positions are chunk local and on a sixteenth of a block, a chunk's far edge is the largest
+pub fn pack_position(pos: Vec3, dir: Direction, occlusion: u8) -> u32 {
+    let [x, y, z] = pos.to_array().map(|axis| {
+        let sixteenths = (axis * 16.0).round() as u32;
+        debug_assert!(sixteenths < 1 << POSITION_BITS, "{} is outside the chunk", pos);
+        sixteenths
+    });
+    x | y << POSITION_BITS | z << (2 * POSITION_BITS) | (dir as u32) << (3 * POSITION_BITS) | (occlusion as u32) << 30
+}
+
+//Quads are at most a chunk across so their uvs run from 0 to CHUNK_SIZE blocks
+pub fn pack_face(uv: [u16; 2], light: Light, texture: u32) -> u32 {
+    debug_assert!(
+        texture < MAX_TEXTURE_LAYERS,
+        "Texture layer {} doesn't fit in a vertex",
+        texture
+    );
+    uv[0] as u32
+        | (uv[1] as u32) << UV_BITS
+        | (light.sky() as u32) << (2 * UV_BITS)
+        | (light.block() as u32) << (2 * UV_BITS + 4)
+        | texture << (2 * UV_BITS + 8)
+}
 
 #[derive(AsBindGroup, Debug, Clone, TypeUuid)]
 #[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
@@ -49,6 +80,8 @@ pub struct CustomMaterial {
     #[texture(0, dimension = "2d_array")]
     #[sampler(1)]
     pub textures: Handle<Image>,
+    //Transparent faces are blended and drawn back to front after everything opaque
+    pub transparent: bool,
 }
 
 impl Material for CustomMaterial {
@@ -58,8 +91,13 @@ impl Material for CustomMaterial {
     fn fragment_shader() -> ShaderRef {
         "custom_material.wgsl".into()
     }
+    //Bevy sorts blended meshes by distance from the camera, so the transparent meshes of chunks are drawn far to near
     fn alpha_mode(&self) -> AlphaMode {
-        AlphaMode::Blend
+        if self.transparent {
+            AlphaMode::Blend
+        } else {
+            AlphaMode::Opaque
+        }
     }
 
     fn specialize(
@@ -69,14 +107,9 @@ impl Material for CustomMaterial {
         _key: MaterialPipelineKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
         let vertex_layout = layout.get_layout(&[
-            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
-            CUSTOM_NORMAL.at_shader_location(1),
-            CUSTOM_UV.at_shader_location(2),
-            ATTRIBUTE_TEXTURE_INDEX.at_shader_location(3),
+            ATTRIBUTE_PACKED_POSITION.at_shader_location(0),
+            ATTRIBUTE_PACKED_FACE.at_shader_location(1),
         ]);
-        descriptor.depth_stencil.as_mut().unwrap().depth_write_enabled = true;
-        //Ugh FIXME Transparent faces need to be ordered or seperate mesh
-        descriptor.depth_stencil.as_mut().unwrap().depth_compare = CompareFunction::GreaterEqual;
         let vertex_layout = vertex_layout.unwrap();
         descriptor.vertex.buffers = vec![vertex_layout];
         Ok(())
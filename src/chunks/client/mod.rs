@@ -1,8 +1,21 @@
 mod click_detection;
 pub mod client_chunks;
+mod falling_blocks;
+mod lod;
 
 pub mod chunk_mesh_generation;
 mod material;
+mod occlusion_culling;
+mod padded_chunk;
+mod program_editor;
+//Only for checking and benchmarking the mesher against, not part of the game
+#[cfg(any(test, feature = "bench"))]
+mod reference_mesher;
 
 pub use crate::chunks::chunk_mesh_generation::*;
-pub use material::{create_array_texture, CustomMaterial};
+pub use lod::*;
+pub use material::{create_array_texture, CustomMaterial, MAX_TEXTURE_LAYERS};
+pub use occlusion_culling::*;
+pub use padded_chunk::PaddedChunk;
+#[cfg(any(test, feature = "bench"))]
+pub use reference_mesher::create_reference_mesh_description;
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+
+use bevy::utils::HashSet;
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+
+use crate::prelude::*;
+
+// Which faces of a chunk can see which other faces through the blocks inside it that aren't opaque
+// Bit a * 6 + b is set when faces a and b are connected, the same both ways around
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkVisibility(u64);
+
+impl ChunkVisibility {
+    //Every face sees every other, for empty chunks and chunks that haven't been meshed yet
+    pub const OPEN: ChunkVisibility = ChunkVisibility((1 << 36) - 1);
+
+    // Floods every pocket of blocks that aren't opaque and connects all the faces each one touches
+    pub fn new(padded: &PaddedChunk, registry: &BlockRegistry) -> Self {
+        if padded.is_empty() {
+            return ChunkVisibility::OPEN;
+        }
+        let size = CHUNK_SIZE as i32;
+        let index = |pos: IVec3| ((pos.x * size + pos.y) * size + pos.z) as usize;
+        let mut visited = vec![false; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE];
+        let mut visibility = ChunkVisibility(0);
+        let mut stack = Vec::default();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let start = IVec3::new(x, y, z);
+                    if visited[index(start)] || registry.is_opaque(padded.block(start)) {
+                        continue;
+                    }
+                    visited[index(start)] = true;
+                    stack.push(start);
+                    let mut touched = [false; 6];
+                    while let Some(pos) = stack.pop() {
+                        for dir in Direction::ALL {
+                            let next = pos + dir.offset();
+                            if next.min_element() < 0 || next.max_element() >= size {
+                                touched[dir] = true;
+                                continue;
+                            }
+                            if !visited[index(next)] && !registry.is_opaque(padded.block(next)) {
+                                visited[index(next)] = true;
+                                stack.push(next);
+                            }
+                        }
+                    }
+                    for a in Direction::ALL {
+                        for b in Direction::ALL {
+                            if touched[a] && touched[b] {
+                                visibility.0 |= 1 << (a as usize * 6 + b as usize);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        visibility
+    }
+
+    pub fn connects(self, a: Direction, b: Direction) -> bool {
+        (self.0 >> (a as usize * 6 + b as usize)) & 1 == 1
+    }
+}
+
+// Hides chunks the camera can't see into through the chunks between them, like caves behind solid rock
+pub struct OcclusionCulling {
+    pub enabled: bool,
+    //Chunks hidden last frame
+    pub culled: usize,
+}
+
+impl Default for OcclusionCulling {
+    fn default() -> Self {
+        OcclusionCulling {
+            enabled: true,
+            culled: 0,
+        }
+    }
+}
+
+// Searches out from the camera's chunk, going from a chunk into its neighbor only through faces its visibility
+// connects to the face it was entered by. The search only ever moves away from the camera so it can't loop back
+// around through a cave into chunks that are behind a wall. Chunks it never reaches are hidden
+pub fn cull_hidden_chunks(
+    camera: Query<&Transform, With<Camera3d>>,
+    loaded_chunks: Res<LoadedChunks>,
+    mut chunks: Query<(Option<&ChunkVisibility>, &mut Visibility), With<ChunkComp>>,
+    mut culling: ResMut<OcclusionCulling>,
+) {
+    let camera_chunk = match camera.get_single() {
+        Ok(camera) => Chunk::world_to_chunk(camera.translation).0,
+        Err(_) => return,
+    };
+    let mut reached = HashSet::default();
+    if culling.enabled {
+        let visibility_of = |pos: IVec3| {
+            loaded_chunks
+                .ent_map
+                .get(&pos)
+                .and_then(|entity| chunks.get(*entity).ok())
+                .and_then(|(visibility, _)| visibility.copied())
+                .unwrap_or(ChunkVisibility::OPEN)
+        };
+        //Chunks along with the face they were entered through, the camera's chunk is entered through none
+        let mut entered = HashSet::default();
+        let mut queue = VecDeque::from([(camera_chunk, None)]);
+        reached.insert(camera_chunk);
+        while let Some((pos, from)) = queue.pop_front() {
+            let visibility = visibility_of(pos);
+            for dir in Direction::ALL {
+                let next = pos + dir.offset();
+                if dir.offset().dot(next - camera_chunk) <= 0 || !loaded_chunks.ent_map.contains_key(&next) {
+                    continue;
+                }
+                if let Some(from) = from {
+                    if !visibility.connects(from, dir) {
+                        continue;
+                    }
+                }
+                if entered.insert((next, dir.opposite())) {
+                    reached.insert(next);
+                    queue.push_back((next, Some(dir.opposite())));
+                }
+            }
+        }
+    }
+
+    let mut culled = 0;
+    for (pos, entity) in loaded_chunks.ent_map.iter() {
+        if let Ok((_, mut visibility)) = chunks.get_mut(*entity) {
+            let visible = !culling.enabled || reached.contains(pos);
+            if visibility.is_visible != visible {
+                visibility.is_visible = visible;
+            }
+            culled += !visible as usize;
+        }
+    }
+    culling.culled = culled;
+}
+
+pub(crate) fn occlusion_culling_window(mut egui_context: ResMut<EguiContext>, mut culling: ResMut<OcclusionCulling>) {
+    egui::Window::new("Occlusion Culling").show(egui_context.ctx_mut(), |ui| {
+        ui.checkbox(&mut culling.enabled, "Hide chunks the camera can't see into");
+        ui.label(format!("{} chunks hidden", culling.culled));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunnel_only_connects_its_ends() {
+        let registry = BlockRegistry::default();
+        let metal = registry.expect("metal");
+        let mut chunk = Chunk::default();
+        assert_eq!(
+            ChunkVisibility::new(&PaddedChunk::new(&chunk), &registry),
+            ChunkVisibility::OPEN
+        );
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    if (y, z) != (4, 4) {
+                        chunk.set_block_with_state(x, y, z, metal, BlockState::default());
+                    }
+                }
+            }
+        }
+        let visibility = ChunkVisibility::new(&PaddedChunk::new(&chunk), &registry);
+        for a in Direction::ALL {
+            for b in Direction::ALL {
+                let ends = [Direction::Front, Direction::Back];
+                assert_eq!(visibility.connects(a, b), ends.contains(&a) && ends.contains(&b));
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::prelude::*;
+
+//A chunk with a block of border on every side
+pub const PADDED_SIZE: usize = CHUNK_SIZE + 2;
+const PADDED_VOLUME: usize = PADDED_SIZE * PADDED_SIZE * PADDED_SIZE;
+
+// A chunk and the blocks just past its borders copied out into flat arrays, everything meshing reads
+// Copying takes each neighbor's lock once so meshing itself never touches a lock and can run on any thread
+// Positions are chunk offsets from -1 to CHUNK_SIZE, cells of unloaded neighbors read as air in full light
+// which hides and shades the same as a missing neighbor does when reading the chunk itself
+pub struct PaddedChunk {
+    blocks: Vec<(Block, BlockState)>,
+    light: Vec<Light>,
+    //Nothing to mesh so nothing was copied
+    empty: bool,
+}
+
+impl PaddedChunk {
+    pub fn new(chunk: &Chunk) -> Self {
+        let empty = matches!(chunk.storage(), BlockStorage::Uniform((block, _)) if block.is_air());
+        let mut padded = PaddedChunk {
+            blocks: vec![(Block::AIR, BlockState::default()); PADDED_VOLUME],
+            light: vec![Light::FULL; PADDED_VOLUME],
+            empty,
+        };
+        if empty {
+            return padded;
+        }
+        padded.copy_from(chunk, IVec3::ZERO);
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let offset = IVec3::new(x, y, z);
+                    if let Some(neighbor) = chunk.linked(offset) {
+                        padded.copy_from(&neighbor.read().unwrap(), offset);
+                    }
+                }
+            }
+        }
+        padded
+    }
+
+    //Copies the part of the chunk offset chunks away that falls inside the padding
+    fn copy_from(&mut self, chunk: &Chunk, offset: IVec3) {
+        let size = CHUNK_SIZE as i32;
+        //The row, edge or corner of a neighbor that touches the chunk, or all of the chunk itself
+        let touching = |step: i32| match step {
+            -1 => size - 1..size,
+            0 => 0..size,
+            _ => 0..1,
+        };
+        for x in touching(offset.x) {
+            for y in touching(offset.y) {
+                for z in touching(offset.z) {
+                    let index = Self::index(offset * size + IVec3::new(x, y, z));
+                    let (x, y, z) = (x as usize, y as usize, z as usize);
+                    self.blocks[index] = chunk.block_with_state(x, y, z);
+                    self.light[index] = chunk.light(x, y, z);
+                }
+            }
+        }
+    }
+
+    // The chunk with every lod sized cube of blocks filled with one block, so meshing merges each cube into a few faces
+    // Any opaque block makes the whole cube opaque so the coarse chunk covers every face its full detail neighbors hide
+    // against it, and the border is emptied so the chunk is closed on every side like a skirt
+    // Light is evened out to the brightest in each cube, and in each cube sized patch of the border, so faces merge too
+    pub fn downsampled(mut self, lod: ChunkLod, registry: &BlockRegistry) -> Self {
+        if lod == ChunkLod::FULL || self.empty {
+            return self;
+        }
+        let size = CHUNK_SIZE as i32;
+        //The border is a layer of its own on every side, each cube is one group inside
+        let groups = CHUNK_SIZE / lod.0 + 2;
+        let group = |pos: IVec3| {
+            let [x, y, z] = pos.to_array().map(|axis| match axis {
+                -1 => 0,
+                axis if axis >= size => groups - 1,
+                axis => 1 + axis as usize / lod.0,
+            });
+            (x * groups + y) * groups + z
+        };
+        let mut brightest = vec![Light::default(); groups * groups * groups];
+        for x in -1..=size {
+            for y in -1..=size {
+                for z in -1..=size {
+                    let pos = IVec3::new(x, y, z);
+                    let light = self.light(pos);
+                    let group = &mut brightest[group(pos)];
+                    for kind in [LightKind::Sky, LightKind::Block] {
+                        if light.get(kind) > group.get(kind) {
+                            *group = group.with(kind, light.get(kind));
+                        }
+                    }
+                    if pos.min_element() < 0 || pos.max_element() >= size {
+                        self.blocks[Self::index(pos)] = (Block::AIR, BlockState::default());
+                    }
+                }
+            }
+        }
+        for x in -1..=size {
+            for y in -1..=size {
+                for z in -1..=size {
+                    let pos = IVec3::new(x, y, z);
+                    self.light[Self::index(pos)] = brightest[group(pos)];
+                }
+            }
+        }
+        let step = lod.0 as i32;
+        for x in (0..size).step_by(lod.0) {
+            for y in (0..size).step_by(lod.0) {
+                for z in (0..size).step_by(lod.0) {
+                    let corner = IVec3::new(x, y, z);
+                    let cube = self.cube_block(corner, step, registry);
+                    for x in 0..step {
+                        for y in 0..step {
+                            for z in 0..step {
+                                self.blocks[Self::index(corner + IVec3::new(x, y, z))] = cube;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    // The most common opaque block in the cube, or the most common block if none are opaque
+    // Model blocks count as air, copied across the cube every one of them would be meshed as a model of its own
+    fn cube_block(&self, corner: IVec3, step: i32, registry: &BlockRegistry) -> (Block, BlockState) {
+        let mut counts: Vec<((Block, BlockState), usize)> = Vec::default();
+        for x in 0..step {
+            for y in 0..step {
+                for z in 0..step {
+                    let (block, state) = self.block_with_state(corner + IVec3::new(x, y, z));
+                    if block.is_air() || !registry.model(block).is_empty() {
+                        continue;
+                    }
+                    match counts.iter_mut().find(|((counted, _), _)| *counted == block) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push(((block, state), 1)),
+                    }
+                }
+            }
+        }
+        counts
+            .into_iter()
+            .max_by_key(|((block, _), count)| (registry.is_opaque(*block), *count))
+            .map_or((Block::AIR, BlockState::default()), |(block, _)| block)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.empty
+    }
+
+    fn index(pos: IVec3) -> usize {
+        let pos = (pos + IVec3::ONE).as_uvec3();
+        ((pos.x as usize * PADDED_SIZE) + pos.y as usize) * PADDED_SIZE + pos.z as usize
+    }
+
+    pub fn block_with_state(&self, pos: IVec3) -> (Block, BlockState) {
+        self.blocks[Self::index(pos)]
+    }
+
+    pub fn block(&self, pos: IVec3) -> Block {
+        self.block_with_state(pos).0
+    }
+
+    pub fn light(&self, pos: IVec3) -> Light {
+        self.light[Self::index(pos)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube_blocks(padded: &PaddedChunk, corner: IVec3, step: i32) -> Vec<Block> {
+        let mut blocks = Vec::default();
+        for x in 0..step {
+            for y in 0..step {
+                for z in 0..step {
+                    blocks.push(padded.block(corner + IVec3::new(x, y, z)));
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn downsampled_border_is_emptied() {
+        let registry = BlockRegistry::default();
+        let metal = registry.expect("metal");
+        let mut chunk = Chunk::default();
+        chunk.set_block_with_state(0, 0, 0, metal, BlockState::default());
+        let mut padded = PaddedChunk::new(&chunk);
+        //Stands in for a loaded neighbor, the border is only ever copied from them
+        for pos in [IVec3::new(-1, 0, 0), IVec3::new(5, 16, 5), IVec3::new(16, 16, -1)] {
+            padded.blocks[PaddedChunk::index(pos)] = (metal, BlockState::default());
+        }
+        let padded = padded.downsampled(ChunkLod(2), &registry);
+        let size = CHUNK_SIZE as i32;
+        for x in -1..=size {
+            for y in -1..=size {
+                for z in -1..=size {
+                    let pos = IVec3::new(x, y, z);
+                    if pos.min_element() < 0 || pos.max_element() >= size {
+                        assert!(padded.block(pos).is_air(), "border at {} wasn't emptied", pos);
+                    }
+                }
+            }
+        }
+        assert_eq!(padded.block(IVec3::ZERO), metal);
+    }
+
+    #[test]
+    fn downsampled_cubes_prefer_opaque_blocks() {
+        let registry = BlockRegistry::default();
+        let (metal, glass, slab) = (
+            registry.expect("metal"),
+            registry.expect("glass"),
+            registry.expect("metal_slab"),
+        );
+        let mut chunk = Chunk::default();
+        //Outnumbered but opaque
+        chunk.set_block_with_state(0, 0, 0, metal, BlockState::default());
+        for (x, y, z) in [(1, 0, 0), (0, 1, 0), (0, 0, 1)] {
+            chunk.set_block_with_state(x, y, z, glass, BlockState::default());
+        }
+        //Nothing opaque so the most common block, the slab is a model and doesn't count
+        for (x, y, z) in [(2, 0, 0), (3, 0, 0)] {
+            chunk.set_block_with_state(x, y, z, glass, BlockState::default());
+        }
+        for (x, y, z) in [(2, 1, 0), (3, 1, 0), (2, 0, 1)] {
+            chunk.set_block_with_state(x, y, z, slab, BlockState::default());
+        }
+        //Only a model block, the cube stays empty
+        chunk.set_block_with_state(4, 0, 0, slab, BlockState::default());
+
+        let padded = PaddedChunk::new(&chunk).downsampled(ChunkLod(2), &registry);
+        assert!(cube_blocks(&padded, IVec3::new(0, 0, 0), 2)
+            .iter()
+            .all(|block| *block == metal));
+        assert!(cube_blocks(&padded, IVec3::new(2, 0, 0), 2)
+            .iter()
+            .all(|block| *block == glass));
+        assert!(cube_blocks(&padded, IVec3::new(4, 0, 0), 2)
+            .iter()
+            .all(|block| block.is_air()));
+        assert!(cube_blocks(&padded, IVec3::new(6, 0, 0), 2)
+            .iter()
+            .all(|block| block.is_air()));
+    }
+
+    #[test]
+    fn downsampled_light_is_the_brightest_in_each_cube() {
+        let registry = BlockRegistry::default();
+        let mut chunk = Chunk::default();
+        chunk.set_block_with_state(0, 0, 0, registry.expect("metal"), BlockState::default());
+        chunk.clear_light();
+        chunk.set_light(1, 1, 1, Light::default().with(LightKind::Block, 12));
+        chunk.set_light(2, 3, 0, Light::default().with(LightKind::Sky, 5));
+        chunk.set_light(3, 2, 1, Light::default().with(LightKind::Block, 7));
+
+        let padded = PaddedChunk::new(&chunk).downsampled(ChunkLod(4), &registry);
+        //Sky and block light are evened out separately, each to the brightest of its kind
+        let mixed = Light::default().with(LightKind::Sky, 5).with(LightKind::Block, 12);
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    assert_eq!(padded.light(IVec3::new(x, y, z)), mixed);
+                }
+            }
+        }
+        assert_eq!(padded.light(IVec3::new(4, 0, 0)), Light::default());
+        //Unloaded neighbors read as full light and stay that way
+        assert_eq!(padded.light(IVec3::new(-1, 0, 0)), Light::FULL);
+    }
+}
@@ -0,0 +1,56 @@
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+
+use crate::prelude::*;
+
+// The microcontroller program being edited, opened by right clicking a controller
+// The program lives on the server so the editor only shows what the server last sent
+#[derive(Default)]
+pub struct ProgramEditor {
+    pos: Option<IVec3>,
+    source: String,
+    error: Option<String>,
+}
+
+pub(crate) fn receive_programs(messages: Res<CurrentClientBlockMessages>, mut editor: ResMut<ProgramEditor>) {
+    for message in messages.iter() {
+        if let ServerBlockMessage::Program(pos, info) = message {
+            editor.pos = Some(*pos);
+            editor.source = info.source.clone();
+            editor.error = info.error.clone();
+        }
+    }
+}
+
+pub(crate) fn program_editor_window(
+    mut egui_context: ResMut<EguiContext>,
+    mut editor: ResMut<ProgramEditor>,
+    mut client: ResMut<RenetClient>,
+) {
+    let pos = match editor.pos {
+        Some(pos) => pos,
+        None => return,
+    };
+    let mut close = false;
+    egui::Window::new(format!("Microcontroller {}", pos)).show(egui_context.ctx_mut(), |ui| {
+        ui.add(
+            egui::TextEdit::multiline(&mut editor.source)
+                .code_editor()
+                .char_limit(MAX_PROGRAM_BYTES)
+                .desired_rows(16),
+        );
+        if let Some(error) = &editor.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        ui.horizontal(|ui| {
+            if ui.button("Upload").clicked() {
+                let _ = ClientMessage::UploadProgram(pos, editor.source.clone()).send(&mut client);
+            }
+            if ui.button("Close").clicked() {
+                close = true;
+            }
+        });
+    });
+    if close {
+        *editor = ProgramEditor::default();
+    }
+}
@@ -0,0 +1,207 @@
+use crate::prelude::*;
+
+// The mesher the bitmask one in chunk_mesh_generation replaced, it builds every slice a block at a time reading
+// each neighbor through the chunk and then merges faces by walking the slice
+// Kept to check the bitmask mesher covers the same faces and to benchmark it against
+pub fn create_reference_mesh_description(chunk: &Chunk, registry: &BlockRegistry) -> MeshDescription {
+    let mut description = MeshDescription::default();
+    create_mesh_faces(chunk, registry, &mut description);
+    description
+}
+
+// A single slide of a chunk, direction agnostic
+// Each face keeps the light of the block it faces and its corner occlusion so faces that look different aren't merged
+#[derive(Default, Copy, Clone)]
+pub struct Sheet {
+    blocks: [[(Block, BlockState, Light, [u8; 4], u8); CHUNK_SIZE]; CHUNK_SIZE],
+}
+
+//Gathers the slices and runs the greedy algorithm
+fn create_mesh_faces(chunk: &Chunk, registry: &BlockRegistry, mesh_description: &mut MeshDescription) {
+    //Fast path for empty chunks, nothing to mesh
+    if let BlockStorage::Uniform((block, _)) = chunk.storage() {
+        if block.is_air() {
+            return;
+        }
+    }
+    //Faces are hidden by opaque neighbors, missing neighbors (unloaded chunks) don't hide anything
+    //Transparent blocks don't show faces against the same block so a lake or a glass wall is only its surface,
+    //but blocks behind them still show their faces
+    let visible = |block: Block, neighbor: Option<Block>| {
+        !block.is_air()
+            && !neighbor.map_or(false, |neighbor| {
+                registry.is_opaque(neighbor) || (neighbor == block && !registry.is_opaque(block))
+            })
+    };
+    let mut top_slices = [Sheet::default(); CHUNK_SIZE];
+    let mut bottom_slices = [Sheet::default(); CHUNK_SIZE];
+    let mut left_slices = [Sheet::default(); CHUNK_SIZE];
+    let mut right_slices = [Sheet::default(); CHUNK_SIZE];
+    let mut front_slices = [Sheet::default(); CHUNK_SIZE];
+    let mut back_slices = [Sheet::default(); CHUNK_SIZE];
+    for x in 0..CHUNK_SIZE as isize {
+        for y in 0..CHUNK_SIZE as isize {
+            for z in 0..CHUNK_SIZE as isize {
+                let mut current_block = chunk.block_with_state(x as usize, y as usize, z as usize);
+                let neighbors = chunk.get_block_neighbors(x as usize, y as usize, z as usize);
+                //Faces are lit by the block in front of them, faces on an unloaded chunk are left fully lit
+                let light = chunk
+                    .get_light_neighbors(x as usize, y as usize, z as usize)
+                    .map(|light| light.unwrap_or(Light::FULL));
+                current_block.1 = filled_state(registry, current_block.0, current_block.1, neighbors[Direction::Top]);
+                let pos = IVec3::new(x as i32, y as i32, z as i32);
+                let read = |pos: IVec3| chunk.get_block_with_state(pos.x as isize, pos.y as isize, pos.z as isize);
+                //Where the face starts, None if it's hidden
+                let floor = |dir: Direction| {
+                    if visible(current_block.0, neighbors[dir]) {
+                        return Some(0);
+                    }
+                    //Sides of a fluid show above the same fluid if it's lower
+                    let front = pos + dir.offset();
+                    match read(front) {
+                        Some((block, state))
+                            if block == current_block.0 && !matches!(dir, Direction::Top | Direction::Bottom) =>
+                        {
+                            let above = read(front + IVec3::Y).map(|(block, _)| block);
+                            let state = filled_state(registry, block, state, above);
+                            fluid_floor(registry, block, current_block.1, state)
+                        }
+                        _ => None,
+                    }
+                };
+                let face = |dir: Direction, floor: u8| {
+                    let occlusion = face_occlusion(pos, dir, |pos| {
+                        chunk
+                            .get_block(pos.x as isize, pos.y as isize, pos.z as isize)
+                            .map_or(false, |block| registry.is_opaque(block))
+                    });
+                    (current_block.0, current_block.1, light[dir], occlusion, floor)
+                };
+
+                if let Some(floor) = floor(Direction::Left) {
+                    left_slices[z as usize].blocks[x as usize][y as usize] = face(Direction::Left, floor);
+                }
+                if let Some(floor) = floor(Direction::Right) {
+                    right_slices[z as usize].blocks[x as usize][y as usize] = face(Direction::Right, floor);
+                }
+                if let Some(floor) = floor(Direction::Front) {
+                    front_slices[x as usize].blocks[z as usize][y as usize] = face(Direction::Front, floor);
+                }
+                if let Some(floor) = floor(Direction::Back) {
+                    back_slices[x as usize].blocks[z as usize][y as usize] = face(Direction::Back, floor);
+                }
+                if let Some(floor) = floor(Direction::Top) {
+                    top_slices[y as usize].blocks[x as usize][z as usize] = face(Direction::Top, floor);
+                }
+                if let Some(floor) = floor(Direction::Bottom) {
+                    bottom_slices[y as usize].blocks[x as usize][z as usize] = face(Direction::Bottom, floor);
+                }
+            }
+        }
+    }
+    for index in 0..CHUNK_SIZE as isize {
+        greedy(
+            &back_slices[index as usize],
+            Direction::Back,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+        greedy(
+            &front_slices[index as usize],
+            Direction::Front,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+        greedy(
+            &left_slices[index as usize],
+            Direction::Left,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+        greedy(
+            &right_slices[index as usize],
+            Direction::Right,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+        greedy(
+            &top_slices[index as usize],
+            Direction::Top,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+        greedy(
+            &bottom_slices[index as usize],
+            Direction::Bottom,
+            registry,
+            mesh_description,
+            index as usize,
+        );
+    }
+}
+
+//I think it makes the code clearer as is, clippy wants to make the iterations over finished
+//But there are 2 different uses for every index so doing the iterator over finished and also enumerating it
+//Seems to break a single concept into 2 for no great reason
+#[allow(clippy::needless_range_loop)]
+fn greedy(sheet: &Sheet, dir: Direction, registry: &BlockRegistry, desc: &mut MeshDescription, z: usize) {
+    let mut finished = [[false; CHUNK_SIZE]; CHUNK_SIZE];
+
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            finished[x][y] = sheet.blocks[x][y].0.is_air();
+        }
+    }
+
+    //gross
+    while finished.iter().flatten().any(|x| x == &false) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                if !finished[x][y] {
+                    //Starting point, walk x to get width
+                    let start = sheet.blocks[x][y];
+                    let mut width = 1;
+                    for w in x + 1..CHUNK_SIZE {
+                        if start == sheet.blocks[w][y] && !finished[w][y] {
+                            width += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    //Now walk y to get height, faces that start part way up their block aren't stacked
+                    let mut height = 1;
+                    for h in (y + 1..CHUNK_SIZE).filter(|_| start.4 == 0) {
+                        let mut all_same = true;
+                        for w in x..x + width {
+                            if start != sheet.blocks[w][h] || finished[w][h] {
+                                all_same = false;
+                                break;
+                            }
+                        }
+                        if all_same {
+                            height += 1;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    //Time to make the rect and mark finished
+                    create_greedy_face(
+                        start.0, start.1, start.2, start.3, start.4, dir, registry, x, y, z, width, height, desc,
+                    );
+
+                    for u in x..x + width {
+                        for v in y..y + height {
+                            finished[u][v] = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
-    f32::consts::PI,
+    f32::consts::{FRAC_PI_2, PI},
     ops::{Index, IndexMut},
 };
 
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Direction {
     Front = 0,  // x + 1
     Back = 1,   // x - 1
@@ -29,6 +30,54 @@ impl<T> IndexMut<Direction> for [T; 6] {
     }
 }
 impl Direction {
+    pub const ALL: [Direction; 6] = [
+        Direction::Front,
+        Direction::Back,
+        Direction::Left,
+        Direction::Right,
+        Direction::Top,
+        Direction::Bottom,
+    ];
+
+    pub fn offset(&self) -> IVec3 {
+        match self {
+            Direction::Front => IVec3::X,
+            Direction::Back => -IVec3::X,
+            Direction::Left => IVec3::Z,
+            Direction::Right => -IVec3::Z,
+            Direction::Top => IVec3::Y,
+            Direction::Bottom => -IVec3::Y,
+        }
+    }
+
+    pub fn from_offset(offset: IVec3) -> Option<Direction> {
+        Direction::ALL.into_iter().find(|dir| dir.offset() == offset)
+    }
+
+    //Rotation that turns something facing Front to face this direction
+    pub fn rotation(&self) -> Quat {
+        match self {
+            Direction::Front => Quat::IDENTITY,
+            Direction::Back => Quat::from_rotation_y(PI),
+            Direction::Left => Quat::from_rotation_y(-FRAC_PI_2),
+            Direction::Right => Quat::from_rotation_y(FRAC_PI_2),
+            Direction::Top => Quat::from_rotation_z(FRAC_PI_2),
+            Direction::Bottom => Quat::from_rotation_z(-FRAC_PI_2),
+        }
+    }
+
+    //Which face of a block rotated to face `facing` points in this direction
+    pub fn relative_to(&self, facing: Direction) -> Direction {
+        let local = facing.rotation().inverse() * self.offset().as_vec3();
+        Direction::from_offset(local.round().as_ivec3()).unwrap()
+    }
+
+    //Which way this face of a block rotated to face `facing` points, the inverse of relative_to
+    pub fn rotated_to(&self, facing: Direction) -> Direction {
+        let world = facing.rotation() * self.offset().as_vec3();
+        Direction::from_offset(world.round().as_ivec3()).unwrap()
+    }
+
     pub fn get_face_rotation(&self) -> Quat {
         match self {
             Direction::Front => Quat::from_axis_angle(Vec3::Y, PI / 2.0),
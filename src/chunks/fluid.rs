@@ -0,0 +1,253 @@
+use crate::prelude::*;
+
+//Fluid ticks past this wait for the next tick, a big flood spreads slower instead of stalling the server
+pub const FLUID_TICK_BUDGET: usize = 1024;
+
+const SIDES: [Direction; 4] = [Direction::Front, Direction::Back, Direction::Left, Direction::Right];
+
+// How a fluid block spreads, set with `fluid` in the block definitions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fluid {
+    //Ticks between each step of spreading
+    pub ticks: u64,
+    //Level lost for every block the fluid spreads sideways
+    pub falloff: u8,
+}
+
+//Flowing fluid at MAX_LEVEL is falling, it's fed from above and fills the whole block
+fn flowing(level: u8) -> BlockState {
+    BlockState::default().with_flowing(true).with_level(level)
+}
+
+// Scheduled rule for fluid blocks, registered once for every fluid
+// Sources never change, flowing fluid takes its level from whatever feeds it and drains away once nothing does
+// Fluid falls first and only spreads sideways once it's resting on something
+pub fn flow_fluid(context: &mut RuleContext) {
+    let fluid = match context.registry.fluid(context.block) {
+        Some(fluid) => fluid,
+        None => return,
+    };
+    let (pos, block) = (context.pos, context.block);
+    let mut level = context.state.level();
+    if context.state.flowing() {
+        let fed = fed_level(context, fluid);
+        if fed != level {
+            if fed == 0 {
+                context.set_block(pos, Block::AIR);
+            } else {
+                context.set(pos, block, flowing(fed));
+            }
+            //Anything this was feeding has to check its own level again
+            for dir in Direction::ALL {
+                context.schedule_tick(pos + dir.offset(), fluid.ticks);
+            }
+            level = fed;
+        }
+        if level == 0 {
+            return;
+        }
+    }
+
+    let below = pos - IVec3::Y;
+    match context.get(below) {
+        Some((below_block, _)) if below_block.is_air() => {
+            context.set(below, block, flowing(BlockState::MAX_LEVEL));
+            context.schedule_tick(below, fluid.ticks);
+            return;
+        }
+        //Unloaded below, wait for it rather than spreading out over nothing
+        None => return,
+        Some((below_block, _)) if below_block == block => return,
+        _ => {}
+    }
+    let spread = level.saturating_sub(fluid.falloff);
+    if spread == 0 {
+        return;
+    }
+    for dir in SIDES {
+        let side = pos + dir.offset();
+        let fill = match context.get(side) {
+            Some((side_block, _)) if side_block.is_air() => true,
+            Some((side_block, side_state)) => {
+                side_block == block && side_state.flowing() && side_state.level() < spread
+            }
+            None => false,
+        };
+        if fill {
+            context.set(side, block, flowing(spread));
+            context.schedule_tick(side, fluid.ticks);
+        }
+    }
+}
+
+//The level the fluid above or the fullest fluid beside this block would give it, 0 if nothing feeds it
+fn fed_level(context: &mut RuleContext, fluid: Fluid) -> u8 {
+    let block = context.block;
+    if context.get(context.pos + IVec3::Y).map(|(above, _)| above) == Some(block) {
+        return BlockState::MAX_LEVEL;
+    }
+    SIDES
+        .iter()
+        .filter_map(|dir| match context.get(context.pos + dir.offset()) {
+            Some((side, state)) if side == block => Some(state.level().saturating_sub(fluid.falloff)),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+
+    // Linked chunks at the given chunk positions, each with a metal floor at y = 2 for fluid to spread out on
+    fn world(chunk_positions: &[IVec3], registry: &BlockRegistry) -> (LinkedChunks, Vec<Arc<RwLock<Chunk>>>) {
+        let metal = registry.expect("metal");
+        let comps = chunk_positions
+            .iter()
+            .map(|chunk_pos| {
+                let mut chunk = Chunk::default();
+                chunk.pos = *chunk_pos;
+                for x in 0..CHUNK_SIZE {
+                    for z in 0..CHUNK_SIZE {
+                        chunk.set_block_with_state(x, 2, z, metal, BlockState::default());
+                    }
+                }
+                (*chunk_pos, ChunkComp::new(Arc::new(RwLock::new(chunk))))
+            })
+            .collect::<HashMap<IVec3, ChunkComp>>();
+        let mut world = LinkedChunks::default();
+        for (chunk_pos, comp) in comps.iter() {
+            comp.connect_neighbors(*chunk_pos, |pos| comps.get(&pos));
+            world.add_chunk(comp.shared());
+        }
+        let chunks = chunk_positions.iter().map(|pos| comps[pos].shared()).collect();
+        (world, chunks)
+    }
+
+    fn water_rules(budget: Option<usize>) -> RuleEngine {
+        let mut rules = RuleEngine::default();
+        rules.register(CellRule {
+            name: "water_flow",
+            block: "water",
+            schedule: RuleSchedule::Scheduled,
+            apply: flow_fluid,
+            budget,
+        });
+        rules
+    }
+
+    fn run(
+        rules: &mut RuleEngine,
+        world: &mut LinkedChunks,
+        chunks: &[Arc<RwLock<Chunk>>],
+        registry: &BlockRegistry,
+        ticks: usize,
+    ) {
+        for _ in 0..ticks {
+            rules.tick(world, chunks, registry);
+        }
+    }
+
+    #[test]
+    fn source_falls_then_spreads() {
+        let registry = BlockRegistry::default();
+        let water = registry.expect("water");
+        let ticks = registry.fluid(water).unwrap().ticks as usize;
+        let (mut world, chunks) = world(&[IVec3::ZERO], &registry);
+        let mut rules = water_rules(None);
+        let source = IVec3::new(8, 4, 8);
+        world.set_block_with_state(source, water, BlockState::default());
+        world.schedule_tick(source, 1);
+
+        run(&mut rules, &mut world, &chunks, &registry, 1);
+        assert_eq!(world.block_with_state(source - IVec3::Y), Some((water, flowing(7))));
+        //Falling fluid doesn't spread from where it fell
+        assert_eq!(world.block(source + IVec3::X), Some(Block::AIR));
+
+        run(&mut rules, &mut world, &chunks, &registry, ticks);
+        for dir in SIDES {
+            let side = source - IVec3::Y + dir.offset();
+            assert_eq!(world.block_with_state(side), Some((water, flowing(6))));
+        }
+        assert_eq!(world.block(source + IVec3::X), Some(Block::AIR));
+        assert_eq!(world.block_with_state(source), Some((water, BlockState::default())));
+    }
+
+    #[test]
+    fn flowing_fluid_drains_without_its_source() {
+        let registry = BlockRegistry::default();
+        let water = registry.expect("water");
+        let (mut world, chunks) = world(&[IVec3::ZERO], &registry);
+        let mut rules = water_rules(None);
+        let source = IVec3::new(8, 3, 8);
+        world.set_block_with_state(source, water, BlockState::default());
+        world.schedule_tick(source, 1);
+
+        run(&mut rules, &mut world, &chunks, &registry, 100);
+        //Loses a level a block until there's nothing left to spread
+        assert_eq!(world.block_with_state(source + IVec3::X * 6), Some((water, flowing(1))));
+        assert_eq!(world.block(source + IVec3::X * 7), Some(Block::AIR));
+
+        world.set_block_with_state(source, Block::AIR, BlockState::default());
+        for dir in SIDES {
+            world.schedule_tick(source + dir.offset(), 1);
+        }
+        run(&mut rules, &mut world, &chunks, &registry, 200);
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                assert_eq!(
+                    world.block(IVec3::new(x, 3, z)),
+                    Some(Block::AIR),
+                    "water left at {} {}",
+                    x,
+                    z
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fluid_flows_into_the_next_chunk() {
+        let registry = BlockRegistry::default();
+        let water = registry.expect("water");
+        let ticks = registry.fluid(water).unwrap().ticks as usize;
+        let (mut world, chunks) = world(&[IVec3::ZERO, IVec3::X], &registry);
+        let mut rules = water_rules(None);
+        let source = IVec3::new(CHUNK_SIZE as i32 - 1, 3, 8);
+        world.set_block_with_state(source, water, BlockState::default());
+        world.schedule_tick(source, 1);
+
+        run(&mut rules, &mut world, &chunks, &registry, 1);
+        assert_eq!(world.block_with_state(source + IVec3::X), Some((water, flowing(6))));
+        //The tick for the block past the border was queued in the other chunk and runs from there
+        run(&mut rules, &mut world, &chunks, &registry, ticks);
+        assert_eq!(world.block_with_state(source + IVec3::X * 2), Some((water, flowing(5))));
+    }
+
+    #[test]
+    fn ticks_over_budget_wait_for_the_next_tick() {
+        let registry = BlockRegistry::default();
+        let water = registry.expect("water");
+        let (mut world, chunks) = world(&[IVec3::ZERO], &registry);
+        let mut rules = water_rules(Some(2));
+        let sources = [2, 6, 10, 14].map(|x| IVec3::new(x, 3, 8));
+        for source in sources {
+            world.set_block_with_state(source, water, BlockState::default());
+            world.schedule_tick(source, 1);
+        }
+        let spread = |world: &mut LinkedChunks| {
+            sources
+                .iter()
+                .filter(|source| world.block(**source + IVec3::Z) == Some(water))
+                .count()
+        };
+
+        run(&mut rules, &mut world, &chunks, &registry, 1);
+        assert_eq!(spread(&mut world), 2);
+        run(&mut rules, &mut world, &chunks, &registry, 1);
+        assert_eq!(spread(&mut world), 4);
+    }
+}
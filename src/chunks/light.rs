@@ -0,0 +1,415 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::prelude::*;
+
+pub const MAX_LIGHT: u8 = 15;
+
+const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightKind {
+    //Falls straight down from the sky without fading and fades by one per block any other way
+    Sky,
+    //Comes from blocks with `light` in their definition and fades by one per block
+    Block,
+}
+
+const KINDS: [LightKind; 2] = [LightKind::Sky, LightKind::Block];
+
+// Sky light in the high nibble and block light in the low one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Light(u8);
+
+impl Light {
+    //What chunks that were never lit show, like the blocks of a falling body
+    pub const FULL: Light = Light(0xFF);
+
+    pub fn sky(self) -> u8 {
+        self.0 >> 4
+    }
+
+    pub fn block(self) -> u8 {
+        self.0 & 0x0F
+    }
+
+    pub fn get(self, kind: LightKind) -> u8 {
+        match kind {
+            LightKind::Sky => self.sky(),
+            LightKind::Block => self.block(),
+        }
+    }
+
+    pub fn with(self, kind: LightKind, level: u8) -> Light {
+        let level = level.min(MAX_LIGHT);
+        match kind {
+            LightKind::Sky => Light(self.0 & 0x0F | level << 4),
+            LightKind::Block => Light(self.0 & 0xF0 | level),
+        }
+    }
+}
+
+// Light of every block in a chunk, derived from the blocks so it is never saved or sent
+// Only the client lights its chunks, the server has no use for it
+#[derive(Clone)]
+pub struct ChunkLight(Box<[Light; CHUNK_VOLUME]>);
+
+impl Default for ChunkLight {
+    fn default() -> Self {
+        ChunkLight(Box::new([Light::default(); CHUNK_VOLUME]))
+    }
+}
+
+impl fmt::Debug for ChunkLight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChunkLight")
+    }
+}
+
+impl ChunkLight {
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Light {
+        self.0[(x * CHUNK_SIZE + y) * CHUNK_SIZE + z]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, light: Light) {
+        self.0[(x * CHUNK_SIZE + y) * CHUNK_SIZE + z] = light;
+    }
+}
+
+// Whether changing a block from old to new can change any light
+pub fn changes_light(registry: &BlockRegistry, old: Block, new: Block) -> bool {
+    registry.is_opaque(old) != registry.is_opaque(new) || registry.light(old) != registry.light(new)
+}
+
+// Lights a chunk from scratch as if none of its neighbors were loaded, the sky is open above it
+// Run link_chunk_light once it has neighbors
+pub fn light_chunk(world: &mut LinkedChunks, registry: &BlockRegistry, chunk_pos: IVec3) {
+    world.clear_light(chunk_pos);
+    let origin = chunk_pos * CHUNK_SIZE as i32;
+    let mut spreads = VecDeque::default();
+    for x in 0..CHUNK_SIZE as i32 {
+        for y in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                seed_source(world, registry, origin + IVec3::new(x, y, z), &mut spreads);
+            }
+        }
+    }
+    spread(world, registry, spreads);
+}
+
+// Evens out the light on every side of a newly linked chunk, both light going out into its neighbors and coming in
+// Sunlight either side assumed was falling in from an unloaded chunk above is taken away first if it isn't really there
+pub fn link_chunk_light(world: &mut LinkedChunks, registry: &BlockRegistry, chunk_pos: IVec3) {
+    let origin = chunk_pos * CHUNK_SIZE as i32;
+    let last = CHUNK_SIZE as i32 - 1;
+    let mut removals = VecDeque::default();
+    let mut spreads = VecDeque::default();
+    for dir in Direction::ALL {
+        for u in 0..CHUNK_SIZE as i32 {
+            for v in 0..CHUNK_SIZE as i32 {
+                let offset = match dir {
+                    Direction::Front => IVec3::new(last, u, v),
+                    Direction::Back => IVec3::new(0, u, v),
+                    Direction::Left => IVec3::new(u, v, last),
+                    Direction::Right => IVec3::new(u, v, 0),
+                    Direction::Top => IVec3::new(u, last, v),
+                    Direction::Bottom => IVec3::new(u, 0, v),
+                };
+                let inside = origin + offset;
+                let outside = inside + dir.offset();
+                if world.light(outside).is_none() {
+                    continue;
+                }
+                let (upper, lower) = match dir {
+                    Direction::Top => (Some(outside), inside),
+                    Direction::Bottom => (Some(inside), outside),
+                    _ => (None, inside),
+                };
+                if let Some(upper) = upper {
+                    remove_unfed_sky(world, upper, lower, &mut removals);
+                }
+                for kind in KINDS {
+                    spreads.push_back((inside, kind));
+                    spreads.push_back((outside, kind));
+                }
+            }
+        }
+    }
+    remove(world, registry, removals, &mut spreads);
+    spread(world, registry, spreads);
+}
+
+// Call after the block at pos changed in a way changes_light says matters
+pub fn update_light(world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3) {
+    let light = match world.light(pos) {
+        Some(light) => light,
+        None => return,
+    };
+    let mut removals = VecDeque::default();
+    let mut spreads = VecDeque::default();
+    for kind in KINDS {
+        if light.get(kind) > 0 {
+            removals.push_back((pos, kind, light.get(kind)));
+        }
+    }
+    world.set_light(pos, Light::default());
+    remove(world, registry, removals, &mut spreads);
+    //The block's own light, then whatever its neighbors can shine into it
+    seed_source(world, registry, pos, &mut spreads);
+    for dir in Direction::ALL {
+        for kind in KINDS {
+            spreads.push_back((pos + dir.offset(), kind));
+        }
+    }
+    spread(world, registry, spreads);
+}
+
+//Full sunlight under anything less than full sunlight only got there by assuming the sky was open above it
+fn remove_unfed_sky(
+    world: &mut LinkedChunks,
+    upper: IVec3,
+    lower: IVec3,
+    removals: &mut VecDeque<(IVec3, LightKind, u8)>,
+) {
+    let (upper_light, lower_light) = match (world.light(upper), world.light(lower)) {
+        (Some(upper), Some(lower)) => (upper, lower),
+        _ => return,
+    };
+    if lower_light.sky() == MAX_LIGHT && upper_light.sky() < MAX_LIGHT {
+        world.set_light(lower, lower_light.with(LightKind::Sky, 0));
+        removals.push_back((lower, LightKind::Sky, MAX_LIGHT));
+    }
+}
+
+//Light a block makes itself, emitting blocks and open sky above an unloaded chunk
+fn source(world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3, kind: LightKind) -> u8 {
+    let block = match world.block(pos) {
+        Some(block) => block,
+        None => return 0,
+    };
+    match kind {
+        LightKind::Block => registry.light(block),
+        LightKind::Sky if !registry.is_opaque(block) && world.block(pos + IVec3::Y).is_none() => MAX_LIGHT,
+        LightKind::Sky => 0,
+    }
+}
+
+fn seed_source(
+    world: &mut LinkedChunks,
+    registry: &BlockRegistry,
+    pos: IVec3,
+    spreads: &mut VecDeque<(IVec3, LightKind)>,
+) {
+    for kind in KINDS {
+        let level = source(world, registry, pos, kind);
+        if level == 0 {
+            continue;
+        }
+        if let Some(light) = world.light(pos) {
+            if light.get(kind) < level {
+                world.set_light(pos, light.with(kind, level));
+                spreads.push_back((pos, kind));
+            }
+        }
+    }
+}
+
+//Level light leaving a block at level reaches the next block with
+fn arriving(kind: LightKind, dir: Direction, level: u8) -> u8 {
+    if kind == LightKind::Sky && dir == Direction::Bottom && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+fn spread(world: &mut LinkedChunks, registry: &BlockRegistry, mut spreads: VecDeque<(IVec3, LightKind)>) {
+    while let Some((pos, kind)) = spreads.pop_front() {
+        let level = match world.light(pos) {
+            Some(light) => light.get(kind),
+            None => continue,
+        };
+        for dir in Direction::ALL {
+            let next = pos + dir.offset();
+            let level = arriving(kind, dir, level);
+            if level == 0 {
+                continue;
+            }
+            let (block, light) = match (world.block(next), world.light(next)) {
+                (Some(block), Some(light)) => (block, light),
+                _ => continue,
+            };
+            if !registry.is_opaque(block) && light.get(kind) < level {
+                world.set_light(next, light.with(kind, level));
+                spreads.push_back((next, kind));
+            }
+        }
+    }
+}
+
+// Darkens everything that was lit by the removed light, light from elsewhere found on the way is spread again after
+// Removed blocks have already been set to 0
+fn remove(
+    world: &mut LinkedChunks,
+    registry: &BlockRegistry,
+    mut removals: VecDeque<(IVec3, LightKind, u8)>,
+    spreads: &mut VecDeque<(IVec3, LightKind)>,
+) {
+    while let Some((pos, kind, level)) = removals.pop_front() {
+        for dir in Direction::ALL {
+            let next = pos + dir.offset();
+            let light = match world.light(next) {
+                Some(light) => light,
+                None => continue,
+            };
+            let current = light.get(kind);
+            if current == 0 {
+                continue;
+            }
+            if current <= arriving(kind, dir, level) {
+                world.set_light(next, light.with(kind, 0));
+                removals.push_back((next, kind, current));
+                seed_source(world, registry, next, spreads);
+            } else {
+                spreads.push_back((next, kind));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+
+    //Lit on its own the way the client lights a chunk as it arrives
+    fn lit_chunk(chunk_pos: IVec3, blocks: &[(IVec3, Block)], registry: &BlockRegistry) -> Arc<RwLock<Chunk>> {
+        let mut chunk = Chunk::default();
+        chunk.pos = chunk_pos;
+        for (offset, block) in blocks {
+            chunk.set_block_with_state(
+                offset.x as usize,
+                offset.y as usize,
+                offset.z as usize,
+                *block,
+                BlockState::default(),
+            );
+        }
+        let chunk = Arc::new(RwLock::new(chunk));
+        let mut world = LinkedChunks::default();
+        world.add_chunk(chunk.clone());
+        light_chunk(&mut world, registry, chunk_pos);
+        chunk
+    }
+
+    fn linked(chunks: &[Arc<RwLock<Chunk>>]) -> LinkedChunks {
+        let comps = chunks
+            .iter()
+            .map(|chunk| (chunk.read().unwrap().pos, ChunkComp::new(chunk.clone())))
+            .collect::<HashMap<IVec3, ChunkComp>>();
+        let mut world = LinkedChunks::default();
+        for (chunk_pos, comp) in comps.iter() {
+            comp.connect_neighbors(*chunk_pos, |pos| comps.get(&pos));
+            world.add_chunk(comp.shared());
+        }
+        world
+    }
+
+    fn set_block(world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3, block: Block) {
+        assert!(world.set_block_with_state(pos, block, BlockState::default()));
+        update_light(world, registry, pos);
+    }
+
+    fn light(world: &mut LinkedChunks, pos: IVec3, kind: LightKind) -> u8 {
+        world.light(pos).unwrap().get(kind)
+    }
+
+    #[test]
+    fn roof_shades_the_column_below_it() {
+        let registry = BlockRegistry::default();
+        let chunk = lit_chunk(IVec3::ZERO, &[], &registry);
+        let mut world = linked(&[chunk]);
+        let roof = IVec3::new(8, 10, 8);
+        set_block(&mut world, &registry, roof, registry.expect("metal"));
+        assert_eq!(light(&mut world, roof, LightKind::Sky), 0);
+        //Only what spills in from the open columns around it
+        for y in 0..roof.y {
+            assert_eq!(light(&mut world, IVec3::new(8, y, 8), LightKind::Sky), MAX_LIGHT - 1);
+        }
+        assert_eq!(light(&mut world, roof + IVec3::Y, LightKind::Sky), MAX_LIGHT);
+
+        set_block(&mut world, &registry, roof, Block::AIR);
+        for y in 0..=roof.y {
+            assert_eq!(light(&mut world, IVec3::new(8, y, 8), LightKind::Sky), MAX_LIGHT);
+        }
+    }
+
+    #[test]
+    fn removing_an_emitter_keeps_the_other_emitters_light() {
+        let registry = BlockRegistry::default();
+        let lava = registry.expect("lava");
+        let chunk = lit_chunk(IVec3::ZERO, &[], &registry);
+        let mut world = linked(&[chunk]);
+        let (first, second) = (IVec3::new(3, 8, 8), IVec3::new(12, 8, 8));
+        set_block(&mut world, &registry, first, lava);
+        set_block(&mut world, &registry, second, lava);
+        assert_eq!(light(&mut world, first, LightKind::Block), 15);
+        assert_eq!(light(&mut world, first - IVec3::X, LightKind::Block), 14);
+
+        set_block(&mut world, &registry, first, Block::AIR);
+        //Only the second emitter's light is left, fading with the distance from it
+        assert_eq!(light(&mut world, first, LightKind::Block), 15 - 9);
+        assert_eq!(light(&mut world, first - IVec3::X, LightKind::Block), 15 - 10);
+        assert_eq!(light(&mut world, first + IVec3::Z * 4, LightKind::Block), 15 - 13);
+        assert_eq!(light(&mut world, second, LightKind::Block), 15);
+        assert_eq!(light(&mut world, second - IVec3::X, LightKind::Block), 14);
+    }
+
+    #[test]
+    fn linking_under_a_roof_takes_the_sky_away() {
+        let registry = BlockRegistry::default();
+        let metal = registry.expect("metal");
+        let mut roof = Vec::default();
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                roof.push((IVec3::new(x, 0, z), metal));
+            }
+        }
+        let above = lit_chunk(IVec3::Y, &roof, &registry);
+        let below = lit_chunk(IVec3::ZERO, &[], &registry);
+        //Lit alone the chunk below assumed the sky was open above it
+        assert_eq!(below.read().unwrap().light(4, 4, 4).sky(), MAX_LIGHT);
+
+        let mut world = linked(&[above, below.clone()]);
+        link_chunk_light(&mut world, &registry, IVec3::ZERO);
+        let below = below.read().unwrap();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    assert_eq!(below.light(x, y, z).sky(), 0, "sky left at {} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn light_crosses_chunk_borders() {
+        let registry = BlockRegistry::default();
+        let lava = (IVec3::new(CHUNK_SIZE as i32 - 1, 8, 8), registry.expect("lava"));
+        let first = lit_chunk(IVec3::ZERO, &[lava], &registry);
+        let second = lit_chunk(IVec3::X, &[], &registry);
+        let mut world = linked(&[first, second]);
+        link_chunk_light(&mut world, &registry, IVec3::X);
+        let (pos, _) = lava;
+        assert_eq!(light(&mut world, pos + IVec3::X, LightKind::Block), 14);
+        assert_eq!(light(&mut world, pos + IVec3::X * 5, LightKind::Block), 10);
+
+        //And is taken away across them too
+        set_block(&mut world, &registry, pos, Block::AIR);
+        assert_eq!(light(&mut world, pos + IVec3::X, LightKind::Block), 0);
+        assert_eq!(light(&mut world, pos + IVec3::X * 5, LightKind::Block), 0);
+
+        set_block(&mut world, &registry, pos + IVec3::X * 2, lava.1);
+        assert_eq!(light(&mut world, pos, LightKind::Block), 13);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, RwLock};
+
+use crate::prelude::*;
+
+// Block access in world coordinates for updates that spread across chunk borders, like signals
+// Chunks are found by walking the neighbor links out from the chunks added to it,
+// so anything not connected to those chunks reads as unloaded
+#[derive(Default)]
+pub struct LinkedChunks {
+    chunks: HashMap<IVec3, Arc<RwLock<Chunk>>>,
+}
+
+impl LinkedChunks {
+    pub fn add_chunk(&mut self, chunk: Arc<RwLock<Chunk>>) {
+        let pos = chunk.read().unwrap().pos;
+        self.chunks.insert(pos, chunk);
+    }
+
+    pub fn contains_chunk(&self, chunk_pos: IVec3) -> bool {
+        self.chunks.contains_key(&chunk_pos)
+    }
+
+    fn chunk(&mut self, chunk_pos: IVec3) -> Option<Arc<RwLock<Chunk>>> {
+        if let Some(chunk) = self.chunks.get(&chunk_pos) {
+            return Some(chunk.clone());
+        }
+        //Updates spread one block at a time so the chunk is almost always next to one already found
+        let found = Direction::ALL.iter().find_map(|dir| {
+            let from = self.chunks.get(&(chunk_pos - dir.offset()))?;
+            from.read().unwrap().neighbor(*dir)
+        })?;
+        self.chunks.insert(chunk_pos, found.clone());
+        Some(found)
+    }
+
+    pub fn block_with_state(&mut self, pos: IVec3) -> Option<(Block, BlockState)> {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        let chunk = self.chunk(chunk_pos)?;
+        let chunk = chunk.read().unwrap();
+        Some(chunk.block_with_state(offset.x as usize, offset.y as usize, offset.z as usize))
+    }
+
+    pub fn block(&mut self, pos: IVec3) -> Option<Block> {
+        self.block_with_state(pos).map(|(block, _)| block)
+    }
+
+    //Dirties the same chunks a ChunkComp write would, returns false if the chunk isn't reachable
+    pub fn set_block_with_state(&mut self, pos: IVec3, block: Block, state: BlockState) -> bool {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        let chunk = match self.chunk(chunk_pos) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+        let mut chunk = chunk.write().unwrap();
+        chunk.set_block_with_state(offset.x as usize, offset.y as usize, offset.z as usize, block, state);
+        dirty_around(&mut chunk, pos);
+        true
+    }
+
+    //None if the chunk isn't reachable or was never lit
+    pub fn light(&mut self, pos: IVec3) -> Option<Light> {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        let chunk = self.chunk(chunk_pos)?;
+        let chunk = chunk.read().unwrap();
+        chunk
+            .is_lit()
+            .then(|| chunk.light(offset.x as usize, offset.y as usize, offset.z as usize))
+    }
+
+    //Faces show the light of the block in front of them so this dirties the same chunks as a block write
+    pub fn set_light(&mut self, pos: IVec3, light: Light) -> bool {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        let chunk = match self.chunk(chunk_pos) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+        let mut chunk = chunk.write().unwrap();
+        if !chunk.is_lit() {
+            return false;
+        }
+        chunk.set_light(offset.x as usize, offset.y as usize, offset.z as usize, light);
+        dirty_around(&mut chunk, pos);
+        true
+    }
+
+    //Starts the chunk's light over from darkness
+    pub fn clear_light(&mut self, chunk_pos: IVec3) -> bool {
+        match self.chunk(chunk_pos) {
+            Some(chunk) => {
+                chunk.write().unwrap().clear_light();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn schedule_tick(&mut self, pos: IVec3, delay: u64) -> bool {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        match self.chunk(chunk_pos) {
+            Some(chunk) => {
+                chunk.write().unwrap().ticks_mut().schedule(offset, delay);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+//Dirties the chunk and any neighbor the block is on the border of
+fn dirty_around(chunk: &mut Chunk, pos: IVec3) {
+    chunk.dirty = true;
+    for dir in Direction::ALL {
+        let on_border = Chunk::i_world_to_chunk(pos + dir.offset()).0 != chunk.pos;
+        if let Some(neighbor) = chunk.neighbor(dir).filter(|_| on_border) {
+            neighbor.write().unwrap().dirty = true;
+        }
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::BTreeMap;
+
+use bevy::utils::HashSet;
+
+use crate::prelude::*;
+
+// The part a block plays in signal circuits, set with `logic` in the block definitions
+// Signal strength is the power field of the block state
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogicKind {
+    //Carries the strongest neighboring signal, losing one power per block
+    Wire,
+    //Switched on and off by interacting with it
+    Lever,
+    //Powered for BUTTON_TICKS after being pressed
+    Button,
+    //Always at full power
+    Source,
+    //Reads its back and side faces and drives its front face after its delay
+    Gate(GateKind),
+    //Faces are switched on and off by the block's machine, like a microcontroller
+    Controller,
+}
+
+impl LogicKind {
+    pub fn is_interactable(&self) -> bool {
+        matches!(
+            self,
+            LogicKind::Lever | LogicKind::Button | LogicKind::Gate(_) | LogicKind::Controller
+        )
+    }
+}
+
+// Faces are relative to the gate's facing, the front is the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GateKind {
+    //Both sides powered
+    And,
+    //Either side powered
+    Or,
+    //Back unpowered
+    Not,
+    //Exactly one side powered
+    Xor,
+    //Back powered, refreshes the signal to full strength
+    Repeater,
+    //Passes the back signal through if it is at least as strong as both sides
+    Comparator,
+}
+
+impl GateKind {
+    //input gives the signal coming into each face of the gate
+    pub fn output(&self, mut input: impl FnMut(Direction) -> u8) -> u8 {
+        let full = |on: bool| if on { BlockState::MAX_POWER } else { 0 };
+        match self {
+            GateKind::And => full(input(Direction::Left) > 0 && input(Direction::Right) > 0),
+            GateKind::Or => full(input(Direction::Left) > 0 || input(Direction::Right) > 0),
+            GateKind::Not => full(input(Direction::Back) == 0),
+            GateKind::Xor => full((input(Direction::Left) > 0) != (input(Direction::Right) > 0)),
+            GateKind::Repeater => full(input(Direction::Back) > 0),
+            GateKind::Comparator => {
+                let back = input(Direction::Back);
+                if back >= input(Direction::Left).max(input(Direction::Right)) {
+                    back
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+pub const BUTTON_TICKS: u32 = 10;
+//Wire networks stop growing here so one huge network can't stall a tick
+pub const MAX_WIRE_NETWORK: usize = 4096;
+
+pub type BlockChange = (IVec3, Block, BlockState);
+
+// Circuit simulation, nothing happens until a block is scheduled so idle circuits cost nothing
+// Every step of a tick works through positions in sorted order so the same circuit always updates the same way,
+// within a tick wires settle first and then gates read them, so only gate delays take time
+#[derive(Default)]
+pub struct LogicQueue {
+    tick: u64,
+    //Blocks whose surroundings changed, the wires and gates touching them get updated next tick
+    pending: HashSet<IVec3>,
+    //Blocks counting their timer down, like pressed buttons
+    timers: HashSet<IVec3>,
+    //Gate outputs waiting out their delay, keyed by the tick they land on and kept in the order they were scheduled
+    scheduled: BTreeMap<u64, Vec<(IVec3, u8)>>,
+    //The last output scheduled for each gate and when it lands, so the same change isn't scheduled twice
+    targets: HashMap<IVec3, (u64, u8)>,
+}
+
+impl LogicQueue {
+    pub fn schedule(&mut self, pos: IVec3) {
+        self.pending.insert(pos);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.timers.is_empty() && self.scheduled.is_empty()
+    }
+
+    //Every position the next tick starts from, so the caller can make their chunks reachable
+    pub fn queued(&self) -> impl Iterator<Item = IVec3> + '_ {
+        self.pending
+            .iter()
+            .chain(self.timers.iter())
+            .copied()
+            .chain(self.scheduled.values().flatten().map(|(pos, _)| *pos))
+    }
+
+    // Timers are only stored in the block states and gate delays aren't stored at all,
+    // so a loaded chunk restarts its buttons and has its gates look at their inputs again
+    pub fn resume_chunk(&mut self, chunk: &Chunk, registry: &BlockRegistry) {
+        let needs_resume = |block: Block, state: BlockState| match registry.logic(block) {
+            Some(LogicKind::Button) => state.timer() > 0,
+            Some(LogicKind::Gate(_)) => true,
+            _ => false,
+        };
+        if !chunk
+            .storage()
+            .palette()
+            .iter()
+            .any(|(block, state)| needs_resume(*block, *state))
+        {
+            return;
+        }
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let (block, state) = chunk.block_with_state(x, y, z);
+                    if needs_resume(block, state) {
+                        let pos = chunk.pos * CHUNK_SIZE as i32 + IVec3::new(x as i32, y as i32, z as i32);
+                        if registry.logic(block) == Some(LogicKind::Button) {
+                            self.timers.insert(pos);
+                        } else {
+                            self.pending.insert(pos);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //Flips levers, presses buttons and steps gate delays, returns the new block if anything changed
+    pub fn interact(&mut self, world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3) -> Option<BlockChange> {
+        let (block, state) = world.block_with_state(pos)?;
+        let state = match registry.logic(block)? {
+            LogicKind::Lever if state.power() > 0 => state.with_power(0),
+            LogicKind::Lever => state.with_power(BlockState::MAX_POWER),
+            LogicKind::Button => {
+                self.timers.insert(pos);
+                state.with_power(BlockState::MAX_POWER).with_timer(BUTTON_TICKS)
+            }
+            LogicKind::Gate(_) => state.with_delay(state.delay() % BlockState::MAX_DELAY + 1),
+            _ => return None,
+        };
+        world.set_block_with_state(pos, block, state);
+        self.schedule(pos);
+        Some((pos, block, state))
+    }
+
+    //Advances every circuit by one tick and returns the blocks that changed
+    pub fn tick(&mut self, world: &mut LinkedChunks, registry: &BlockRegistry) -> Vec<BlockChange> {
+        self.tick += 1;
+        let mut changes = Vec::default();
+
+        for (pos, power) in self.scheduled.remove(&self.tick).unwrap_or_default() {
+            if self.targets.get(&pos).map_or(false, |(due, _)| *due == self.tick) {
+                self.targets.remove(&pos);
+            }
+            //Gates in unloaded chunks or that were replaced just drop out
+            let (block, state) = match world.block_with_state(pos) {
+                Some((block, state)) if matches!(registry.logic(block), Some(LogicKind::Gate(_))) => (block, state),
+                _ => continue,
+            };
+            if state.power() != power {
+                let state = state.with_power(power);
+                world.set_block_with_state(pos, block, state);
+                self.pending.insert(pos);
+                changes.push((pos, block, state));
+            }
+        }
+
+        for pos in sorted(self.timers.drain()) {
+            let (block, state) = match world.block_with_state(pos) {
+                Some((block, state)) if registry.logic(block) == Some(LogicKind::Button) => (block, state),
+                _ => continue,
+            };
+            let remaining = state.timer().saturating_sub(1);
+            if remaining > 0 {
+                world.set_block_with_state(pos, block, state.with_timer(remaining));
+                self.timers.insert(pos);
+            } else {
+                let state = state.with_timer(0).with_power(0);
+                world.set_block_with_state(pos, block, state);
+                self.pending.insert(pos);
+                changes.push((pos, block, state));
+            }
+        }
+
+        let mut visited = HashSet::default();
+        let mut gates = HashSet::default();
+        let first_wire_change = changes.len();
+        for pos in sorted(self.pending.drain()) {
+            for next in std::iter::once(pos).chain(Direction::ALL.iter().map(|dir| pos + dir.offset())) {
+                match world.block(next).and_then(|block| registry.logic(block)) {
+                    Some(LogicKind::Wire) if !visited.contains(&next) => {
+                        update_wire_network(world, registry, next, &mut visited, &mut changes);
+                    }
+                    Some(LogicKind::Gate(_)) => {
+                        gates.insert(next);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        //Wires that changed feed any gates next to them
+        for (pos, _, _) in changes[first_wire_change..].iter() {
+            for dir in Direction::ALL {
+                let next = *pos + dir.offset();
+                if matches!(
+                    world.block(next).and_then(|block| registry.logic(block)),
+                    Some(LogicKind::Gate(_))
+                ) {
+                    gates.insert(next);
+                }
+            }
+        }
+
+        for pos in sorted(gates.into_iter()) {
+            self.evaluate_gate(world, registry, pos);
+        }
+        changes
+    }
+
+    fn evaluate_gate(&mut self, world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3) {
+        let (block, state) = match world.block_with_state(pos) {
+            Some(block) => block,
+            None => return,
+        };
+        let gate = match registry.logic(block) {
+            Some(LogicKind::Gate(gate)) => gate,
+            _ => return,
+        };
+        let output = gate.output(|face| input_power(world, registry, pos, face.rotated_to(state.facing())));
+        let heading_to = self.targets.get(&pos).map_or(state.power(), |(_, power)| *power);
+        if output != heading_to {
+            let due = self.tick + state.delay() as u64;
+            self.scheduled.entry(due).or_default().push((pos, output));
+            self.targets.insert(pos, (due, output));
+        }
+    }
+}
+
+// What a machine gets on a logic tick, after wires and gates have settled
+// Output changes are picked up by the circuit on the next tick like any other change
+pub struct LogicContext<'a> {
+    world: &'a mut LinkedChunks,
+    registry: &'a BlockRegistry,
+    queue: &'a mut LogicQueue,
+    changes: &'a mut Vec<BlockChange>,
+    pub pos: IVec3,
+}
+
+impl<'a> LogicContext<'a> {
+    pub fn new(
+        world: &'a mut LinkedChunks,
+        registry: &'a BlockRegistry,
+        queue: &'a mut LogicQueue,
+        changes: &'a mut Vec<BlockChange>,
+        pos: IVec3,
+    ) -> Self {
+        LogicContext {
+            world,
+            registry,
+            queue,
+            changes,
+            pos,
+        }
+    }
+
+    pub fn facing(&mut self) -> Direction {
+        self.world
+            .block_with_state(self.pos)
+            .map_or(Direction::Front, |(_, state)| state.facing())
+    }
+
+    //Signal coming into the face pointing in `dir`
+    pub fn input(&mut self, dir: Direction) -> u8 {
+        input_power(self.world, self.registry, self.pos, dir)
+    }
+
+    pub fn set_output(&mut self, dir: Direction, on: bool) {
+        let (block, state) = match self.world.block_with_state(self.pos) {
+            Some(block) => block,
+            None => return,
+        };
+        if self.registry.logic(block) != Some(LogicKind::Controller) || state.output(dir) == on {
+            return;
+        }
+        let state = state.with_output(dir, on);
+        self.world.set_block_with_state(self.pos, block, state);
+        self.queue.schedule(self.pos);
+        self.changes.push((self.pos, block, state));
+    }
+}
+
+fn sorted(positions: impl Iterator<Item = IVec3>) -> Vec<IVec3> {
+    let mut positions = positions.collect::<Vec<IVec3>>();
+    positions.sort_by_key(|pos| pos.to_array());
+    positions
+}
+
+fn is_wire(world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3) -> bool {
+    world
+        .block(pos)
+        .map_or(false, |block| registry.logic(block) == Some(LogicKind::Wire))
+}
+
+//Signal a block sends out of its face pointing toward `toward`, wires are handled by the network update
+fn output_power(registry: &BlockRegistry, block: Block, state: BlockState, toward: Direction) -> u8 {
+    match registry.logic(block) {
+        Some(LogicKind::Source) => BlockState::MAX_POWER,
+        Some(LogicKind::Lever | LogicKind::Button) => state.power(),
+        Some(LogicKind::Gate(_)) if state.facing() == toward => state.power(),
+        Some(LogicKind::Controller) if state.output(toward) => BlockState::MAX_POWER,
+        _ => 0,
+    }
+}
+
+//Signal coming into pos from its neighbor in `dir`, gates read wires directly
+fn input_power(world: &mut LinkedChunks, registry: &BlockRegistry, pos: IVec3, dir: Direction) -> u8 {
+    match world.block_with_state(pos + dir.offset()) {
+        Some((block, state)) if registry.logic(block) == Some(LogicKind::Wire) => state.power(),
+        Some((block, state)) => output_power(registry, block, state, dir.opposite()),
+        None => 0,
+    }
+}
+
+//Recomputes the power of every wire connected to start from scratch,
+//partial updates can't tell when a signal was cut off somewhere else in the network
+fn update_wire_network(
+    world: &mut LinkedChunks,
+    registry: &BlockRegistry,
+    start: IVec3,
+    visited: &mut HashSet<IVec3>,
+    changes: &mut Vec<BlockChange>,
+) {
+    let mut network = vec![start];
+    visited.insert(start);
+    let mut next = 0;
+    while next < network.len() && network.len() < MAX_WIRE_NETWORK {
+        let pos = network[next];
+        next += 1;
+        for dir in Direction::ALL {
+            let neighbor = pos + dir.offset();
+            if !visited.contains(&neighbor) && is_wire(world, registry, neighbor) {
+                visited.insert(neighbor);
+                network.push(neighbor);
+            }
+        }
+    }
+
+    //Wires grouped by the strongest signal fed into them from outside the network
+    let mut power = HashMap::default();
+    let mut levels = vec![Vec::default(); BlockState::MAX_POWER as usize + 1];
+    for pos in network.iter() {
+        power.insert(*pos, 0);
+        let input = Direction::ALL
+            .iter()
+            .filter_map(|dir| {
+                let (block, state) = world.block_with_state(*pos + dir.offset())?;
+                Some(output_power(registry, block, state, dir.opposite()))
+            })
+            .max()
+            .unwrap_or(0);
+        levels[input as usize].push(*pos);
+    }
+
+    //Spreading from the strongest signal down means a wire is final the first time it is reached
+    for level in (1..=BlockState::MAX_POWER).rev() {
+        for pos in std::mem::take(&mut levels[level as usize]) {
+            let current = power.get_mut(&pos).unwrap();
+            if *current >= level {
+                continue;
+            }
+            *current = level;
+            for dir in Direction::ALL {
+                let neighbor = pos + dir.offset();
+                if power.get(&neighbor).map_or(false, |power| *power < level - 1) {
+                    levels[level as usize - 1].push(neighbor);
+                }
+            }
+        }
+    }
+
+    for pos in network {
+        let (block, state) = match world.block_with_state(pos) {
+            Some(block) => block,
+            None => continue,
+        };
+        if state.power() != power[&pos] {
+            let state = state.with_power(power[&pos]);
+            world.set_block_with_state(pos, block, state);
+            changes.push((pos, block, state));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+
+    // A few blocks of circuit in a single chunk at the origin
+    struct Circuit {
+        world: LinkedChunks,
+        queue: LogicQueue,
+        registry: BlockRegistry,
+    }
+
+    impl Circuit {
+        fn new() -> Self {
+            let mut world = LinkedChunks::default();
+            world.add_chunk(Arc::new(RwLock::new(Chunk::default())));
+            Circuit {
+                world,
+                queue: LogicQueue::default(),
+                registry: BlockRegistry::default(),
+            }
+        }
+
+        fn place(&mut self, pos: IVec3, name: &str, state: BlockState) {
+            let block = self.registry.expect(name);
+            assert!(self.world.set_block_with_state(pos, block, state));
+            self.queue.schedule(pos);
+        }
+
+        fn gate(&mut self, pos: IVec3, name: &str, facing: Direction, delay: u32) {
+            self.place(pos, name, BlockState::default().with_facing(facing).with_delay(delay));
+        }
+
+        fn interact(&mut self, pos: IVec3) {
+            self.queue.interact(&mut self.world, &self.registry, pos).unwrap();
+        }
+
+        fn tick(&mut self, ticks: usize) -> Vec<BlockChange> {
+            (0..ticks)
+                .flat_map(|_| self.queue.tick(&mut self.world, &self.registry))
+                .collect()
+        }
+
+        fn power(&mut self, pos: IVec3) -> u8 {
+            self.world.block_with_state(pos).unwrap().1.power()
+        }
+    }
+
+    // A gate at GATE facing +x with a lever on each side and a wire on the output
+    const GATE: IVec3 = IVec3::new(5, 0, 5);
+    const LEFT: IVec3 = IVec3::new(5, 0, 6);
+    const RIGHT: IVec3 = IVec3::new(5, 0, 4);
+    const BACK: IVec3 = IVec3::new(4, 0, 5);
+    const OUT: IVec3 = IVec3::new(6, 0, 5);
+
+    fn two_input_gate(name: &str) -> Circuit {
+        let mut circuit = Circuit::new();
+        circuit.gate(GATE, name, Direction::Front, 1);
+        circuit.place(LEFT, "lever", BlockState::default());
+        circuit.place(RIGHT, "lever", BlockState::default());
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.tick(2);
+        circuit
+    }
+
+    //Output for each of off/off, on/off, on/on, off/on
+    fn truth_table(name: &str) -> [u8; 4] {
+        let mut circuit = two_input_gate(name);
+        let mut outputs = [circuit.power(OUT); 4];
+        for (step, lever) in [LEFT, RIGHT, LEFT].into_iter().enumerate() {
+            circuit.interact(lever);
+            circuit.tick(2);
+            outputs[step + 1] = circuit.power(OUT);
+        }
+        outputs
+    }
+
+    #[test]
+    fn wire_loses_one_power_per_block() {
+        let mut circuit = Circuit::new();
+        circuit.place(IVec3::new(0, 0, 0), "power_source", BlockState::default());
+        for x in 1..CHUNK_SIZE as i32 {
+            circuit.place(IVec3::new(x, 0, 0), "wire", BlockState::default());
+        }
+        circuit.tick(1);
+        assert_eq!(circuit.power(IVec3::new(1, 0, 0)), 15);
+        assert_eq!(circuit.power(IVec3::new(15, 0, 0)), 1);
+
+        circuit.place(IVec3::new(0, 0, 0), "air", BlockState::default());
+        circuit.tick(1);
+        assert_eq!(circuit.power(IVec3::new(1, 0, 0)), 0);
+    }
+
+    #[test]
+    fn wire_crosses_chunk_borders() {
+        let first = ChunkComp::new(Arc::new(RwLock::new(Chunk::default())));
+        let mut chunk = Chunk::default();
+        chunk.pos = IVec3::X;
+        let second = ChunkComp::new(Arc::new(RwLock::new(chunk)));
+        second.connect_neighbors(IVec3::X, |pos| (pos == IVec3::ZERO).then_some(&first));
+
+        let mut circuit = Circuit::new();
+        circuit.world = LinkedChunks::default();
+        circuit.world.add_chunk(first.shared());
+        circuit.place(IVec3::new(14, 0, 0), "power_source", BlockState::default());
+        circuit.place(IVec3::new(15, 0, 0), "wire", BlockState::default());
+        circuit.place(IVec3::new(16, 0, 0), "wire", BlockState::default());
+        circuit.tick(1);
+        assert_eq!(second.read_chunk().state(0, 0, 0).power(), 14);
+    }
+
+    #[test]
+    fn and_gate() {
+        assert_eq!(truth_table("and_gate"), [0, 0, 15, 0]);
+    }
+
+    #[test]
+    fn or_gate() {
+        assert_eq!(truth_table("or_gate"), [0, 15, 15, 15]);
+    }
+
+    #[test]
+    fn xor_gate() {
+        assert_eq!(truth_table("xor_gate"), [0, 15, 0, 15]);
+    }
+
+    #[test]
+    fn not_gate_inverts_its_back() {
+        let mut circuit = Circuit::new();
+        circuit.gate(GATE, "not_gate", Direction::Front, 1);
+        circuit.place(BACK, "lever", BlockState::default());
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.tick(2);
+        assert_eq!(circuit.power(OUT), 15);
+        circuit.interact(BACK);
+        circuit.tick(2);
+        assert_eq!(circuit.power(OUT), 0);
+    }
+
+    #[test]
+    fn repeater_waits_its_delay() {
+        let mut circuit = Circuit::new();
+        circuit.gate(GATE, "repeater", Direction::Front, 3);
+        circuit.place(BACK, "lever", BlockState::default());
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.tick(4);
+        circuit.interact(BACK);
+
+        let mut outputs = Vec::default();
+        for _ in 0..4 {
+            circuit.tick(1);
+            outputs.push(circuit.power(OUT));
+        }
+        assert_eq!(outputs, [0, 0, 0, 15]);
+    }
+
+    #[test]
+    fn repeater_restores_full_power() {
+        let mut circuit = Circuit::new();
+        circuit.place(IVec3::new(0, 0, 5), "power_source", BlockState::default());
+        for x in 1..5 {
+            circuit.place(IVec3::new(x, 0, 5), "wire", BlockState::default());
+        }
+        circuit.gate(GATE, "repeater", Direction::Front, 1);
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.tick(2);
+        assert_eq!(circuit.power(BACK), 12);
+        assert_eq!(circuit.power(OUT), 15);
+    }
+
+    #[test]
+    fn gates_follow_their_facing() {
+        let mut circuit = Circuit::new();
+        //Facing -z the back is +z and the output is -z
+        circuit.gate(GATE, "repeater", Direction::Right, 1);
+        circuit.place(LEFT, "power_source", BlockState::default());
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.place(RIGHT, "wire", BlockState::default());
+        circuit.tick(2);
+        assert_eq!(circuit.power(RIGHT), 15);
+        assert_eq!(circuit.power(OUT), 0);
+    }
+
+    #[test]
+    fn comparator_passes_back_signal_when_strongest() {
+        let mut circuit = Circuit::new();
+        circuit.place(IVec3::new(2, 0, 5), "power_source", BlockState::default());
+        circuit.place(IVec3::new(3, 0, 5), "wire", BlockState::default());
+        circuit.place(BACK, "wire", BlockState::default());
+        circuit.gate(GATE, "comparator", Direction::Front, 1);
+        circuit.place(LEFT, "lever", BlockState::default());
+        circuit.place(OUT, "wire", BlockState::default());
+        circuit.tick(2);
+        assert_eq!(circuit.power(OUT), 14);
+
+        circuit.interact(LEFT);
+        circuit.tick(2);
+        assert_eq!(circuit.power(OUT), 0);
+    }
+
+    #[test]
+    fn button_turns_itself_off() {
+        let mut circuit = Circuit::new();
+        circuit.place(BACK, "button", BlockState::default());
+        circuit.place(GATE, "wire", BlockState::default());
+        circuit.interact(BACK);
+        circuit.tick(1);
+        assert_eq!(circuit.power(GATE), 15);
+        circuit.tick(BUTTON_TICKS as usize);
+        assert_eq!(circuit.power(GATE), 0);
+        assert!(circuit.queue.is_idle());
+    }
+
+    //A not gate feeding its own back through a wire loop blinks forever
+    fn clock() -> Circuit {
+        let mut circuit = Circuit::new();
+        circuit.gate(GATE, "not_gate", Direction::Front, 2);
+        for pos in [
+            OUT,
+            IVec3::new(6, 0, 6),
+            IVec3::new(5, 0, 7),
+            IVec3::new(4, 0, 7),
+            IVec3::new(4, 0, 6),
+            BACK,
+        ] {
+            circuit.place(pos, "wire", BlockState::default());
+        }
+        circuit.place(IVec3::new(6, 0, 7), "wire", BlockState::default());
+        circuit
+    }
+
+    #[test]
+    fn clock_blinks_with_its_delay() {
+        let mut circuit = clock();
+        let mut outputs = Vec::default();
+        for _ in 0..8 {
+            circuit.tick(1);
+            outputs.push(circuit.power(OUT));
+        }
+        assert_eq!(outputs, [0, 0, 15, 15, 0, 0, 15, 15]);
+    }
+
+    #[test]
+    fn identical_circuits_update_identically() {
+        let mut first = clock();
+        let mut second = clock();
+        assert_eq!(first.tick(50), second.tick(50));
+    }
+}
@@ -1,13 +1,29 @@
 pub mod block;
+pub mod block_registry;
 pub mod chunk;
+pub mod chunk_storage;
 pub mod chunk_updating;
 pub mod client;
 pub mod direction;
+pub mod fluid;
+pub mod light;
+pub mod linked_chunks;
+pub mod logic;
+pub mod rules;
 pub mod server;
+pub mod structure;
 
 pub use block::*;
+pub use block_registry::*;
 pub use chunk::*;
+pub use chunk_storage::*;
 pub use chunk_updating::*;
 pub use client::*;
 pub use direction::Direction;
+pub use fluid::*;
+pub use light::*;
+pub use linked_chunks::*;
+pub use logic::*;
+pub use rules::*;
 pub use server::*;
+pub use structure::*;
@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use bevy::utils::HashSet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::prelude::*;
+
+//Blocks picked in every chunk each tick for random tick rules
+pub const RANDOM_TICKS_PER_CHUNK: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleSchedule {
+    //Runs on every matching block in every loaded chunk, once every N ticks
+    Every(u64),
+    //Runs when a matching block is one of the RANDOM_TICKS_PER_CHUNK picked in its chunk
+    Random,
+    //Runs when a tick scheduled for a matching block comes due, see RuleContext::schedule_tick
+    Scheduled,
+}
+
+// Blocks in a chunk waiting for a scheduled tick, saved with the chunk
+// Due ticks count the ticks this chunk has been loaded for, so time stops for pending ticks while it's unloaded
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct TickQueue {
+    now: u64,
+    due: BTreeMap<u64, Vec<IVec3>>,
+}
+
+impl TickQueue {
+    //A delay of 0 still waits for the next tick
+    pub fn schedule(&mut self, offset: IVec3, delay: u64) {
+        self.due.entry(self.now + delay.max(1)).or_default().push(offset);
+    }
+
+    //Steps the chunk forward a tick and returns the blocks due, a block scheduled twice for one tick only ticks once
+    pub fn advance(&mut self) -> Vec<IVec3> {
+        self.now += 1;
+        let mut offsets = self.due.remove(&self.now).unwrap_or_default();
+        offsets.sort_by_key(|offset| offset.to_array());
+        offsets.dedup();
+        offsets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.due.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.due.values().map(Vec::len).sum()
+    }
+}
+
+// A cellular automaton rule, registered once with App::register_rule
+pub struct CellRule {
+    pub name: &'static str,
+    //Name of the block the rule runs on
+    pub block: &'static str,
+    pub schedule: RuleSchedule,
+    pub apply: fn(&mut RuleContext),
+    //Most blocks the rule runs on in one tick, scheduled ticks past it wait for the next tick and others are skipped
+    pub budget: Option<usize>,
+}
+
+// What a rule sees of the world, reads are of the world as it was at the start of the tick
+// and writes are held until every rule has run so no rule sees another rule's writes
+pub struct RuleContext<'a> {
+    world: &'a mut LinkedChunks,
+    writes: &'a mut Vec<BlockChange>,
+    scheduled: &'a mut Vec<(IVec3, u64)>,
+    detached: &'a mut Vec<BlockChange>,
+    rng: &'a mut StdRng,
+    pub registry: &'a BlockRegistry,
+    pub pos: IVec3,
+    pub block: Block,
+    pub state: BlockState,
+}
+
+impl<'a> RuleContext<'a> {
+    //None if the chunk isn't loaded
+    pub fn get(&mut self, pos: IVec3) -> Option<(Block, BlockState)> {
+        self.world.block_with_state(pos)
+    }
+
+    pub fn neighbor(&mut self, dir: Direction) -> Option<Block> {
+        self.world.block(self.pos + dir.offset())
+    }
+
+    //Can be anywhere, including other chunks, writes into chunks that aren't loaded are dropped
+    pub fn set(&mut self, pos: IVec3, block: Block, state: BlockState) {
+        self.writes.push((pos, block, state));
+    }
+
+    pub fn set_block(&mut self, pos: IVec3, block: Block) {
+        self.set(pos, block, BlockState::default());
+    }
+
+    //Held like writes, the tick lands on whatever block is there by then
+    pub fn schedule_tick(&mut self, pos: IVec3, delay: u64) {
+        self.scheduled.push((pos, delay));
+    }
+
+    //Takes the block out of the grid so the server can simulate it as a physics body, see RuleTick::detached
+    //Its neighbors get a tick so blocks it was holding up, like the rest of a stack of sand, can fall after it
+    pub fn detach(&mut self, pos: IVec3) {
+        if let Some((block, state)) = self.get(pos) {
+            self.set_block(pos, Block::AIR);
+            self.detached.push((pos, block, state));
+            for dir in Direction::ALL {
+                self.schedule_tick(pos + dir.offset(), 1);
+            }
+        }
+    }
+
+    //Rules share the engine's seeded rng so a tick plays out the same way for the same world
+    pub fn random(&mut self, below: u32) -> u32 {
+        self.rng.gen_range(0..below)
+    }
+}
+
+#[derive(Default)]
+pub struct RuleTick {
+    //Blocks that actually changed, they have already been written to the world
+    pub changes: Vec<BlockChange>,
+    //Blocks rules took out of the grid, as they were before being replaced with air
+    pub detached: Vec<BlockChange>,
+}
+
+// Runs the registered rules on a fixed tick
+// Chunks are visited in sorted order and rules in the order they were registered,
+// when two rules write the same block in one tick the last write wins
+pub struct RuleEngine {
+    tick: u64,
+    rules: Vec<CellRule>,
+    rng: StdRng,
+}
+
+impl Default for RuleEngine {
+    fn default() -> Self {
+        RuleEngine {
+            tick: 0,
+            rules: Vec::default(),
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+impl RuleEngine {
+    pub fn register(&mut self, rule: CellRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &CellRule> {
+        self.rules.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn tick(
+        &mut self,
+        world: &mut LinkedChunks,
+        chunks: &[Arc<RwLock<Chunk>>],
+        registry: &BlockRegistry,
+    ) -> RuleTick {
+        self.tick += 1;
+        //Rules for blocks the registry doesn't have just never run
+        let rules = self
+            .rules
+            .iter()
+            .filter_map(|rule| Some((registry.id(rule.block)?, rule)))
+            .collect::<Vec<_>>();
+        let mut chunks = chunks
+            .iter()
+            .map(|chunk| (chunk.read().unwrap().pos, chunk))
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|(pos, _)| pos.to_array());
+        //Start somewhere else every tick so rules out of budget don't always starve the same chunks
+        if !chunks.is_empty() {
+            let start = self.tick as usize % chunks.len();
+            chunks.rotate_left(start);
+        }
+
+        let mut writes = Vec::default();
+        let mut scheduled = Vec::default();
+        let mut detached = Vec::default();
+        let mut runs = vec![0; rules.len()];
+        for (chunk_pos, chunk) in chunks {
+            let ticked = chunk.write().unwrap().ticks_mut().advance();
+            let cells = due_cells(
+                self.tick,
+                &mut self.rng,
+                chunk_pos,
+                &chunk.read().unwrap(),
+                &ticked,
+                &rules,
+            );
+            for (pos, block, state, index, from_queue) in cells {
+                let (_, rule) = rules[index];
+                if rule.budget.map_or(false, |budget| runs[index] >= budget) {
+                    if from_queue {
+                        scheduled.push((pos, 1));
+                    }
+                    continue;
+                }
+                runs[index] += 1;
+                (rule.apply)(&mut RuleContext {
+                    world: &mut *world,
+                    writes: &mut writes,
+                    scheduled: &mut scheduled,
+                    detached: &mut detached,
+                    rng: &mut self.rng,
+                    registry,
+                    pos,
+                    block,
+                    state,
+                });
+            }
+        }
+        for (pos, delay) in scheduled {
+            world.schedule_tick(pos, delay);
+        }
+        let changes = apply_writes(world, writes);
+        //A later write to the same block keeps it in the grid, and a block can only be detached once
+        let mut removed = changes
+            .iter()
+            .filter(|(_, block, _)| block.is_air())
+            .map(|(pos, _, _)| *pos)
+            .collect::<HashSet<IVec3>>();
+        detached.retain(|(pos, _, _)| removed.remove(pos));
+        RuleTick { changes, detached }
+    }
+}
+
+//Every block in the chunk due for a rule this tick with the index of the rule and whether it was a scheduled tick,
+//scheduled ticks come first then the rest in the order the rules run
+fn due_cells(
+    tick: u64,
+    rng: &mut StdRng,
+    chunk_pos: IVec3,
+    chunk: &Chunk,
+    ticked: &[IVec3],
+    rules: &[(Block, &CellRule)],
+) -> Vec<(IVec3, Block, BlockState, usize, bool)> {
+    let origin = chunk_pos * CHUNK_SIZE as i32;
+    let mut cells = Vec::default();
+    for offset in ticked {
+        let (block, state) = chunk.block_with_state(offset.x as usize, offset.y as usize, offset.z as usize);
+        for (index, (target, rule)) in rules.iter().enumerate() {
+            if rule.schedule == RuleSchedule::Scheduled && block == *target {
+                cells.push((origin + *offset, block, state, index, true));
+            }
+        }
+    }
+    for (index, (target, rule)) in rules.iter().enumerate() {
+        let due = matches!(rule.schedule, RuleSchedule::Every(ticks) if tick % ticks.max(1) == 0);
+        //Most chunks don't have the block at all
+        if !due || !chunk.storage().palette().iter().any(|(block, _)| block == target) {
+            continue;
+        }
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let (block, state) = chunk.block_with_state(x, y, z);
+                    if block == *target {
+                        let pos = origin + IVec3::new(x as i32, y as i32, z as i32);
+                        cells.push((pos, block, state, index, false));
+                    }
+                }
+            }
+        }
+    }
+    for _ in 0..RANDOM_TICKS_PER_CHUNK {
+        let (x, y, z) = (
+            rng.gen_range(0..CHUNK_SIZE),
+            rng.gen_range(0..CHUNK_SIZE),
+            rng.gen_range(0..CHUNK_SIZE),
+        );
+        let (block, state) = chunk.block_with_state(x, y, z);
+        let pos = origin + IVec3::new(x as i32, y as i32, z as i32);
+        for (index, (target, rule)) in rules.iter().enumerate() {
+            if rule.schedule == RuleSchedule::Random && block == *target {
+                cells.push((pos, block, state, index, false));
+            }
+        }
+    }
+    cells
+}
+
+//Later writes to a block replace earlier ones and writes that wouldn't change anything are dropped
+fn apply_writes(world: &mut LinkedChunks, writes: Vec<BlockChange>) -> Vec<BlockChange> {
+    let mut last_write = HashMap::default();
+    for (index, (pos, _, _)) in writes.iter().enumerate() {
+        last_write.insert(*pos, index);
+    }
+    writes
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (pos, _, _))| last_write[pos] == *index)
+        .map(|(_, change)| change)
+        .filter(|(pos, block, state)| {
+            world
+                .block_with_state(*pos)
+                .map_or(false, |current| current != (*block, *state))
+                && world.set_block_with_state(*pos, *block, *state)
+        })
+        .collect()
+}
+
+pub trait RegisterRule {
+    fn register_rule(&mut self, rule: CellRule) -> &mut Self;
+}
+
+impl RegisterRule for App {
+    fn register_rule(&mut self, rule: CellRule) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(RuleEngine::default)
+            .register(rule);
+        self
+    }
+}
@@ -0,0 +1,331 @@
+use bevy::utils::{FloatOrd, HashSet};
+use bevy_rapier3d::prelude::*;
+
+use crate::prelude::*;
+use crate::server::{
+    server_chunks::{PendingBlockUpdates, ServerLoadedChunks},
+    server_logic::linked_chunks,
+};
+
+//How long a falling body has to stay still before it turns back into blocks
+const REST_SECONDS: f32 = 0.3;
+const REST_SPEED: f32 = 0.1;
+//Bodies still moving by then are put back where they are, so one stuck rolling around can't live forever
+const MAX_FALL_SECONDS: f32 = 30.0;
+//Chunks this many chunks from a falling block get a collider on the server
+const TERRAIN_COLLIDER_RADIUS: i32 = 1;
+//How far a landed block looks for an empty cell when its own is taken
+const SETTLE_SEARCH_RADIUS: i32 = 2;
+
+// Blocks taken out of the grid and simulated as one rigid body until it comes to rest
+// Positions are offsets from the body's origin, which starts on the lowest corner of the blocks
+#[derive(Component)]
+pub struct FallingBody {
+    pub blocks: Vec<BlockChange>,
+    still_seconds: f32,
+    fall_seconds: f32,
+}
+
+//Server chunks only have colliders while a falling body is near them
+#[derive(Component)]
+pub struct TerrainCollider;
+
+// Scheduled rule for blocks like sand, they fall as soon as nothing solid is under them
+pub fn fall_when_unsupported(context: &mut RuleContext) {
+    let below = context.get(context.pos - IVec3::Y);
+    if below.map_or(false, |(below, _)| !context.registry.is_solid(below)) {
+        context.detach(context.pos);
+    }
+}
+
+//Blocks have to be out of the grid already, they all move together
+pub fn spawn_falling_body(commands: &mut Commands, blocks: &[BlockChange]) {
+    let origin = blocks
+        .iter()
+        .map(|(pos, _, _)| *pos)
+        .reduce(IVec3::min)
+        .expect("Falling bodies need at least one block");
+    let blocks = blocks
+        .iter()
+        .map(|(pos, block, state)| (*pos - origin, *block, *state))
+        .collect::<Vec<_>>();
+    //A little under a block so single blocks drop through one block gaps
+    let collider = Collider::compound(
+        blocks
+            .iter()
+            .map(|(offset, _, _)| {
+                (
+                    offset.as_vec3() + Vec3::splat(0.5),
+                    Quat::IDENTITY,
+                    Collider::cuboid(0.48, 0.48, 0.48),
+                )
+            })
+            .collect(),
+    );
+    commands
+        .spawn_bundle(TransformBundle::from(Transform::from_translation(origin.as_vec3())))
+        .insert(RigidBody::Dynamic)
+        .insert(collider)
+        .insert(CollisionGroups::new(SERVER_COLLISION_GROUP, SERVER_COLLISION_GROUP))
+        .insert(Velocity::default())
+        .insert(FallingBody {
+            blocks,
+            still_seconds: 0.0,
+            fall_seconds: 0.0,
+        })
+        .insert(Name::new("Falling Body"));
+}
+
+// Called after a block is broken, every structure it was the last thing holding up breaks off as its own body
+pub fn break_off_islands(
+    commands: &mut Commands,
+    world: &mut LinkedChunks,
+    registry: &BlockRegistry,
+    logic: &mut LogicQueue,
+    updates: &mut PendingBlockUpdates,
+    broken: IVec3,
+) {
+    let mut visited = HashSet::default();
+    for dir in Direction::ALL {
+        let island = match find_island(world, registry, broken + dir.offset(), &mut visited) {
+            Some(island) => island,
+            None => continue,
+        };
+        let blocks = island
+            .into_iter()
+            .filter_map(|pos| world.block_with_state(pos).map(|(block, state)| (pos, block, state)))
+            .collect::<Vec<_>>();
+        for (pos, _, _) in blocks.iter() {
+            world.set_block_with_state(*pos, Block::AIR, BlockState::default());
+            logic.schedule(*pos);
+            updates.push((*pos, Block::AIR, BlockState::default()));
+            //Fluids flow into the hole and sand on the edge loses its support
+            for dir in Direction::ALL {
+                world.schedule_tick(*pos + dir.offset(), 1);
+            }
+        }
+        info!("{} blocks broke off next to {}", blocks.len(), broken);
+        spawn_falling_body(commands, &blocks);
+    }
+}
+
+// Gives the chunks around falling bodies a collider and takes it away again once they are gone
+pub fn server_terrain_colliders(
+    mut commands: Commands,
+    loaded_chunks: Res<ServerLoadedChunks>,
+    chunks: Query<(&ChunkComp, Option<&TerrainCollider>)>,
+    falling: Query<(&Transform, &FallingBody)>,
+    registry: Res<BlockRegistry>,
+) {
+    let occupied = falling
+        .iter()
+        .flat_map(|(transform, body)| {
+            body.blocks
+                .iter()
+                .map(|(offset, _, _)| block_center(transform, *offset))
+        })
+        .map(|center| Chunk::world_to_chunk(center).0)
+        .collect::<HashSet<IVec3>>();
+    let mut near = HashSet::default();
+    for chunk_pos in occupied {
+        for x in -TERRAIN_COLLIDER_RADIUS..=TERRAIN_COLLIDER_RADIUS {
+            for y in -TERRAIN_COLLIDER_RADIUS..=TERRAIN_COLLIDER_RADIUS {
+                for z in -TERRAIN_COLLIDER_RADIUS..=TERRAIN_COLLIDER_RADIUS {
+                    near.insert(chunk_pos + IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+
+    for (chunk_pos, entity) in loaded_chunks.ent_map.iter() {
+        let (chunk, collider) = match chunks.get(*entity) {
+            Ok(chunk) => chunk,
+            Err(_) => continue,
+        };
+        if !near.contains(chunk_pos) {
+            if collider.is_some() {
+                commands
+                    .entity(*entity)
+                    .remove::<TerrainCollider>()
+                    .remove::<Collider>();
+            }
+            continue;
+        }
+        if collider.is_some() && !chunk.read_dirty() {
+            continue;
+        }
+        let description = create_mesh_description(&chunk.read_chunk(), &registry);
+        chunk.write_dirty(false);
+        let mut entity = commands.entity(*entity);
+        entity
+            .insert(TerrainCollider)
+            .insert_bundle(TransformBundle::from(Transform::from_translation(
+                (*chunk_pos * CHUNK_SIZE as i32).as_vec3(),
+            )));
+        match create_collider(description) {
+            Some(collider) => {
+                entity
+                    .insert(collider)
+                    .insert(CollisionGroups::new(SERVER_COLLISION_GROUP, SERVER_COLLISION_GROUP));
+            }
+            None => {
+                entity.remove::<Collider>();
+            }
+        }
+    }
+}
+
+// Puts falling bodies that have come to rest back into the grid, the clients hear about it like any other block update
+pub fn server_settle_falling_blocks(
+    mut commands: Commands,
+    loaded_chunks: Res<ServerLoadedChunks>,
+    comps: Query<&ChunkComp>,
+    registry: Res<BlockRegistry>,
+    mut falling: Query<(Entity, &Transform, &Velocity, &mut FallingBody)>,
+    mut logic: ResMut<LogicQueue>,
+    mut updates: ResMut<PendingBlockUpdates>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    let mut settled = Vec::default();
+    for (entity, transform, velocity, mut body) in falling.iter_mut() {
+        body.fall_seconds += delta;
+        if velocity.linvel.length() < REST_SPEED && velocity.angvel.length() < REST_SPEED {
+            body.still_seconds += delta;
+        } else {
+            body.still_seconds = 0.0;
+        }
+        if body.still_seconds >= REST_SECONDS || body.fall_seconds >= MAX_FALL_SECONDS {
+            let blocks = body
+                .blocks
+                .iter()
+                .map(|(offset, block, state)| (block_center(transform, *offset), *block, *state))
+                .collect::<Vec<_>>();
+            settled.push((entity, blocks));
+        }
+    }
+    if settled.is_empty() {
+        return;
+    }
+
+    let mut world = linked_chunks(
+        &loaded_chunks,
+        &comps,
+        settled
+            .iter()
+            .flat_map(|(_, blocks)| blocks.iter().map(|(center, _, _)| center.floor().as_ivec3())),
+    );
+    //Sorted so two bodies landing on the same cells in one frame always end up the same way around
+    settled.sort_by_key(|(entity, _)| entity.to_bits());
+    for (entity, mut blocks) in settled {
+        commands.entity(entity).despawn_recursive();
+        //Lowest first so the blocks on top pile onto the ones below instead of taking their cells
+        blocks.sort_by_key(|(center, _, _)| FloatOrd(center.y));
+        for (center, block, state) in blocks {
+            let pos = match nearest_empty_cell(&mut world, &registry, center) {
+                Some(pos) => pos,
+                None => {
+                    warn!(
+                        "Nowhere to put falling {} at {}, dropping it",
+                        registry.name(block),
+                        center
+                    );
+                    continue;
+                }
+            };
+            world.set_block_with_state(pos, block, state);
+            //The block may have landed on something that isn't there anymore, and fluids have to flow around it
+            world.schedule_tick(pos, 1);
+            for dir in Direction::ALL {
+                world.schedule_tick(pos + dir.offset(), 1);
+            }
+            logic.schedule(pos);
+            updates.push((pos, block, state));
+        }
+    }
+}
+
+fn block_center(transform: &Transform, offset: IVec3) -> Vec3 {
+    transform.transform_point(offset.as_vec3() + Vec3::splat(0.5))
+}
+
+//The cell the center is in if it's empty, otherwise the closest empty one around it, fluids count as empty
+fn nearest_empty_cell(world: &mut LinkedChunks, registry: &BlockRegistry, center: Vec3) -> Option<IVec3> {
+    let cell = center.floor().as_ivec3();
+    let mut cells = Vec::default();
+    for x in -SETTLE_SEARCH_RADIUS..=SETTLE_SEARCH_RADIUS {
+        for y in -SETTLE_SEARCH_RADIUS..=SETTLE_SEARCH_RADIUS {
+            for z in -SETTLE_SEARCH_RADIUS..=SETTLE_SEARCH_RADIUS {
+                cells.push(cell + IVec3::new(x, y, z));
+            }
+        }
+    }
+    cells.sort_by_key(|pos| FloatOrd((pos.as_vec3() + Vec3::splat(0.5)).distance_squared(center)));
+    cells.into_iter().find(|pos| {
+        world
+            .block(*pos)
+            .map_or(false, |block| block.is_air() || registry.fluid(block).is_some())
+    })
+}
+
+// New bodies are sent reliably with their blocks, after that only where they are is sent
+// Positions are unreliable so clients only ever draw the latest ones, a client that misses a few frames just sees a jump
+// Clients that connect while a body is falling never hear about it and just see its blocks land
+pub fn server_send_falling_blocks(
+    new_bodies: Query<(Entity, &Transform, &FallingBody), Added<FallingBody>>,
+    falling: Query<(Entity, &Transform), With<FallingBody>>,
+    mut server: ResMut<RenetServer>,
+) {
+    for (entity, transform, body) in new_bodies.iter() {
+        let message = ServerBlockMessage::FallingBody(entity.to_bits(), transform.translation, body.blocks.clone());
+        let _ = message.broadcast(&mut server);
+    }
+    let snapshots = falling
+        .iter()
+        .map(|(entity, transform)| FallingBodySnapshot {
+            id: entity.to_bits(),
+            translation: transform.translation,
+            rotation: transform.rotation,
+        })
+        .collect::<Vec<_>>();
+    for snapshots in snapshots.chunks(MAX_FALLING_BODIES_PER_MESSAGE) {
+        let _ = ServerMessage::FallingBodies(snapshots.to_vec()).broadcast(&mut server);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+
+    #[test]
+    fn stack_of_sand_falls_together() {
+        let registry = BlockRegistry::default();
+        let sand = registry.expect("sand");
+        let chunk = Arc::new(RwLock::new(Chunk::default()));
+        let mut world = LinkedChunks::default();
+        world.add_chunk(chunk.clone());
+        let mut rules = RuleEngine::default();
+        rules.register(CellRule {
+            name: "sand_fall",
+            block: "sand",
+            schedule: RuleSchedule::Scheduled,
+            apply: fall_when_unsupported,
+            budget: None,
+        });
+        let stack = [1, 2, 3].map(|y| IVec3::new(4, y, 4));
+        for pos in stack {
+            assert!(world.set_block_with_state(pos, sand, BlockState::default()));
+        }
+        world.schedule_tick(stack[0], 1);
+
+        let mut detached = Vec::default();
+        for _ in 0..stack.len() {
+            let tick = rules.tick(&mut world, &[chunk.clone()], &registry);
+            detached.extend(tick.detached.into_iter().map(|(pos, _, _)| pos));
+        }
+        assert_eq!(detached, stack);
+        assert!(stack.iter().all(|pos| world.block(*pos) == Some(Block::AIR)));
+    }
+}
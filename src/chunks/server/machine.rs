@@ -0,0 +1,278 @@
+use std::any::Any;
+
+use serde::de::DeserializeOwned;
+
+use crate::prelude::*;
+use crate::server::server_chunks::ServerLoadedChunks;
+
+// Machines are the server side state of blocks like furnaces
+// Every machine type implements Machine and is registered once with App::register_machine,
+// after that blocks can name it in their definition and spawning, ticking and saving are all handled here
+pub trait Machine: Default + Serialize + DeserializeOwned + Send + Sync + 'static {
+    //Used by block definitions and save files, renaming a machine needs a save migration
+    const NAME: &'static str;
+
+    fn tick(&mut self, _context: &mut MachineContext) {}
+
+    //Only called for blocks with `logic: Some(Controller)`, on the fixed logic tick
+    fn logic_tick(&mut self, _context: &mut LogicContext) {}
+}
+
+// Everything a machine can see while ticking
+pub struct MachineContext<'a> {
+    pub chunk: &'a ChunkComp,
+    //Offset of the machine's block inside the chunk
+    pub offset: IVec3,
+    pub delta_seconds: f32,
+}
+
+//Object safe side of Machine so every machine type can live in the same component
+pub trait DynMachine: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn tick(&mut self, context: &mut MachineContext);
+    fn logic_tick(&mut self, context: &mut LogicContext);
+    fn save(&self) -> Vec<u8>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<M: Machine> DynMachine for M {
+    fn name(&self) -> &'static str {
+        M::NAME
+    }
+
+    fn tick(&mut self, context: &mut MachineContext) {
+        Machine::tick(self, context);
+    }
+
+    fn logic_tick(&mut self, context: &mut LogicContext) {
+        Machine::logic_tick(self, context);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Component)]
+pub struct MachineComp(pub Box<dyn DynMachine>);
+
+impl MachineComp {
+    pub fn get<M: Machine>(&self) -> Option<&M> {
+        self.0.as_any().downcast_ref()
+    }
+
+    pub fn get_mut<M: Machine>(&mut self) -> Option<&mut M> {
+        self.0.as_any_mut().downcast_mut()
+    }
+}
+
+// Links a machine entity back to the block it lives in
+#[derive(Component, Clone)]
+pub struct BlockController {
+    pub block: Block,
+    pub chunk_pos: IVec3,
+    //Offset of the block inside its chunk
+    pub offset: IVec3,
+}
+
+struct MachineHooks {
+    create: fn() -> Box<dyn DynMachine>,
+    load: fn(&[u8]) -> Result<Box<dyn DynMachine>, bincode::Error>,
+}
+
+#[derive(Default)]
+pub struct MachineRegistry {
+    machines: HashMap<&'static str, MachineHooks>,
+}
+
+impl MachineRegistry {
+    pub fn register<M: Machine>(&mut self) {
+        let hooks = MachineHooks {
+            create: create_machine::<M>,
+            load: load_machine::<M>,
+        };
+        if self.machines.insert(M::NAME, hooks).is_some() {
+            warn!("Machine {} was registered twice", M::NAME);
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.machines.contains_key(name)
+    }
+
+    pub fn spawn_machine(
+        &self,
+        commands: &mut Commands,
+        name: &str,
+        block: Block,
+        chunk_pos: IVec3,
+        offset: IVec3,
+    ) -> Option<Entity> {
+        let hooks = self.machines.get(name)?;
+        Some(spawn_machine_entity(
+            commands,
+            (hooks.create)(),
+            block,
+            chunk_pos,
+            offset,
+        ))
+    }
+}
+
+fn create_machine<M: Machine>() -> Box<dyn DynMachine> {
+    Box::new(M::default())
+}
+
+fn load_machine<M: Machine>(bytes: &[u8]) -> Result<Box<dyn DynMachine>, bincode::Error> {
+    Ok(Box::new(bincode::deserialize::<M>(bytes)?))
+}
+
+fn spawn_machine_entity(
+    commands: &mut Commands,
+    machine: Box<dyn DynMachine>,
+    block: Block,
+    chunk_pos: IVec3,
+    offset: IVec3,
+) -> Entity {
+    commands
+        .spawn()
+        .insert(MachineComp(machine))
+        .insert(BlockController {
+            block,
+            chunk_pos,
+            offset,
+        })
+        .insert(Name::new(format!("Machine {} {}", chunk_pos, offset)))
+        .id()
+}
+
+pub trait RegisterMachine {
+    fn register_machine<M: Machine>(&mut self) -> &mut Self;
+}
+
+impl RegisterMachine for App {
+    fn register_machine<M: Machine>(&mut self) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(MachineRegistry::default)
+            .register::<M>();
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SerializedMachine {
+    pub name: String,
+    pub offset: IVec3,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SerializedMachines {
+    pub machines: Vec<SerializedMachine>,
+}
+
+pub type MachineQuery<'w, 's> = Query<'w, 's, (&'static MachineComp, &'static BlockController)>;
+
+impl SerializedMachines {
+    pub fn serialize_all_machines(
+        //Usually from a chunks associated entities
+        entities: impl Iterator<Item = Entity>,
+        machine_query: &MachineQuery,
+    ) -> SerializedMachines {
+        let machines = entities
+            .filter_map(|entity| machine_query.get(entity).ok())
+            .map(|(machine, controller)| SerializedMachine {
+                name: machine.0.name().to_string(),
+                offset: controller.offset,
+                data: machine.0.save(),
+            })
+            .collect();
+        SerializedMachines { machines }
+    }
+
+    //Spawns every machine and returns each one with its block offset so it can be associated with the chunk
+    //Machines that fail to load or no longer match their block are dropped, the server gives their blocks a fresh one
+    pub fn spawn_all(
+        self,
+        commands: &mut Commands,
+        registry: &BlockRegistry,
+        machines: &MachineRegistry,
+        chunk: &Chunk,
+    ) -> Vec<(IVec3, Entity)> {
+        let mut spawned = Vec::default();
+        for saved in self.machines {
+            let offset = saved.offset;
+            let block = chunk.block(offset.x as usize, offset.y as usize, offset.z as usize);
+            //The block was replaced or its definition changed since the save, its machine would run on the wrong block
+            if registry.get(block).machine.as_deref() != Some(saved.name.as_str()) {
+                warn!(
+                    "Dropping saved {} at {} which is now {}",
+                    saved.name,
+                    offset,
+                    registry.name(block)
+                );
+                continue;
+            }
+            let machine = match machines.machines.get(saved.name.as_str()) {
+                Some(hooks) => (hooks.load)(&saved.data),
+                None => {
+                    warn!("Dropping saved machine of unknown type {}", saved.name);
+                    continue;
+                }
+            };
+            match machine {
+                Ok(machine) => {
+                    let entity = spawn_machine_entity(commands, machine, block, chunk.pos, offset);
+                    spawned.push((offset, entity));
+                }
+                Err(err) => warn!("Dropping saved {} that failed to load: {}", saved.name, err),
+            }
+        }
+        spawned
+    }
+}
+
+pub fn tick_machines(
+    loaded_chunks: Res<ServerLoadedChunks>,
+    chunks: Query<&ChunkComp>,
+    mut machines: Query<(&mut MachineComp, &BlockController)>,
+    time: Res<Time>,
+) {
+    for (mut machine, controller) in &mut machines {
+        let chunk = match loaded_chunks.ent_map.get(&controller.chunk_pos) {
+            Some(chunk) => chunks.get(*chunk),
+            None => continue,
+        };
+        if let Ok(chunk) = chunk {
+            machine.0.tick(&mut MachineContext {
+                chunk,
+                offset: controller.offset,
+                delta_seconds: time.delta_seconds(),
+            });
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Furnace {
+    heat: f32,
+}
+
+impl Default for Furnace {
+    fn default() -> Self {
+        Furnace { heat: 100.0 }
+    }
+}
+
+impl Machine for Furnace {
+    const NAME: &'static str = "furnace";
+}
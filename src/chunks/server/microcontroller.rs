@@ -0,0 +1,472 @@
+use crate::prelude::*;
+use crate::server::{machine::*, server_chunks::ServerLoadedChunks};
+
+pub const REGISTERS: usize = 8;
+//Instructions a controller may run per logic tick, a program that never waits just picks up where it stopped next tick
+pub const INSTRUCTION_BUDGET: u32 = 64;
+pub const MAX_PROGRAM_LEN: usize = 256;
+
+//Shown in the editor for a freshly placed controller
+const DEFAULT_PROGRAM: &str = "\
+# Faces are front back left right top bottom, the front faces whoever placed it
+# Registers are r0 to r7, the program starts over every tick once it runs off the end
+# Inverts the back signal onto the front
+read r0 back
+not r0
+write front r0
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operand {
+    Register(u8),
+    Value(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MathOp {
+    Set,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+}
+
+// Jump targets are instruction indices, labels only exist in the source
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Instruction {
+    //register = register op operand
+    Math(MathOp, u8, Operand),
+    //1 if the register is 0, otherwise 0
+    Not(u8),
+    Jump(u16),
+    JumpZero(u8, u16),
+    JumpNotZero(u8, u16),
+    JumpLess(u8, Operand, u16),
+    //Signal coming into a face, 0 to 15
+    Read(u8, Direction),
+    //Powers the face if the operand isn't 0
+    Write(Direction, Operand),
+    //Ends the tick early
+    Wait,
+}
+
+// A programmable block, see DEFAULT_PROGRAM for the language
+// Everything is saved with the chunk so a program carries on where it was after a reload
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Microcontroller {
+    source: String,
+    program: Vec<Instruction>,
+    pc: u16,
+    registers: [i32; REGISTERS],
+    //Why the program stopped, it stays stopped until a new program is uploaded
+    fault: Option<String>,
+}
+
+impl Default for Microcontroller {
+    fn default() -> Self {
+        Microcontroller::new(DEFAULT_PROGRAM.to_string(), assemble(DEFAULT_PROGRAM).unwrap())
+    }
+}
+
+impl Machine for Microcontroller {
+    const NAME: &'static str = "microcontroller";
+
+    fn logic_tick(&mut self, context: &mut LogicContext) {
+        if let Err(fault) = self.run(context) {
+            self.fault = Some(fault);
+        }
+    }
+}
+
+impl Microcontroller {
+    fn new(source: String, program: Vec<Instruction>) -> Self {
+        Microcontroller {
+            source,
+            program,
+            pc: 0,
+            registers: [0; REGISTERS],
+            fault: None,
+        }
+    }
+
+    pub fn info(&self) -> ProgramInfo {
+        ProgramInfo {
+            source: self.source.clone(),
+            error: self.fault.clone(),
+        }
+    }
+
+    //A program that doesn't assemble leaves the running one alone
+    pub fn upload(&mut self, source: &str) -> ProgramInfo {
+        let program = if source.len() > MAX_PROGRAM_BYTES {
+            Err(format!("programs can be at most {} bytes", MAX_PROGRAM_BYTES))
+        } else {
+            assemble(source)
+        };
+        match program {
+            Ok(program) => {
+                *self = Microcontroller::new(source.to_string(), program);
+                self.info()
+            }
+            Err(err) => ProgramInfo {
+                source: source.to_string(),
+                error: Some(format!("Not uploaded, {}", err)),
+            },
+        }
+    }
+
+    fn run(&mut self, context: &mut LogicContext) -> Result<(), String> {
+        if self.fault.is_some() {
+            return Ok(());
+        }
+        let facing = context.facing();
+        for _ in 0..INSTRUCTION_BUDGET {
+            let instruction = match self.program.get(self.pc as usize) {
+                Some(instruction) => *instruction,
+                None => {
+                    self.pc = 0;
+                    return Ok(());
+                }
+            };
+            let line = self.pc + 1;
+            self.pc += 1;
+            match instruction {
+                Instruction::Math(op, register, operand) => {
+                    let value = self.value(operand)?;
+                    let current = self.register(register)?;
+                    let result = match op {
+                        MathOp::Set => value,
+                        MathOp::Add => current.wrapping_add(value),
+                        MathOp::Sub => current.wrapping_sub(value),
+                        MathOp::Mul => current.wrapping_mul(value),
+                        MathOp::Div | MathOp::Mod if value == 0 => {
+                            return Err(format!("instruction {} divided by zero", line))
+                        }
+                        MathOp::Div => current.wrapping_div(value),
+                        MathOp::Mod => current.wrapping_rem(value),
+                        MathOp::And => current & value,
+                        MathOp::Or => current | value,
+                        MathOp::Xor => current ^ value,
+                    };
+                    self.set_register(register, result)?;
+                }
+                Instruction::Not(register) => {
+                    let value = (self.register(register)? == 0) as i32;
+                    self.set_register(register, value)?;
+                }
+                Instruction::Jump(target) => self.pc = target,
+                Instruction::JumpZero(register, target) => {
+                    if self.register(register)? == 0 {
+                        self.pc = target;
+                    }
+                }
+                Instruction::JumpNotZero(register, target) => {
+                    if self.register(register)? != 0 {
+                        self.pc = target;
+                    }
+                }
+                Instruction::JumpLess(register, operand, target) => {
+                    if self.register(register)? < self.value(operand)? {
+                        self.pc = target;
+                    }
+                }
+                Instruction::Read(register, face) => {
+                    let input = context.input(face.rotated_to(facing));
+                    self.set_register(register, input as i32)?;
+                }
+                Instruction::Write(face, operand) => {
+                    let on = self.value(operand)? != 0;
+                    context.set_output(face.rotated_to(facing), on);
+                }
+                Instruction::Wait => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    //Programs are checked when they are assembled but saves could still hold anything
+    fn register(&self, register: u8) -> Result<i32, String> {
+        self.registers
+            .get(register as usize)
+            .copied()
+            .ok_or_else(|| format!("r{} doesn't exist", register))
+    }
+
+    fn set_register(&mut self, register: u8, value: i32) -> Result<(), String> {
+        let slot = self
+            .registers
+            .get_mut(register as usize)
+            .ok_or_else(|| format!("r{} doesn't exist", register))?;
+        *slot = value;
+        Ok(())
+    }
+
+    fn value(&self, operand: Operand) -> Result<i32, String> {
+        match operand {
+            Operand::Register(register) => self.register(register),
+            Operand::Value(value) => Ok(value),
+        }
+    }
+}
+
+// One instruction per line, `label:` can start a line and # starts a comment
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, String> {
+    let mut labels = HashMap::default();
+    let mut lines = Vec::default();
+    for (number, line) in source.lines().enumerate() {
+        let mut line = line.split('#').next().unwrap().trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || label.contains(char::is_whitespace) {
+                return Err(format!("line {}: bad label {:?}", number + 1, label));
+            }
+            if labels.insert(label.to_string(), lines.len() as u16).is_some() {
+                return Err(format!("line {}: label {} is defined twice", number + 1, label));
+            }
+            line = rest.trim();
+        }
+        if !line.is_empty() {
+            lines.push((number + 1, line));
+        }
+    }
+    if lines.len() > MAX_PROGRAM_LEN {
+        return Err(format!("programs can be at most {} instructions", MAX_PROGRAM_LEN));
+    }
+
+    lines
+        .into_iter()
+        .map(|(number, line)| parse_instruction(line, &labels).map_err(|err| format!("line {}: {}", number, err)))
+        .collect()
+}
+
+fn parse_instruction(line: &str, labels: &HashMap<String, u16>) -> Result<Instruction, String> {
+    let words = line.split_whitespace().collect::<Vec<&str>>();
+    let (name, args) = words.split_first().unwrap();
+    let expect = |count: usize| {
+        if args.len() == count {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} arguments", name, count))
+        }
+    };
+    let label = |word: &str| {
+        labels
+            .get(word)
+            .copied()
+            .ok_or_else(|| format!("unknown label {}", word))
+    };
+    let math = |op: MathOp| -> Result<Instruction, String> {
+        expect(2)?;
+        Ok(Instruction::Math(op, parse_register(args[0])?, parse_operand(args[1])?))
+    };
+    match *name {
+        "set" => math(MathOp::Set),
+        "add" => math(MathOp::Add),
+        "sub" => math(MathOp::Sub),
+        "mul" => math(MathOp::Mul),
+        "div" => math(MathOp::Div),
+        "mod" => math(MathOp::Mod),
+        "and" => math(MathOp::And),
+        "or" => math(MathOp::Or),
+        "xor" => math(MathOp::Xor),
+        "not" => {
+            expect(1)?;
+            Ok(Instruction::Not(parse_register(args[0])?))
+        }
+        "jmp" => {
+            expect(1)?;
+            Ok(Instruction::Jump(label(args[0])?))
+        }
+        "jz" => {
+            expect(2)?;
+            Ok(Instruction::JumpZero(parse_register(args[0])?, label(args[1])?))
+        }
+        "jnz" => {
+            expect(2)?;
+            Ok(Instruction::JumpNotZero(parse_register(args[0])?, label(args[1])?))
+        }
+        "jlt" => {
+            expect(3)?;
+            Ok(Instruction::JumpLess(
+                parse_register(args[0])?,
+                parse_operand(args[1])?,
+                label(args[2])?,
+            ))
+        }
+        "read" => {
+            expect(2)?;
+            Ok(Instruction::Read(parse_register(args[0])?, parse_face(args[1])?))
+        }
+        "write" => {
+            expect(2)?;
+            Ok(Instruction::Write(parse_face(args[0])?, parse_operand(args[1])?))
+        }
+        "wait" => {
+            expect(0)?;
+            Ok(Instruction::Wait)
+        }
+        _ => Err(format!("unknown instruction {}", name)),
+    }
+}
+
+fn parse_register(word: &str) -> Result<u8, String> {
+    word.strip_prefix('r')
+        .and_then(|index| index.parse::<u8>().ok())
+        .filter(|index| (*index as usize) < REGISTERS)
+        .ok_or_else(|| format!("{} isn't a register, use r0 to r{}", word, REGISTERS - 1))
+}
+
+fn parse_operand(word: &str) -> Result<Operand, String> {
+    if word.starts_with('r') {
+        return Ok(Operand::Register(parse_register(word)?));
+    }
+    word.parse()
+        .map(Operand::Value)
+        .map_err(|_| format!("{} isn't a number or register", word))
+}
+
+fn parse_face(word: &str) -> Result<Direction, String> {
+    match word {
+        "front" => Ok(Direction::Front),
+        "back" => Ok(Direction::Back),
+        "left" => Ok(Direction::Left),
+        "right" => Ok(Direction::Right),
+        "top" => Ok(Direction::Top),
+        "bottom" => Ok(Direction::Bottom),
+        _ => Err(format!("{} isn't a face", word)),
+    }
+}
+
+pub fn server_edit_programs(
+    loaded_chunks: Res<ServerLoadedChunks>,
+    comps: Query<&ChunkComp>,
+    mut machines: Query<&mut MachineComp>,
+    messages: Res<CurrentServerMessages>,
+    mut server: ResMut<RenetServer>,
+) {
+    for (id, message) in messages.iter() {
+        let (pos, upload) = match message {
+            ClientMessage::OpenProgram(pos) => (*pos, None),
+            ClientMessage::UploadProgram(pos, source) => (*pos, Some(source)),
+            _ => continue,
+        };
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos);
+        let machine = loaded_chunks
+            .ent_map
+            .get(&chunk_pos)
+            .and_then(|chunk| comps.get(*chunk).ok())
+            .and_then(|chunk| chunk.associated_entity(offset));
+        let mut machine = match machine.and_then(|machine| machines.get_mut(machine).ok()) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Client {} tried to edit the program of {} which has no machine",
+                    id, pos
+                );
+                continue;
+            }
+        };
+        let controller = match machine.get_mut::<Microcontroller>() {
+            Some(controller) => controller,
+            None => {
+                warn!(
+                    "Client {} tried to edit the program of {} which isn't a microcontroller",
+                    id, pos
+                );
+                continue;
+            }
+        };
+        let info = match upload {
+            Some(source) => controller.upload(source),
+            None => controller.info(),
+        };
+        let _ = ServerBlockMessage::Program(pos, info).send(&mut server, *id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+
+    fn controller(source: &str) -> Microcontroller {
+        Microcontroller::new(source.to_string(), assemble(source).unwrap())
+    }
+
+    //Runs the controller alone in an empty chunk
+    fn tick(controller: &mut Microcontroller, ticks: usize) {
+        let registry = BlockRegistry::default();
+        let mut world = LinkedChunks::default();
+        world.add_chunk(Arc::new(RwLock::new(Chunk::default())));
+        let mut queue = LogicQueue::default();
+        let mut changes = Vec::default();
+        for _ in 0..ticks {
+            controller.logic_tick(&mut LogicContext::new(
+                &mut world,
+                &registry,
+                &mut queue,
+                &mut changes,
+                IVec3::ZERO,
+            ));
+        }
+    }
+
+    #[test]
+    fn assemble_rejects_bad_programs() {
+        assert!(assemble("frob r0").unwrap_err().contains("unknown instruction frob"));
+        assert!(assemble("jmp nowhere").unwrap_err().contains("unknown label nowhere"));
+        assert!(assemble("two words: wait").unwrap_err().contains("bad label"));
+        assert!(assemble("top:\ntop: wait").unwrap_err().contains("defined twice"));
+        assert!(assemble("set r8 1").unwrap_err().contains("isn't a register"));
+        assert!(assemble(&"wait\n".repeat(MAX_PROGRAM_LEN)).is_ok());
+        assert!(assemble(&"wait\n".repeat(MAX_PROGRAM_LEN + 1))
+            .unwrap_err()
+            .contains("at most"));
+    }
+
+    #[test]
+    fn program_over_budget_resumes_next_tick() {
+        let total = INSTRUCTION_BUDGET as i32 + 10;
+        let mut controller = controller(&format!("{}wait", "add r0 1\n".repeat(total as usize)));
+        tick(&mut controller, 1);
+        assert_eq!(controller.registers[0], INSTRUCTION_BUDGET as i32);
+        tick(&mut controller, 1);
+        assert_eq!(controller.registers[0], total);
+        assert_eq!(controller.fault, None);
+    }
+
+    #[test]
+    fn loop_that_never_waits_still_ends_the_tick() {
+        let mut controller = controller("top: add r0 1\njmp top");
+        tick(&mut controller, 2);
+        assert_eq!(controller.registers[0], INSTRUCTION_BUDGET as i32);
+    }
+
+    #[test]
+    fn wait_ends_the_tick_early() {
+        let mut controller = controller("add r0 1\nwait\nadd r0 10");
+        tick(&mut controller, 1);
+        assert_eq!(controller.registers[0], 1);
+        tick(&mut controller, 1);
+        assert_eq!(controller.registers[0], 11);
+        //Runs off the end and starts over on the next tick
+        tick(&mut controller, 1);
+        assert_eq!(controller.registers[0], 12);
+    }
+
+    #[test]
+    fn dividing_by_zero_faults_and_stops() {
+        for source in ["set r0 7\ndiv r0 0\nadd r1 1", "set r0 7\nmod r0 r2\nadd r1 1"] {
+            let mut controller = controller(source);
+            tick(&mut controller, 3);
+            assert_eq!(controller.fault.as_deref(), Some("instruction 2 divided by zero"));
+            assert_eq!(controller.registers[..2], [7, 0]);
+            assert!(controller.info().error.is_some());
+        }
+    }
+}
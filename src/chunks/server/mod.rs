@@ -1 +1,8 @@
+pub mod falling_blocks;
+pub mod machine;
+pub mod microcontroller;
+pub mod region;
+pub mod save_format;
 pub mod server_chunks;
+pub mod server_logic;
+pub mod server_rules;
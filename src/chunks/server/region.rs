@@ -0,0 +1,374 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::prelude::*;
+
+// Region files group REGION_SIZE^3 chunks into a single file so worlds don't turn into thousands of tiny files
+// Layout: a header of one (sector, length) entry per chunk followed by chunk data aligned to sectors
+// 16 instead of 32 per axis keeps the header at 32KB, chunks are 3d so 32^3 entries would be 256KB per file
+pub const REGION_SIZE: i32 = 16;
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const SECTOR_SIZE: u64 = 4096;
+const ENTRY_SIZE: u64 = 8;
+const HEADER_SECTORS: u32 = (REGION_VOLUME as u64 * ENTRY_SIZE / SECTOR_SIZE) as u32;
+
+#[derive(Clone, Copy, Default)]
+struct RegionEntry {
+    sector: u32,
+    length: u32,
+}
+
+impl RegionEntry {
+    fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn sector_count(&self) -> u32 {
+        ((self.length as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32
+    }
+
+    fn sectors(&self) -> std::ops::Range<usize> {
+        self.sector as usize..(self.sector + self.sector_count()) as usize
+    }
+}
+
+pub struct RegionFile {
+    file: File,
+    entries: Vec<RegionEntry>,
+    used_sectors: Vec<bool>,
+}
+
+impl RegionFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let header_length = HEADER_SECTORS as u64 * SECTOR_SIZE;
+        let mut header = vec![0; header_length as usize];
+
+        let file_length = file.metadata()?.len();
+        if file_length == 0 {
+            file.write_all(&header)?;
+        } else if file_length < header_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("region file {:?} has a truncated header", path),
+            ));
+        } else {
+            file.read_exact(&mut header)?;
+        }
+
+        let entries = header
+            .chunks_exact(ENTRY_SIZE as usize)
+            .map(|bytes| RegionEntry {
+                sector: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                length: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            })
+            .collect::<Vec<RegionEntry>>();
+
+        let mut used_sectors = vec![true; HEADER_SECTORS as usize];
+        for entry in entries.iter().filter(|entry| !entry.is_empty()) {
+            if entry.sector < HEADER_SECTORS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("region file {:?} has a chunk inside the header", path),
+                ));
+            }
+            let sectors = entry.sectors();
+            if used_sectors.len() < sectors.end {
+                used_sectors.resize(sectors.end, false);
+            }
+            used_sectors[sectors].iter_mut().for_each(|used| *used = true);
+        }
+
+        Ok(RegionFile {
+            file,
+            entries,
+            used_sectors,
+        })
+    }
+
+    pub fn read(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let entry = self.entries[index];
+        if entry.is_empty() {
+            return Ok(None);
+        }
+        let mut data = vec![0; entry.length as usize];
+        self.file.seek(SeekFrom::Start(entry.sector as u64 * SECTOR_SIZE))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    pub fn write(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        let old_entry = self.entries[index];
+        let mut new_entry = RegionEntry {
+            sector: 0,
+            length: data.len() as u32,
+        };
+
+        //The old sectors stay allocated until the header points at the new data so a crash mid write
+        //leaves the previous version of the chunk readable
+        if !new_entry.is_empty() {
+            new_entry.sector = self.allocate(new_entry.sector_count());
+            self.file.seek(SeekFrom::Start(new_entry.sector as u64 * SECTOR_SIZE))?;
+            self.file.write_all(data)?;
+            //Pad out the last sector so the file length always stays sector aligned
+            let padding = new_entry.sector_count() as u64 * SECTOR_SIZE - data.len() as u64;
+            self.file.write_all(&vec![0; padding as usize])?;
+        }
+
+        self.file.seek(SeekFrom::Start(index as u64 * ENTRY_SIZE))?;
+        self.file.write_all(&new_entry.sector.to_le_bytes())?;
+        self.file.write_all(&new_entry.length.to_le_bytes())?;
+        self.entries[index] = new_entry;
+
+        if !old_entry.is_empty() {
+            self.used_sectors[old_entry.sectors()]
+                .iter_mut()
+                .for_each(|used| *used = false);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    //First fit search for a run of free sectors, grows the file if there is no gap big enough
+    fn allocate(&mut self, count: u32) -> u32 {
+        let count = count as usize;
+        let mut run_start = HEADER_SECTORS as usize;
+        for sector in HEADER_SECTORS as usize..self.used_sectors.len() {
+            if self.used_sectors[sector] {
+                run_start = sector + 1;
+            } else if sector + 1 - run_start == count {
+                break;
+            }
+        }
+        let run_end = run_start + count;
+        if self.used_sectors.len() < run_end {
+            self.used_sectors.resize(run_end, false);
+        }
+        self.used_sectors[run_start..run_end]
+            .iter_mut()
+            .for_each(|used| *used = true);
+        run_start as u32
+    }
+}
+
+// All the open region files for a world, chunks are addressed by chunk position
+pub struct RegionStorage {
+    directory: PathBuf,
+    regions: HashMap<IVec3, RegionFile>,
+}
+
+impl Default for RegionStorage {
+    fn default() -> Self {
+        RegionStorage::new("saves")
+    }
+}
+
+impl RegionStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        RegionStorage {
+            directory: directory.into(),
+            regions: HashMap::default(),
+        }
+    }
+
+    // Region pos and index of the chunk inside the region
+    pub fn chunk_to_region(chunk_pos: IVec3) -> (IVec3, usize) {
+        let region_pos = IVec3::new(
+            chunk_pos.x.div_euclid(REGION_SIZE),
+            chunk_pos.y.div_euclid(REGION_SIZE),
+            chunk_pos.z.div_euclid(REGION_SIZE),
+        );
+        let local = chunk_pos - region_pos * REGION_SIZE;
+        let index = ((local.x * REGION_SIZE + local.y) * REGION_SIZE + local.z) as usize;
+        (region_pos, index)
+    }
+
+    pub fn load_chunk_bytes(&mut self, chunk_pos: IVec3) -> io::Result<Option<Vec<u8>>> {
+        let (region_pos, index) = Self::chunk_to_region(chunk_pos);
+        //Don't create region files just by looking for chunks in them
+        if !self.regions.contains_key(&region_pos) && !self.region_path(region_pos).exists() {
+            return Ok(None);
+        }
+        self.region(region_pos)?.read(index)
+    }
+
+    pub fn save_chunk_bytes(&mut self, chunk_pos: IVec3, bytes: &[u8]) -> io::Result<()> {
+        let (region_pos, index) = Self::chunk_to_region(chunk_pos);
+        self.region(region_pos)?.write(index, bytes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for region in self.regions.values_mut() {
+            region.flush()?;
+        }
+        Ok(())
+    }
+
+    // Converter for the old one file per chunk layout, imported files are moved into legacy_chunks
+    // so running it again doesn't overwrite newer region data
+    pub fn import_chunk_files(&mut self, legacy_directory: &Path) -> io::Result<usize> {
+        let imported_directory = legacy_directory.join("legacy_chunks");
+        let mut imported = 0;
+        for entry in fs::read_dir(legacy_directory)? {
+            let path = entry?.path();
+            if let Some(chunk_pos) = Self::parse_chunk_filename(&path) {
+                let bytes = fs::read(&path)?;
+                self.save_chunk_bytes(chunk_pos, &bytes)?;
+                fs::create_dir_all(&imported_directory)?;
+                fs::rename(&path, imported_directory.join(path.file_name().unwrap()))?;
+                imported += 1;
+            }
+        }
+        self.flush()?;
+        Ok(imported)
+    }
+
+    fn parse_chunk_filename(path: &Path) -> Option<IVec3> {
+        if path.extension()? != "chunk" {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?.strip_prefix("chunk_")?;
+        let coords = stem
+            .split('_')
+            .map(|coord| coord.parse::<i32>().ok())
+            .collect::<Option<Vec<i32>>>()?;
+        match coords[..] {
+            [x, y, z] => Some(IVec3::new(x, y, z)),
+            _ => None,
+        }
+    }
+
+    fn region_path(&self, region_pos: IVec3) -> PathBuf {
+        self.directory.join(format!(
+            "region_{}_{}_{}.region",
+            region_pos.x, region_pos.y, region_pos.z
+        ))
+    }
+
+    fn region(&mut self, region_pos: IVec3) -> io::Result<&mut RegionFile> {
+        if !self.regions.contains_key(&region_pos) {
+            fs::create_dir_all(&self.directory)?;
+            let region = RegionFile::open(&self.region_path(region_pos))?;
+            self.regions.insert(region_pos, region);
+        }
+        Ok(self.regions.get_mut(&region_pos).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A fresh directory per test so tests running in parallel don't share region files
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("region_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn bytes(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|index| (index as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_map_to_regions_below_zero() {
+        assert_eq!(RegionStorage::chunk_to_region(IVec3::ZERO), (IVec3::ZERO, 0));
+        assert_eq!(
+            RegionStorage::chunk_to_region(IVec3::new(-1, 0, 0)),
+            (IVec3::new(-1, 0, 0), (15 * 16 * 16) as usize)
+        );
+        assert_eq!(
+            RegionStorage::chunk_to_region(IVec3::new(17, -17, 2)),
+            (IVec3::new(1, -2, 0), ((16 + 15) * 16 + 2) as usize)
+        );
+    }
+
+    #[test]
+    fn chunks_read_back_after_growing_and_reopening() {
+        let directory = test_directory("grow");
+        let mut storage = RegionStorage::new(&directory);
+        let (first, second, third) = (IVec3::new(0, 0, 0), IVec3::new(0, 0, 1), IVec3::new(3, 2, 1));
+        assert!(storage.load_chunk_bytes(first).unwrap().is_none());
+        //Looking for a chunk doesn't create its region
+        assert!(!directory.exists());
+
+        storage.save_chunk_bytes(first, &bytes(100, 1)).unwrap();
+        storage.save_chunk_bytes(second, &bytes(100, 2)).unwrap();
+        //Too big for its old sector with the second chunk right after it, so it has to move
+        storage.save_chunk_bytes(first, &bytes(5000, 3)).unwrap();
+        let region = storage.regions.get(&IVec3::ZERO).unwrap();
+        assert_eq!(region.entries[0].sector, HEADER_SECTORS + 2);
+        assert_eq!(region.entries[0].sector_count(), 2);
+
+        //The sector the first chunk moved out of is free again
+        storage.save_chunk_bytes(third, &bytes(10, 4)).unwrap();
+        let (_, third_index) = RegionStorage::chunk_to_region(third);
+        assert_eq!(
+            storage.regions[&IVec3::ZERO].entries[third_index].sector,
+            HEADER_SECTORS
+        );
+
+        assert_eq!(storage.load_chunk_bytes(first).unwrap(), Some(bytes(5000, 3)));
+        assert_eq!(storage.load_chunk_bytes(second).unwrap(), Some(bytes(100, 2)));
+        storage.flush().unwrap();
+        drop(storage);
+
+        let mut reopened = RegionStorage::new(&directory);
+        assert_eq!(reopened.load_chunk_bytes(first).unwrap(), Some(bytes(5000, 3)));
+        assert_eq!(reopened.load_chunk_bytes(second).unwrap(), Some(bytes(100, 2)));
+        assert_eq!(reopened.load_chunk_bytes(third).unwrap(), Some(bytes(10, 4)));
+        assert!(reopened.load_chunk_bytes(IVec3::new(0, 1, 0)).unwrap().is_none());
+        //A rewrite never lands on the chunk's own sectors, the next chunk written fills the gap it left instead
+        reopened.save_chunk_bytes(second, &bytes(4096, 5)).unwrap();
+        assert_eq!(reopened.regions[&IVec3::ZERO].entries[1].sector, HEADER_SECTORS + 4);
+        reopened.save_chunk_bytes(IVec3::new(0, 1, 0), &bytes(10, 6)).unwrap();
+        assert_eq!(reopened.regions[&IVec3::ZERO].entries[16].sector, HEADER_SECTORS + 1);
+        assert_eq!(reopened.load_chunk_bytes(second).unwrap(), Some(bytes(4096, 5)));
+        assert_eq!(reopened.load_chunk_bytes(first).unwrap(), Some(bytes(5000, 3)));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn chunk_files_are_imported_once() {
+        let directory = test_directory("import");
+        let legacy = directory.join("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("chunk_1_-2_3.chunk"), bytes(300, 6)).unwrap();
+        fs::write(legacy.join("chunk_1_2.chunk"), bytes(10, 7)).unwrap();
+        fs::write(legacy.join("notes.txt"), bytes(10, 8)).unwrap();
+
+        let mut storage = RegionStorage::new(directory.join("regions"));
+        assert_eq!(storage.import_chunk_files(&legacy).unwrap(), 1);
+        assert_eq!(
+            storage.load_chunk_bytes(IVec3::new(1, -2, 3)).unwrap(),
+            Some(bytes(300, 6))
+        );
+        assert!(legacy.join("legacy_chunks").join("chunk_1_-2_3.chunk").exists());
+        assert!(!legacy.join("chunk_1_-2_3.chunk").exists());
+        assert!(legacy.join("chunk_1_2.chunk").exists());
+
+        //Newer data isn't overwritten by importing again
+        storage.save_chunk_bytes(IVec3::new(1, -2, 3), &bytes(20, 9)).unwrap();
+        assert_eq!(storage.import_chunk_files(&legacy).unwrap(), 0);
+        assert_eq!(
+            storage.load_chunk_bytes(IVec3::new(1, -2, 3)).unwrap(),
+            Some(bytes(20, 9))
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,458 @@
+use std::{fmt, io::Cursor};
+
+use lz4::block::{compress, decompress, CompressionMode};
+
+use crate::prelude::*;
+use crate::server::machine::*;
+
+// Save file layout:
+// [magic: 4 bytes][format version: u32 le][bincode block name table][lz4 compressed bincode payload]
+// Blocks are stored as indices into the name table so registry ids, which depend on the order of the block
+// definitions, never end up in a save
+pub const SAVE_MAGIC: [u8; 4] = *b"LVCK";
+pub const SAVE_VERSION: u32 = 6;
+
+//Files written before the header existed are treated as version 0
+//They encoded Block by enum index so this is the variant order at the time, it must never change
+const LEGACY_BLOCK_NAMES: [&str; 6] = ["Air", "Grass", "Dirt", "Metal", "Glass", "Machine"];
+
+#[derive(Debug)]
+pub enum SaveError {
+    Corrupt(String),
+    UnsupportedVersion(u32),
+    MissingMigration(u32),
+    //A migration from the first version that produced the second instead of the next one
+    BadMigration(u32, u32),
+    UnknownBlock(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Corrupt(reason) => write!(f, "save data is corrupt: {}", reason),
+            SaveError::UnsupportedVersion(version) => write!(
+                f,
+                "save format version {} is newer than this build supports ({})",
+                version, SAVE_VERSION
+            ),
+            SaveError::MissingMigration(version) => {
+                write!(f, "no migration registered to upgrade save format version {}", version)
+            }
+            SaveError::BadMigration(from, to) => write!(
+                f,
+                "the migration from save format version {} produced version {} instead of {}",
+                from,
+                to,
+                from + 1
+            ),
+            SaveError::UnknownBlock(name) => write!(f, "save references unknown block {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+//Decompressed save contents at some format version, migrations turn one of these into the next version
+pub struct SaveData {
+    pub version: u32,
+    pub block_names: Vec<String>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkPayload {
+    pos: IVec3,
+    blocks: BlockStorage<(u16, BlockState)>,
+    machines: SerializedMachines,
+    ticks: TickQueue,
+}
+
+//Payload of version 5, before scheduled ticks were saved with their chunk
+#[derive(Serialize, Deserialize)]
+struct TicklessChunkPayload {
+    pos: IVec3,
+    blocks: BlockStorage<(u16, BlockState)>,
+    machines: SerializedMachines,
+}
+
+//Payload of versions 1 and 2, before blocks had state
+#[derive(Serialize, Deserialize)]
+struct StatelessChunkPayload {
+    pos: IVec3,
+    blocks: BlockStorage<u16>,
+}
+
+//Payload of version 4, machines were hardcoded furnaces
+#[derive(Serialize, Deserialize)]
+struct FurnaceChunkPayload {
+    pos: IVec3,
+    blocks: BlockStorage<(u16, BlockState)>,
+    furnaces: Vec<(u32, f32, IVec3)>,
+}
+
+//Payload of version 3, before machines were saved with their chunk
+#[derive(Serialize, Deserialize)]
+struct MachinelessChunkPayload {
+    pos: IVec3,
+    blocks: BlockStorage<(u16, BlockState)>,
+}
+
+pub type Migration = fn(SaveData) -> Result<SaveData, SaveError>;
+
+// Keyed by the version a migration upgrades from, each migration must produce exactly the next version
+pub struct SaveMigrations {
+    migrations: HashMap<u32, Migration>,
+}
+
+impl Default for SaveMigrations {
+    fn default() -> Self {
+        let mut migrations = SaveMigrations {
+            migrations: HashMap::default(),
+        };
+        migrations.register(0, migrate_legacy_chunk);
+        migrations.register(1, migrate_block_enum_names);
+        migrations.register(2, migrate_add_block_states);
+        migrations.register(3, migrate_add_machines);
+        migrations.register(4, migrate_furnaces_to_machines);
+        migrations.register(5, migrate_add_scheduled_ticks);
+        migrations
+    }
+}
+
+impl SaveMigrations {
+    pub fn register(&mut self, from_version: u32, migration: Migration) {
+        if self.migrations.insert(from_version, migration).is_some() {
+            warn!("Replacing save migration from version {}", from_version);
+        }
+    }
+
+    pub fn upgrade(&self, mut data: SaveData) -> Result<SaveData, SaveError> {
+        if data.version > SAVE_VERSION {
+            return Err(SaveError::UnsupportedVersion(data.version));
+        }
+        while data.version < SAVE_VERSION {
+            let from_version = data.version;
+            let migration = self
+                .migrations
+                .get(&from_version)
+                .ok_or(SaveError::MissingMigration(from_version))?;
+            data = migration(data)?;
+            if data.version != from_version + 1 {
+                return Err(SaveError::BadMigration(from_version, data.version));
+            }
+        }
+        Ok(data)
+    }
+}
+
+pub fn save_chunk(chunk: &Chunk, machines: SerializedMachines, registry: &BlockRegistry) -> Vec<u8> {
+    let block_names = registry
+        .blocks()
+        .map(|block| registry.name(block).to_string())
+        .collect::<Vec<String>>();
+    let mut storage = chunk.storage().clone();
+    storage.compact();
+    let payload = ChunkPayload {
+        pos: chunk.pos,
+        blocks: storage.map_palette(|(block, state)| (block.id(), state)),
+        machines,
+        ticks: chunk.ticks().clone(),
+    };
+
+    let mut bytes = SAVE_MAGIC.to_vec();
+    bytes.extend_from_slice(&SAVE_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(&block_names).unwrap());
+    let payload = bincode::serialize(&payload).unwrap();
+    bytes.extend(compress(&payload, Some(CompressionMode::HIGHCOMPRESSION(12)), true).unwrap());
+    bytes
+}
+
+//Machines have to be spawned by the caller since they are entities
+pub fn load_chunk(
+    bytes: &[u8],
+    migrations: &SaveMigrations,
+    registry: &BlockRegistry,
+) -> Result<(Chunk, SerializedMachines), SaveError> {
+    let data = migrations.upgrade(read_save_data(bytes)?)?;
+
+    let payload: ChunkPayload =
+        bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let blocks = payload.blocks.try_map_palette(|(id, state)| {
+        let name = data
+            .block_names
+            .get(id as usize)
+            .ok_or_else(|| SaveError::Corrupt(format!("block id {} is not in the name table", id)))?;
+        let block = registry.id(name).ok_or_else(|| SaveError::UnknownBlock(name.clone()))?;
+        Ok((block, state))
+    })?;
+    Ok((
+        Chunk::from_storage(payload.pos, blocks, payload.ticks),
+        payload.machines,
+    ))
+}
+
+fn read_save_data(bytes: &[u8]) -> Result<SaveData, SaveError> {
+    if bytes.len() < 8 || bytes[0..4] != SAVE_MAGIC {
+        return Ok(SaveData {
+            version: 0,
+            block_names: LEGACY_BLOCK_NAMES.iter().map(|name| name.to_string()).collect(),
+            payload: decompress_payload(bytes)?,
+        });
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let mut reader = Cursor::new(&bytes[8..]);
+    let block_names: Vec<String> =
+        bincode::deserialize_from(&mut reader).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let payload_start = 8 + reader.position() as usize;
+    Ok(SaveData {
+        version,
+        block_names,
+        payload: decompress_payload(&bytes[payload_start..])?,
+    })
+}
+
+fn decompress_payload(bytes: &[u8]) -> Result<Vec<u8>, SaveError> {
+    decompress(bytes, None).map_err(|err| SaveError::Corrupt(err.to_string()))
+}
+
+//Version 0 was a bare bincode Chunk with the blocks as a full array of enum indices
+fn migrate_legacy_chunk(data: SaveData) -> Result<SaveData, SaveError> {
+    #[derive(Deserialize)]
+    struct LegacyChunk {
+        pos: IVec3,
+        cubes: [[[u32; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE],
+        #[allow(dead_code)]
+        dirty: bool,
+    }
+    let legacy: LegacyChunk = bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+
+    let mut blocks = BlockStorage::<u16>::default();
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                blocks.set(x, y, z, legacy.cubes[x][y][z] as u16);
+            }
+        }
+    }
+    let payload = StatelessChunkPayload {
+        pos: legacy.pos,
+        blocks,
+    };
+    Ok(SaveData {
+        version: 1,
+        block_names: data.block_names,
+        payload: bincode::serialize(&payload).unwrap(),
+    })
+}
+
+//Version 1 named blocks after the old Block enum variants, the registry uses lowercase names
+fn migrate_block_enum_names(data: SaveData) -> Result<SaveData, SaveError> {
+    Ok(SaveData {
+        version: 2,
+        block_names: data.block_names.iter().map(|name| name.to_lowercase()).collect(),
+        payload: data.payload,
+    })
+}
+
+//Every block from before version 3 gets the default state
+fn migrate_add_block_states(data: SaveData) -> Result<SaveData, SaveError> {
+    let old: StatelessChunkPayload =
+        bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let payload = MachinelessChunkPayload {
+        pos: old.pos,
+        blocks: old.blocks.map_palette(|id| (id, BlockState::default())),
+    };
+    Ok(SaveData {
+        version: 3,
+        block_names: data.block_names,
+        payload: bincode::serialize(&payload).unwrap(),
+    })
+}
+
+//No machine data before version 4, the server spawns fresh machines for machine blocks without one
+fn migrate_add_machines(data: SaveData) -> Result<SaveData, SaveError> {
+    let old: MachinelessChunkPayload =
+        bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let payload = FurnaceChunkPayload {
+        pos: old.pos,
+        blocks: old.blocks,
+        furnaces: Vec::default(),
+    };
+    Ok(SaveData {
+        version: 4,
+        block_names: data.block_names,
+        payload: bincode::serialize(&payload).unwrap(),
+    })
+}
+
+//Version 4 stored (machine type, heat, offset) for furnaces, the only machine at the time
+//Furnaces now save themselves as just their heat
+fn migrate_furnaces_to_machines(data: SaveData) -> Result<SaveData, SaveError> {
+    let old: FurnaceChunkPayload =
+        bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let machines = old
+        .furnaces
+        .into_iter()
+        .map(|(_machine_type, heat, offset)| SerializedMachine {
+            name: Furnace::NAME.to_string(),
+            offset,
+            data: bincode::serialize(&heat).unwrap(),
+        })
+        .collect();
+    let payload = TicklessChunkPayload {
+        pos: old.pos,
+        blocks: old.blocks,
+        machines: SerializedMachines { machines },
+    };
+    Ok(SaveData {
+        version: 5,
+        block_names: data.block_names,
+        payload: bincode::serialize(&payload).unwrap(),
+    })
+}
+
+//Nothing was waiting on a tick before version 6
+fn migrate_add_scheduled_ticks(data: SaveData) -> Result<SaveData, SaveError> {
+    let old: TicklessChunkPayload =
+        bincode::deserialize(&data.payload).map_err(|err| SaveError::Corrupt(err.to_string()))?;
+    let payload = ChunkPayload {
+        pos: old.pos,
+        blocks: old.blocks,
+        machines: old.machines,
+        ticks: TickQueue::default(),
+    };
+    Ok(SaveData {
+        version: 6,
+        block_names: data.block_names,
+        payload: bincode::serialize(&payload).unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A save with a header at any version, the payload is whatever that version stored
+    fn save_bytes(version: u32, block_names: &[String], payload: &impl Serialize) -> Vec<u8> {
+        let mut bytes = SAVE_MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend(bincode::serialize(block_names).unwrap());
+        bytes.extend(compress(&bincode::serialize(payload).unwrap(), None, true).unwrap());
+        bytes
+    }
+
+    fn metal_chunk(registry: &BlockRegistry) -> Chunk {
+        let mut chunk = Chunk::default();
+        chunk.pos = IVec3::new(1, -2, 3);
+        let state = BlockState::default().with_facing(Direction::Left);
+        chunk.set_block_with_state(1, 2, 3, registry.expect("metal"), state);
+        chunk
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let registry = BlockRegistry::default();
+        let mut chunk = metal_chunk(&registry);
+        chunk.ticks_mut().schedule(IVec3::new(1, 2, 3), 5);
+        let machines = SerializedMachines {
+            machines: vec![SerializedMachine {
+                name: Furnace::NAME.to_string(),
+                offset: IVec3::new(4, 5, 6),
+                data: vec![1, 2, 3],
+            }],
+        };
+        let bytes = save_chunk(&chunk, machines, &registry);
+        let (loaded, machines) = load_chunk(&bytes, &SaveMigrations::default(), &registry).unwrap();
+        assert_eq!(loaded.pos, chunk.pos);
+        assert_eq!(loaded.block_with_state(1, 2, 3), chunk.block_with_state(1, 2, 3));
+        assert!(loaded.block(0, 0, 0).is_air());
+        assert_eq!(loaded.ticks().len(), 1);
+        assert_eq!(machines.machines.len(), 1);
+        assert_eq!(machines.machines[0].offset, IVec3::new(4, 5, 6));
+        assert_eq!(machines.machines[0].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn legacy_chunk_upgrades_through_every_version() {
+        #[derive(Serialize)]
+        struct LegacyChunk {
+            pos: IVec3,
+            cubes: [[[u32; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE],
+            dirty: bool,
+        }
+        let mut legacy = LegacyChunk {
+            pos: IVec3::new(-1, 0, 2),
+            cubes: Default::default(),
+            dirty: true,
+        };
+        //Indices into LEGACY_BLOCK_NAMES
+        legacy.cubes[1][2][3] = 3;
+        legacy.cubes[4][5][6] = 4;
+        let bytes = compress(&bincode::serialize(&legacy).unwrap(), None, true).unwrap();
+
+        let registry = BlockRegistry::default();
+        let (chunk, machines) = load_chunk(&bytes, &SaveMigrations::default(), &registry).unwrap();
+        assert_eq!(chunk.pos, legacy.pos);
+        assert_eq!(chunk.block(1, 2, 3), registry.expect("metal"));
+        assert_eq!(chunk.block(4, 5, 6), registry.expect("glass"));
+        assert_eq!(chunk.state(1, 2, 3), BlockState::default());
+        assert!(chunk.block(0, 0, 0).is_air());
+        assert!(chunk.ticks().is_empty());
+        assert!(machines.machines.is_empty());
+    }
+
+    #[test]
+    fn furnaces_become_machines() {
+        let registry = BlockRegistry::default();
+        let chunk = metal_chunk(&registry);
+        let payload = FurnaceChunkPayload {
+            pos: chunk.pos,
+            blocks: chunk.storage().map_palette(|(block, state)| (block.id(), state)),
+            furnaces: vec![(0, 42.0, IVec3::new(7, 8, 9))],
+        };
+        let bytes = save_bytes(4, &registry.names(), &payload);
+        let (loaded, machines) = load_chunk(&bytes, &SaveMigrations::default(), &registry).unwrap();
+        assert_eq!(loaded.block_with_state(1, 2, 3), chunk.block_with_state(1, 2, 3));
+        assert_eq!(machines.machines.len(), 1);
+        let furnace = &machines.machines[0];
+        assert_eq!(furnace.name, Furnace::NAME);
+        assert_eq!(furnace.offset, IVec3::new(7, 8, 9));
+        let heat: f32 = bincode::deserialize(&furnace.data).unwrap();
+        assert_eq!(heat, 42.0);
+    }
+
+    #[test]
+    fn versions_outside_the_migrations_are_errors() {
+        let data = |version| SaveData {
+            version,
+            block_names: Vec::default(),
+            payload: Vec::default(),
+        };
+        let migrations = SaveMigrations::default();
+        assert!(matches!(
+            migrations.upgrade(data(SAVE_VERSION + 1)),
+            Err(SaveError::UnsupportedVersion(version)) if version == SAVE_VERSION + 1
+        ));
+        assert!(migrations.upgrade(data(SAVE_VERSION)).is_ok());
+
+        let missing = SaveMigrations {
+            migrations: HashMap::default(),
+        };
+        assert!(matches!(
+            missing.upgrade(data(SAVE_VERSION - 1)),
+            Err(SaveError::MissingMigration(version)) if version == SAVE_VERSION - 1
+        ));
+
+        let mut skipping = SaveMigrations::default();
+        skipping.register(SAVE_VERSION - 2, |data| {
+            Ok(SaveData {
+                version: data.version + 2,
+                ..data
+            })
+        });
+        assert!(matches!(
+            skipping.upgrade(data(SAVE_VERSION - 2)),
+            Err(SaveError::BadMigration(from, to)) if from == SAVE_VERSION - 2 && to == SAVE_VERSION
+        ));
+    }
+}
@@ -5,7 +5,10 @@ use std::{
 };
 
 use crate::prelude::*;
-use bevy::app::AppExit;
+use crate::server::{
+    falling_blocks::*, machine::*, microcontroller::*, region::*, save_format::*, server_logic::*, server_rules::*,
+};
+use bevy::{app::AppExit, time::FixedTimestep, utils::HashSet};
 use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
 use noise::{NoiseFn, Perlin};
 
@@ -16,14 +19,262 @@ impl Plugin for ServerChunkPlugin {
             .add_system(server_break_blocks)
             .add_system(server_place_blocks)
             .add_system(server_save_and_quit)
-            .init_resource::<LoadedChunks>();
+            .add_system(server_track_chunk_viewers)
+            .add_system(server_evict_chunks)
+            .add_system(tick_machines)
+            .add_system(server_interact_blocks)
+            .add_system(server_edit_programs)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(FixedTimestep::step(LOGIC_TICK_SECONDS))
+                    .with_system(server_logic_tick)
+                    .with_system(server_rule_tick.after(server_logic_tick)),
+            )
+            .add_system(server_settle_falling_blocks)
+            .add_system(server_terrain_colliders)
+            .add_system(server_send_falling_blocks)
+            .add_system(server_send_block_names.before(server_send_block_updates))
+            .add_system(server_send_block_updates)
+            .add_startup_system(import_legacy_chunk_files)
+            .add_startup_system(check_rule_blocks)
+            .init_resource::<SaveMigrations>()
+            .init_resource::<RegionStorage>()
+            .init_resource::<ChunkViewers>()
+            .init_resource::<ServerLoadedChunks>()
+            .init_resource::<MachineRegistry>()
+            .init_resource::<RuleEngine>()
+            .init_resource::<LogicQueue>()
+            .init_resource::<LogicControllers>()
+            .init_resource::<PendingBlockUpdates>()
+            .register_machine::<Furnace>()
+            .register_machine::<Microcontroller>()
+            .register_rule(CellRule {
+                name: "grass_decay",
+                block: "grass",
+                schedule: RuleSchedule::Random,
+                apply: grass_decay,
+                budget: None,
+            })
+            .register_rule(CellRule {
+                name: "grass_spread",
+                block: "grass",
+                schedule: RuleSchedule::Random,
+                apply: grass_spread,
+                budget: None,
+            })
+            .register_rule(CellRule {
+                name: "water_flow",
+                block: "water",
+                schedule: RuleSchedule::Scheduled,
+                apply: flow_fluid,
+                budget: Some(FLUID_TICK_BUDGET),
+            })
+            .register_rule(CellRule {
+                name: "lava_flow",
+                block: "lava",
+                schedule: RuleSchedule::Scheduled,
+                apply: flow_fluid,
+                budget: Some(FLUID_TICK_BUDGET),
+            })
+            .register_rule(CellRule {
+                name: "sand_fall",
+                block: "sand",
+                schedule: RuleSchedule::Scheduled,
+                apply: fall_when_unsupported,
+                budget: None,
+            })
+            .register_rule(CellRule {
+                name: "gravel_fall",
+                block: "gravel",
+                schedule: RuleSchedule::Scheduled,
+                apply: fall_when_unsupported,
+                budget: None,
+            });
+    }
+}
+
+//Separate from the client's LoadedChunks so in host mode the two sides don't replace and evict each others chunks
+#[derive(Default, Deref, DerefMut)]
+pub struct ServerLoadedChunks(LoadedChunks);
+
+// Which clients have each chunk loaded, chunks nobody has needed for CHUNK_EVICTION_SECONDS get evicted
+#[derive(Default)]
+pub struct ChunkViewers {
+    viewers: HashMap<IVec3, HashSet<u64>>,
+    last_needed: HashMap<IVec3, f64>,
+}
+
+impl ChunkViewers {
+    pub fn add_viewer(&mut self, pos: IVec3, id: u64) {
+        self.viewers.entry(pos).or_default().insert(id);
+    }
+
+    pub fn remove_viewer(&mut self, pos: IVec3, id: u64) {
+        if let Some(viewers) = self.viewers.get_mut(&pos) {
+            viewers.remove(&id);
+        }
+    }
+
+    pub fn remove_client(&mut self, id: u64) {
+        for viewers in self.viewers.values_mut() {
+            viewers.remove(&id);
+        }
+    }
+
+    pub fn viewers(&self, pos: IVec3) -> impl Iterator<Item = u64> + '_ {
+        self.viewers.get(&pos).into_iter().flatten().copied()
+    }
+
+    fn should_evict(&mut self, pos: IVec3, now: f64) -> bool {
+        let last_needed = self.last_needed.entry(pos).or_insert(now);
+        if self.viewers.get(&pos).map_or(false, |viewers| !viewers.is_empty()) {
+            *last_needed = now;
+        }
+        now - *last_needed > CHUNK_EVICTION_SECONDS
+    }
+
+    fn forget(&mut self, pos: IVec3) {
+        self.viewers.remove(&pos);
+        self.last_needed.remove(&pos);
+    }
+}
+
+//Blocks the server changed on its own, like signals, sent to everyone viewing their chunk at the end of the frame
+#[derive(Default, Deref, DerefMut)]
+pub struct PendingBlockUpdates(Vec<BlockChange>);
+
+fn server_send_block_updates(
+    mut updates: ResMut<PendingBlockUpdates>,
+    viewers: Res<ChunkViewers>,
+    mut server: ResMut<RenetServer>,
+) {
+    if updates.is_empty() {
+        return;
+    }
+    let mut by_chunk: HashMap<IVec3, Vec<BlockChange>> = HashMap::default();
+    for change in updates.drain(..) {
+        let (chunk_pos, _) = Chunk::i_world_to_chunk(change.0);
+        by_chunk.entry(chunk_pos).or_default().push(change);
+    }
+    for (chunk_pos, changes) in by_chunk {
+        let update = ServerBlockMessage::BlockUpdates(changes);
+        for viewer in viewers.viewers(chunk_pos) {
+            let _ = update.send(&mut server, viewer);
+        }
+    }
+}
+
+// Block ids are indices into each side's own registry, so clients are sent the server's names to translate them with
+// Reloading only ever adds ids, so ids already sent stay right and the new names just have to arrive before the blocks
+fn server_send_block_names(
+    mut events: EventReader<ServerEvent>,
+    registry: Res<BlockRegistry>,
+    mut server: ResMut<RenetServer>,
+) {
+    let connected = events
+        .iter()
+        .filter_map(|event| match event {
+            ServerEvent::ClientConnected(id, _) => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<u64>>();
+    let clients = if registry.is_changed() {
+        server.clients_id()
+    } else {
+        connected
+    };
+    if clients.is_empty() {
+        return;
+    }
+    let message = ServerBlockMessage::BlockNames(registry.names());
+    for id in clients {
+        let _ = message.send(&mut server, id);
+    }
+}
+
+//Worlds saved before region files have one file per chunk, pull them into regions so they aren't regenerated
+fn import_legacy_chunk_files(mut regions: ResMut<RegionStorage>) {
+    let saves = Path::new("saves");
+    if !saves.exists() {
+        return;
+    }
+    match regions.import_chunk_files(saves) {
+        Ok(0) => {}
+        Ok(imported) => info!("Imported {} chunk files into region files", imported),
+        Err(err) => error!("Failed to import chunk files into region files: {}", err),
+    }
+}
+
+fn save_loaded_chunk(
+    regions: &mut RegionStorage,
+    registry: &BlockRegistry,
+    machine_query: &MachineQuery,
+    pos: IVec3,
+    chunk: &ChunkComp,
+) {
+    let machines = SerializedMachines::serialize_all_machines(chunk.associated_entities(), machine_query);
+    if let Err(err) = regions.save_chunk_bytes(pos, &save_chunk(&chunk.read_chunk(), machines, registry)) {
+        error!("Failed to save chunk {}: {}", pos, err);
+    }
+}
+
+fn server_track_chunk_viewers(
+    messages: Res<CurrentServerMessages>,
+    mut server_events: EventReader<ServerEvent>,
+    mut viewers: ResMut<ChunkViewers>,
+) {
+    for (id, message) in messages.iter() {
+        if let ClientMessage::UnloadChunk(pos) = message {
+            viewers.remove_viewer(*pos, *id);
+        }
+    }
+    for event in server_events.iter() {
+        if let ServerEvent::ClientDisconnected(id) = event {
+            viewers.remove_client(*id);
+        }
+    }
+}
+
+fn server_evict_chunks(
+    mut commands: Commands,
+    comps: Query<&ChunkComp>,
+    mut loaded_chunks: ResMut<ServerLoadedChunks>,
+    mut viewers: ResMut<ChunkViewers>,
+    mut regions: ResMut<RegionStorage>,
+    registry: Res<BlockRegistry>,
+    machine_query: MachineQuery,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let to_evict = loaded_chunks
+        .ent_map
+        .keys()
+        .copied()
+        .filter(|pos| viewers.should_evict(*pos, now))
+        .collect::<Vec<IVec3>>();
+
+    for pos in to_evict {
+        let ent = loaded_chunks.ent_map.remove(&pos).unwrap();
+        if let Ok(chunk) = comps.get(ent) {
+            info!("Evicting chunk {}", pos);
+            save_loaded_chunk(&mut regions, &registry, &machine_query, pos, chunk);
+            chunk.disconnect_neighbors();
+            for machine in chunk.associated_entities() {
+                commands.entity(machine).despawn_recursive();
+            }
+        }
+        commands.entity(ent).despawn_recursive();
+        viewers.forget(pos);
     }
 }
 
 fn server_save_and_quit(
     mut egui_context: ResMut<EguiContext>,
-    loaded_chunks: Res<LoadedChunks>,
+    loaded_chunks: Res<ServerLoadedChunks>,
     comps: Query<&ChunkComp>,
+    mut regions: ResMut<RegionStorage>,
+    registry: Res<BlockRegistry>,
+    machine_query: MachineQuery,
     _keyboard: Res<Input<KeyCode>>,
     mut exit: EventWriter<AppExit>,
     mut server: ResMut<RenetServer>,
@@ -32,11 +283,16 @@ fn server_save_and_quit(
         //if ui.button("Quit and Save").clicked() || keyboard.just_pressed(KeyCode::Escape) {
         if ui.button("Quit and Save").clicked() {
             for (pos, chunk) in loaded_chunks.ent_map.iter() {
-                let chunk = comps.get(*chunk).unwrap();
-                //Copied from elsewhere! Duplicated code
-                let filename = format!("saves/chunk_{}_{}_{}.chunk", pos.x, pos.y, pos.z);
-                let filename = Path::new(&filename);
-                fs::write(filename, chunk.read_chunk().compress()).unwrap();
+                save_loaded_chunk(
+                    &mut regions,
+                    &registry,
+                    &machine_query,
+                    *pos,
+                    comps.get(*chunk).unwrap(),
+                );
+            }
+            if let Err(err) = regions.flush() {
+                error!("Failed to flush region files: {}", err);
             }
             //XXX does this actually reach all clients?
             server.disconnect_clients();
@@ -46,18 +302,41 @@ fn server_save_and_quit(
 }
 
 fn server_break_blocks(
-    loaded_chunks: Res<LoadedChunks>,
-    comps: Query<&ChunkComp>,
+    mut commands: Commands,
+    loaded_chunks: Res<ServerLoadedChunks>,
+    mut comps: Query<&mut ChunkComp>,
+    viewers: Res<ChunkViewers>,
     messages: Res<CurrentServerMessages>,
     mut server: ResMut<RenetServer>,
+    registry: Res<BlockRegistry>,
+    machines: Res<MachineRegistry>,
+    mut logic: ResMut<LogicQueue>,
+    mut updates: ResMut<PendingBlockUpdates>,
 ) {
     for (id, message) in messages.iter() {
         if let ClientMessage::BreakBlock(pos) = message {
             let (chunk_pos, offset) = Chunk::i_world_to_chunk(*pos);
             if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
-                let chunk = comps.get(*chunk).unwrap();
-                chunk.write_block(offset, Block::Air);
-                ServerBlockMessage::Chunk(chunk.read_chunk().compress()).broadcast_except(&mut server, *id);
+                let mut chunk = comps.get_mut(*chunk).unwrap();
+                chunk.write_block(offset, Block::AIR, &registry);
+                update_block_machine(
+                    &mut commands,
+                    &mut chunk,
+                    &registry,
+                    &machines,
+                    chunk_pos,
+                    offset,
+                    Block::AIR,
+                );
+                logic.schedule(*pos);
+                let update = ServerBlockMessage::Chunk(chunk.read_chunk().compress());
+                for viewer in viewers.viewers(chunk_pos).filter(|viewer| viewer != id) {
+                    let _ = update.send(&mut server, viewer);
+                }
+                let mut world = LinkedChunks::default();
+                world.add_chunk(chunk.shared());
+                schedule_neighbor_ticks(&loaded_chunks, &comps, *pos);
+                break_off_islands(&mut commands, &mut world, &registry, &mut logic, &mut updates, *pos);
             } else {
                 warn!("Chunk not loaded on server!");
             }
@@ -66,19 +345,44 @@ fn server_break_blocks(
 }
 
 fn server_place_blocks(
-    loaded_chunks: Res<LoadedChunks>,
-    comps: Query<&ChunkComp>,
+    mut commands: Commands,
+    loaded_chunks: Res<ServerLoadedChunks>,
+    mut comps: Query<&mut ChunkComp>,
+    viewers: Res<ChunkViewers>,
     messages: Res<CurrentServerMessages>,
     mut server: ResMut<RenetServer>,
+    registry: Res<BlockRegistry>,
+    machines: Res<MachineRegistry>,
+    mut logic: ResMut<LogicQueue>,
 ) {
     for (id, message) in messages.iter() {
-        if let ClientMessage::PlaceBlock(pos, block) = message {
+        if let ClientMessage::PlaceBlock(pos, block, state) = message {
+            if !registry.contains(*block) {
+                warn!("Client {} tried to place unknown block {:?}", id, block);
+                continue;
+            }
             let (chunk_pos, offset) = Chunk::i_world_to_chunk(*pos);
             if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
-                let chunk = comps.get(*chunk).unwrap();
+                let mut chunk = comps.get_mut(*chunk).unwrap();
                 info!("Writing to {}, {} {}", pos, chunk_pos, offset);
-                chunk.write_block(offset, *block);
-                ServerBlockMessage::Chunk(chunk.read_chunk().compress()).broadcast_except(&mut server, *id);
+                chunk.write_block_with_state(offset, *block, *state, &registry);
+                update_block_machine(
+                    &mut commands,
+                    &mut chunk,
+                    &registry,
+                    &machines,
+                    chunk_pos,
+                    offset,
+                    *block,
+                );
+                //Scheduled rules for the block get their first tick right after it's placed
+                chunk.schedule_tick(offset, 1);
+                logic.schedule(*pos);
+                let update = ServerBlockMessage::Chunk(chunk.read_chunk().compress());
+                for viewer in viewers.viewers(chunk_pos).filter(|viewer| viewer != id) {
+                    let _ = update.send(&mut server, viewer);
+                }
+                schedule_neighbor_ticks(&loaded_chunks, &comps, *pos);
             } else {
                 warn!("Chunk not loaded on server!");
             }
@@ -86,79 +390,203 @@ fn server_place_blocks(
     }
 }
 
+//Edits wake up the scheduled rules next to them, like fluid flowing into a hole
+fn schedule_neighbor_ticks(loaded_chunks: &ServerLoadedChunks, comps: &Query<&mut ChunkComp>, pos: IVec3) {
+    for dir in Direction::ALL {
+        let (chunk_pos, offset) = Chunk::i_world_to_chunk(pos + dir.offset());
+        if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
+            if let Ok(chunk) = comps.get(*chunk) {
+                chunk.schedule_tick(offset, 1);
+            }
+        }
+    }
+}
+
+//Despawns the machine of whatever block was at offset and spawns one for the new block if it has one
+fn update_block_machine(
+    commands: &mut Commands,
+    chunk: &mut ChunkComp,
+    registry: &BlockRegistry,
+    machines: &MachineRegistry,
+    chunk_pos: IVec3,
+    offset: IVec3,
+    block: Block,
+) {
+    if let Some(old_machine) = chunk.remove_associated_entity(offset) {
+        commands.entity(old_machine).despawn_recursive();
+    }
+    spawn_block_machine(commands, chunk, registry, machines, chunk_pos, offset, block);
+}
+
+fn spawn_block_machine(
+    commands: &mut Commands,
+    chunk: &mut ChunkComp,
+    registry: &BlockRegistry,
+    machines: &MachineRegistry,
+    chunk_pos: IVec3,
+    offset: IVec3,
+    block: Block,
+) {
+    if let Some(name) = &registry.get(block).machine {
+        match machines.spawn_machine(commands, name, block, chunk_pos, offset) {
+            Some(entity) => {
+                chunk.associate_entity(offset, entity);
+            }
+            None => warn!("Block {} uses unknown machine {}", registry.name(block), name),
+        }
+    }
+}
+
+//Spawns the saved machines and fresh ones for any machine blocks that are missing theirs
+fn spawn_chunk_machines(
+    commands: &mut Commands,
+    comp: &mut ChunkComp,
+    registry: &BlockRegistry,
+    machines: &MachineRegistry,
+    saved: SerializedMachines,
+) {
+    let chunk_pos = comp.read_chunk().pos;
+    let spawned = saved.spawn_all(commands, registry, machines, &comp.read_chunk());
+    for (offset, entity) in spawned {
+        comp.associate_entity(offset, entity);
+    }
+
+    let chunk = comp.read_chunk();
+    let has_machine_blocks = chunk
+        .storage()
+        .palette()
+        .iter()
+        .any(|(block, _)| registry.get(*block).machine.is_some());
+    drop(chunk);
+    if !has_machine_blocks {
+        return;
+    }
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let offset = IVec3::new(x as i32, y as i32, z as i32);
+                if !comp.has_associated_entity(offset) {
+                    let block = comp.read_block(offset);
+                    spawn_block_machine(commands, comp, registry, machines, chunk_pos, offset, block);
+                }
+            }
+        }
+    }
+}
+
 //World generation
-fn gen_chunk(chunk_x: i32, chunk_y: i32, chunk_z: i32) -> Chunk {
-    //Check if file, if not then write
-    //FIXME handle windows path encoding
-    let filename = format!("saves/chunk_{}_{}_{}.chunk", chunk_x, chunk_y, chunk_z);
-    let filename = Path::new(&filename);
-
-    if filename.exists() {
-        let chunk_bytes = fs::read(filename).unwrap();
-        Chunk::from_compressed(&chunk_bytes)
-    } else {
-        info!("Creating new chunk {:?}", filename);
-        let mut chunk = Chunk::default();
-        let perlin = Perlin::new();
-
-        for x in 0..CHUNK_SIZE {
-            for y in 0..CHUNK_SIZE {
-                for z in 0..CHUNK_SIZE {
-                    let chunk_x = chunk_x * CHUNK_SIZE as i32;
-                    let chunk_y = chunk_y * CHUNK_SIZE as i32;
-                    let chunk_z = chunk_z * CHUNK_SIZE as i32;
-                    let value = (perlin.get([
-                        (x as f64 + chunk_x as f64) / 21.912,
-                        (y as f64 + chunk_y as f64) / 29.312,
-                        (z as f64 + chunk_z as f64) / 23.253,
-                    ]) + 1.0)
-                        / 2.0
-                        + (0.12
-                            * perlin.get([
-                                (x as f64 + chunk_x as f64) / 3.912,
-                                (y as f64 + chunk_y as f64) / 2.312,
-                                (z as f64 + chunk_z as f64) / 3.253,
-                            ])
-                            + 0.06);
-                    //if value >= (y as f32 / CHUNK_SIZE as f32) as f64 || y == 0 {
-                    if value >= 0.95 {
-                        chunk.cubes[x][y][z] = Block::Grass
-                    }
+fn gen_chunk(
+    chunk_pos: IVec3,
+    regions: &mut RegionStorage,
+    migrations: &SaveMigrations,
+    registry: &BlockRegistry,
+) -> (Chunk, SerializedMachines) {
+    //Check if saved, if not then generate and save
+    match regions.load_chunk_bytes(chunk_pos) {
+        Ok(Some(bytes)) => match load_chunk(&bytes, migrations, registry) {
+            Ok(loaded) => return loaded,
+            Err(err) => {
+                //Keep a copy so the world isn't lost when the chunk gets regenerated and saved over it
+                //FIXME handle windows path encoding
+                let unreadable = format!(
+                    "saves/unreadable_chunk_{}_{}_{}.chunk",
+                    chunk_pos.x, chunk_pos.y, chunk_pos.z
+                );
+                error!(
+                    "Failed to load chunk {}: {}, copying it to {} and generating a new chunk",
+                    chunk_pos, err, unreadable
+                );
+                if let Err(err) = fs::write(&unreadable, &bytes) {
+                    error!("Failed to copy unreadable chunk {}: {}", chunk_pos, err);
+                }
+            }
+        },
+        Ok(None) => {}
+        Err(err) => error!("Failed to read region for chunk {}: {}", chunk_pos, err),
+    }
+
+    //Fresh chunks never have machines
+    let chunk = generate_chunk(chunk_pos, registry);
+    if let Err(err) = regions.save_chunk_bytes(chunk_pos, &save_chunk(&chunk, SerializedMachines::default(), registry))
+    {
+        error!("Failed to save chunk {}: {}", chunk_pos, err);
+    }
+    (chunk, SerializedMachines::default())
+}
+
+fn generate_chunk(chunk_pos: IVec3, registry: &BlockRegistry) -> Chunk {
+    info!("Creating new chunk {}", chunk_pos);
+    let grass = registry.expect("grass");
+    let mut chunk = Chunk::default();
+    let (chunk_x, chunk_y, chunk_z) = (chunk_pos.x, chunk_pos.y, chunk_pos.z);
+    let perlin = Perlin::new();
+
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let chunk_x = chunk_x * CHUNK_SIZE as i32;
+                let chunk_y = chunk_y * CHUNK_SIZE as i32;
+                let chunk_z = chunk_z * CHUNK_SIZE as i32;
+                let value = (perlin.get([
+                    (x as f64 + chunk_x as f64) / 21.912,
+                    (y as f64 + chunk_y as f64) / 29.312,
+                    (z as f64 + chunk_z as f64) / 23.253,
+                ]) + 1.0)
+                    / 2.0
+                    + (0.12
+                        * perlin.get([
+                            (x as f64 + chunk_x as f64) / 3.912,
+                            (y as f64 + chunk_y as f64) / 2.312,
+                            (z as f64 + chunk_z as f64) / 3.253,
+                        ])
+                        + 0.06);
+                //if value >= (y as f32 / CHUNK_SIZE as f32) as f64 || y == 0 {
+                if value >= 0.95 {
+                    chunk.set_block(x, y, z, grass)
                 }
             }
         }
-        //Write to file
-        fs::write(filename, chunk.compress()).unwrap();
-        chunk
     }
+    chunk.pos = chunk_pos;
+    chunk
 }
 
-//FIXME needs to wire up neighbors and stuff..
 fn server_load_chunk(
     commands: &mut Commands,
     loaded_chunks: &mut LoadedChunks,
     chunks: &Query<&ChunkComp>,
+    spawned_this_frame: &mut HashMap<Entity, ChunkComp>,
+    regions: &mut RegionStorage,
+    migrations: &SaveMigrations,
+    registry: &BlockRegistry,
+    machines: &MachineRegistry,
+    logic: &mut LogicQueue,
     chunk_pos: IVec3,
 ) -> CompressedChunk {
     //Check doesn't already exists!
-    match loaded_chunks.ent_map.get(&chunk_pos) {
-        Some(ent) => {
-            info!("I already have this chunk loaded! {:?}", chunk_pos);
-            chunks.get(*ent).unwrap().read_chunk().compress()
-        }
-        None => {
-            info!("Creating new chunk");
-            let mut chunk = gen_chunk(chunk_pos.x, chunk_pos.y, chunk_pos.z);
-            chunk.pos = chunk_pos;
-            let data = chunk.compress();
-
-            let arc = Arc::new(RwLock::new(chunk));
-            let comp = ChunkComp::new(arc);
-            let ent = commands.spawn().insert(comp).id();
-            loaded_chunks.ent_map.insert(chunk_pos, ent);
-            data
-        }
+    if let Some(ent) = loaded_chunks.ent_map.get(&chunk_pos) {
+        info!("I already have this chunk loaded! {:?}", chunk_pos);
+        let chunk = chunks.get(*ent).ok().or_else(|| spawned_this_frame.get(ent)).unwrap();
+        return chunk.read_chunk().compress();
     }
+
+    info!("Creating new chunk");
+    let (mut chunk, saved_machines) = gen_chunk(chunk_pos, regions, migrations, registry);
+    chunk.pos = chunk_pos;
+    let data = chunk.compress();
+    logic.resume_chunk(&chunk, registry);
+
+    let mut comp = ChunkComp::new(Arc::new(RwLock::new(chunk)));
+    spawn_chunk_machines(commands, &mut comp, registry, machines, saved_machines);
+    comp.connect_neighbors(chunk_pos, |pos| {
+        let neighbor = loaded_chunks.ent_map.get(&pos)?;
+        chunks.get(*neighbor).ok().or_else(|| spawned_this_frame.get(neighbor))
+    });
+    //Comps can't be queried until commands are applied so hold onto them for neighbor lookups
+    let ent = commands.spawn().id();
+    spawned_this_frame.insert(ent, comp);
+    loaded_chunks.ent_map.insert(chunk_pos, ent);
+    data
 }
 
 pub fn server_create_chunks(
@@ -167,27 +595,43 @@ pub fn server_create_chunks(
     mut server: ResMut<RenetServer>,
     mut queued_requests: Local<Vec<(u64, IVec3)>>,
     chunks: Query<&ChunkComp>,
-    mut loaded_chunks: ResMut<LoadedChunks>,
+    mut loaded_chunks: ResMut<ServerLoadedChunks>,
+    mut viewers: ResMut<ChunkViewers>,
+    mut regions: ResMut<RegionStorage>,
+    migrations: Res<SaveMigrations>,
+    registry: Res<BlockRegistry>,
+    machines: Res<MachineRegistry>,
+    mut logic: ResMut<LogicQueue>,
 ) {
-    queued_requests.retain(|(id, pos)| {
-        if server.can_send_message(*id, Channel::Block.id()) {
-            info!("Sending Chunk! {}", *pos);
-            let chunk_data = server_load_chunk(&mut commands, &mut loaded_chunks, &chunks, *pos);
-            ServerBlockMessage::Chunk(chunk_data).send(&mut server, *id);
-            return false;
-        }
-        true
-    });
-
     for message in messages.iter() {
         if let (id, ClientMessage::RequestChunk(pos)) = message {
-            if server.can_send_message(*id, Channel::Block.id()) {
-                info!("Sending Chunk! {}", pos);
-                let chunk_data = server_load_chunk(&mut commands, &mut loaded_chunks, &chunks, *pos);
-                ServerBlockMessage::Chunk(chunk_data).send(&mut server, *id);
-            } else {
-                queued_requests.push((*id, *pos));
-            }
+            queued_requests.push((*id, *pos));
         }
     }
+
+    let mut spawned_this_frame = HashMap::default();
+    queued_requests.retain(|(id, pos)| {
+        if !server.can_send_message(*id, Channel::Block.id()) {
+            return true;
+        }
+        info!("Sending Chunk! {}", *pos);
+        viewers.add_viewer(*pos, *id);
+        let chunk_data = server_load_chunk(
+            &mut commands,
+            &mut loaded_chunks,
+            &chunks,
+            &mut spawned_this_frame,
+            &mut regions,
+            &migrations,
+            &registry,
+            &machines,
+            &mut logic,
+            *pos,
+        );
+        let _ = ServerBlockMessage::Chunk(chunk_data).send(&mut server, *id);
+        false
+    });
+    for (ent, comp) in spawned_this_frame.into_iter() {
+        commands.entity(ent).insert(comp);
+    }
 }
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use crate::prelude::*;
+use crate::server::{
+    machine::{BlockController, MachineComp},
+    server_chunks::{PendingBlockUpdates, ServerLoadedChunks},
+};
+
+//Seeds the walk with every chunk a queued position is in, the rest are found through neighbor links
+pub fn linked_chunks(
+    loaded_chunks: &ServerLoadedChunks,
+    comps: &Query<&ChunkComp>,
+    positions: impl Iterator<Item = IVec3>,
+) -> LinkedChunks {
+    let mut world = LinkedChunks::default();
+    for pos in positions {
+        let (chunk_pos, _) = Chunk::i_world_to_chunk(pos);
+        if world.contains_chunk(chunk_pos) {
+            continue;
+        }
+        if let Some(chunk) = loaded_chunks.ent_map.get(&chunk_pos) {
+            if let Ok(chunk) = comps.get(*chunk) {
+                world.add_chunk(chunk.shared());
+            }
+        }
+    }
+    world
+}
+
+pub fn server_interact_blocks(
+    loaded_chunks: Res<ServerLoadedChunks>,
+    comps: Query<&ChunkComp>,
+    messages: Res<CurrentServerMessages>,
+    registry: Res<BlockRegistry>,
+    mut logic: ResMut<LogicQueue>,
+    mut updates: ResMut<PendingBlockUpdates>,
+) {
+    for (id, message) in messages.iter() {
+        if let ClientMessage::InteractBlock(pos) = message {
+            let mut world = linked_chunks(&loaded_chunks, &comps, std::iter::once(*pos));
+            match logic.interact(&mut world, &registry, *pos) {
+                Some(change) => updates.push(change),
+                None => warn!("Client {} interacted with {} which can't be interacted with", id, pos),
+            }
+        }
+    }
+}
+
+// Controller machines by where their block is, kept in order so every tick runs them in the same order
+// Machines are picked up as they spawn and dropped once their entity is gone
+#[derive(Default)]
+pub struct LogicControllers(BTreeMap<[i32; 3], Entity>);
+
+//Runs on LOGIC_TICK_SECONDS, controllers run after the circuit so they see this tick's signals
+pub fn server_logic_tick(
+    loaded_chunks: Res<ServerLoadedChunks>,
+    comps: Query<&ChunkComp>,
+    registry: Res<BlockRegistry>,
+    mut logic: ResMut<LogicQueue>,
+    mut updates: ResMut<PendingBlockUpdates>,
+    mut controllers: ResMut<LogicControllers>,
+    new_machines: Query<(Entity, &BlockController), Added<BlockController>>,
+    mut machines: Query<&mut MachineComp>,
+) {
+    for (entity, controller) in new_machines.iter() {
+        if registry.logic(controller.block) == Some(LogicKind::Controller) {
+            let pos = controller.chunk_pos * CHUNK_SIZE as i32 + controller.offset;
+            controllers.0.insert(pos.to_array(), entity);
+        }
+    }
+    controllers.0.retain(|_, entity| machines.contains(*entity));
+    if logic.is_idle() && controllers.0.is_empty() {
+        return;
+    }
+
+    let positions = logic
+        .queued()
+        .chain(controllers.0.keys().map(|pos| IVec3::from(*pos)))
+        .collect::<Vec<IVec3>>();
+    let mut world = linked_chunks(&loaded_chunks, &comps, positions.into_iter());
+    let mut changes = logic.tick(&mut world, &registry);
+    for (pos, entity) in controllers.0.iter() {
+        if let Ok(mut machine) = machines.get_mut(*entity) {
+            machine.0.logic_tick(&mut LogicContext::new(
+                &mut world,
+                &registry,
+                &mut logic,
+                &mut changes,
+                IVec3::from(*pos),
+            ));
+        }
+    }
+    updates.extend(changes);
+}
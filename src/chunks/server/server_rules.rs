@@ -0,0 +1,48 @@
+use crate::prelude::*;
+use crate::server::{
+    falling_blocks::spawn_falling_body,
+    server_chunks::{PendingBlockUpdates, ServerLoadedChunks},
+};
+
+//A typo in a rule's block name would otherwise just quietly never run
+pub fn check_rule_blocks(rules: Res<RuleEngine>, registry: Res<BlockRegistry>) {
+    for rule in rules.rules() {
+        if registry.id(rule.block).is_none() {
+            warn!("Rule {} runs on unknown block {}", rule.name, rule.block);
+        }
+    }
+}
+
+//Runs on LOGIC_TICK_SECONDS after the circuits, changed blocks are passed on to the circuits for the next tick
+//and detached blocks carry on as falling blocks
+pub fn server_rule_tick(
+    mut commands: Commands,
+    loaded_chunks: Res<ServerLoadedChunks>,
+    comps: Query<&ChunkComp>,
+    registry: Res<BlockRegistry>,
+    mut rules: ResMut<RuleEngine>,
+    mut logic: ResMut<LogicQueue>,
+    mut updates: ResMut<PendingBlockUpdates>,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    let chunks = loaded_chunks
+        .ent_map
+        .values()
+        .filter_map(|chunk| comps.get(*chunk).ok())
+        .map(|chunk| chunk.shared())
+        .collect::<Vec<_>>();
+    let mut world = LinkedChunks::default();
+    for chunk in chunks.iter() {
+        world.add_chunk(chunk.clone());
+    }
+    let tick = rules.tick(&mut world, &chunks, &registry);
+    for (pos, _, _) in tick.changes.iter() {
+        logic.schedule(*pos);
+    }
+    updates.extend(tick.changes);
+    for detached in tick.detached {
+        spawn_falling_body(&mut commands, &[detached]);
+    }
+}
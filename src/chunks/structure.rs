@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+
+use bevy::utils::HashSet;
+
+use crate::prelude::*;
+
+//Structures bigger than this are left standing, the search gives up and treats them as held up
+pub const MAX_ISLAND_BLOCKS: usize = 512;
+
+// The solid blocks connected to start, if none of them connect to an anchor
+// A search that reaches an unloaded chunk or grows past MAX_ISLAND_BLOCKS counts as anchored, nothing falls over
+// what the server can't see. Every block searched is added to visited so a structure touching the broken block on
+// several sides is only searched once
+pub fn find_island(
+    world: &mut LinkedChunks,
+    registry: &BlockRegistry,
+    start: IVec3,
+    visited: &mut HashSet<IVec3>,
+) -> Option<Vec<IVec3>> {
+    match world.block(start) {
+        Some(block) if registry.is_solid(block) && visited.insert(start) => {}
+        _ => return None,
+    }
+    let mut island = Vec::default();
+    let mut queue = VecDeque::from([start]);
+    while let Some(pos) = queue.pop_front() {
+        if registry.is_anchor(world.block(pos)?) {
+            return None;
+        }
+        island.push(pos);
+        if island.len() > MAX_ISLAND_BLOCKS {
+            return None;
+        }
+        for dir in Direction::ALL {
+            let next = pos + dir.offset();
+            if visited.contains(&next) {
+                continue;
+            }
+            match world.block(next) {
+                Some(block) if registry.is_solid(block) => {
+                    visited.insert(next);
+                    queue.push_back(next);
+                }
+                Some(_) => {}
+                None => return None,
+            }
+        }
+    }
+    Some(island)
+}
@@ -7,7 +7,7 @@ use crate::*;
 use bevy::app::AppExit;
 use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
 use bevy_rapier3d::prelude::{
-    Ccd, Collider, Damping, Dominance, ExternalForce, GravityScale, LockedAxes, RigidBody, Velocity,
+    Ccd, Collider, CollisionGroups, Damping, Dominance, ExternalForce, GravityScale, LockedAxes, RigidBody, Velocity,
 };
 
 pub fn create_renet_client(server_addr: SocketAddr) -> RenetClient {
@@ -174,6 +174,7 @@ pub fn spawn_camera(mut commands: Commands) {
             Vec3::new(0.0, 0.0, 0.0),
             0.4,
         ))
+        .insert(CollisionGroups::new(CLIENT_COLLISION_GROUP, CLIENT_COLLISION_GROUP))
         .insert(LockedAxes::ROTATION_LOCKED)
         .insert(Dominance::group(10))
         .insert(GravityScale(0.1))
@@ -11,20 +11,60 @@ pub struct CurrentClientBlockMessages(Vec<ServerBlockMessage>);
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerMessage {
     Pong,
+    //Where the server's falling bodies are, sent unreliably every frame so a lost one is just a skipped frame
+    FallingBodies(Vec<FallingBodySnapshot>),
+}
+
+//Keeps each falling body message well inside a single packet
+pub const MAX_FALLING_BODIES_PER_MESSAGE: usize = 32;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FallingBodySnapshot {
+    //Stays the same for as long as the body is falling
+    pub id: u64,
+    //The body's origin, its blocks are offsets from here
+    pub translation: Vec3,
+    pub rotation: Quat,
 }
 
 //Enum size is the max message size, so big messages need to be handled seperate
 #[derive(Serialize, Deserialize)]
 pub enum ServerBlockMessage {
     Chunk(CompressedChunk),
+    //Changes the server made itself, like signals spreading, in the order they happened
+    BlockUpdates(Vec<BlockChange>),
+    //Reply to opening or uploading a microcontroller program
+    Program(IVec3, ProgramInfo),
+    //A body the server started simulating, with its id, origin and blocks as offsets from the origin
+    FallingBody(u64, Vec3, Vec<BlockChange>),
+    //The server's block names in id order, every block id the server sends or is sent is one of its own
+    //Sent when a client connects and again whenever the server reloads its definitions
+    BlockNames(Vec<String>),
+}
+
+//Uploads have to fit in a single reliable message
+pub const MAX_PROGRAM_BYTES: usize = 2048;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgramInfo {
+    pub source: String,
+    //Why the upload was rejected or why the program stopped
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
     Ping,
     BreakBlock(IVec3),
-    PlaceBlock(IVec3, Block),
+    PlaceBlock(IVec3, Block, BlockState),
+    //Use a block like a lever instead of placing against it
+    InteractBlock(IVec3),
+    //Ask for the program of the microcontroller at a position
+    OpenProgram(IVec3),
+    UploadProgram(IVec3, String),
     RequestChunk(IVec3),
+    //Client dropped the chunk, stop sending it updates
+    UnloadChunk(IVec3),
 }
 
 pub enum SendError {
@@ -69,41 +109,44 @@ impl ServerBlockMessage {
 }
 
 impl ServerMessage {
+    fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::Pong => Channel::Reliable,
+            ServerMessage::FallingBodies(..) => Channel::Unreliable,
+        }
+    }
+
     pub fn send(&self, server: &mut RenetServer, id: u64) -> Result<(), SendError> {
-        if server.can_send_message(id, Channel::Reliable.id()) {
+        let channel = self.channel().id();
+        if !server.can_send_message(id, channel) {
             return Err(SendError::CannotSend);
         }
         let message = bincode::serialize(self).unwrap();
-        match self {
-            ServerMessage::Pong => server.send_message(id, Channel::Reliable.id(), message),
-        }
+        server.send_message(id, channel, message);
         Ok(())
     }
 
     pub fn broadcast(&self, server: &mut RenetServer) -> Result<(), SendError> {
-        //TODO only in debug please
+        let channel = self.channel().id();
         for id in server.clients_id() {
-            if server.can_send_message(id, Channel::Reliable.id()) {
+            if !server.can_send_message(id, channel) {
                 return Err(SendError::CannotSend);
             }
         }
         let message = bincode::serialize(self).unwrap();
-        match self {
-            ServerMessage::Pong => server.broadcast_message(Channel::Reliable.id(), message),
-        }
+        server.broadcast_message(channel, message);
         Ok(())
     }
 
     pub fn broadcast_except(&self, server: &mut RenetServer, id: u64) -> Result<(), SendError> {
-        for id in server.clients_id() {
-            if server.can_send_message(id, Channel::Reliable.id()) {
+        let channel = self.channel().id();
+        for other in server.clients_id() {
+            if other != id && !server.can_send_message(other, channel) {
                 return Err(SendError::CannotSend);
             }
         }
         let message = bincode::serialize(self).unwrap();
-        match self {
-            ServerMessage::Pong => server.broadcast_message_except(id, Channel::Reliable.id(), message),
-        }
+        server.broadcast_message_except(id, channel, message);
         Ok(())
     }
 }
@@ -114,8 +157,12 @@ impl ClientMessage {
         match self {
             ClientMessage::Ping
             | ClientMessage::RequestChunk(..)
+            | ClientMessage::UnloadChunk(..)
             | ClientMessage::BreakBlock(..)
-            | ClientMessage::PlaceBlock(..) => {
+            | ClientMessage::PlaceBlock(..)
+            | ClientMessage::InteractBlock(..)
+            | ClientMessage::OpenProgram(..)
+            | ClientMessage::UploadProgram(..) => {
                 if client.can_send_message(Channel::Reliable.id()) {
                     client.send_message(Channel::Reliable.id(), message);
                     Ok(())
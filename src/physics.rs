@@ -5,6 +5,11 @@ use bevy_rapier3d::prelude::*;
 
 use crate::prelude::*;
 
+//In single player the server's falling blocks share the physics world with the client,
+//each side only collides with its own bodies so nothing gets simulated twice
+pub const CLIENT_COLLISION_GROUP: u32 = 1 << 0;
+pub const SERVER_COLLISION_GROUP: u32 = 1 << 1;
+
 pub struct PhysicsPlugin;
 
 impl Plugin for PhysicsPlugin {
@@ -39,6 +44,7 @@ fn test_physics(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut ma
         .insert(LockedAxes::ROTATION_LOCKED)
         .insert(RigidBody::Dynamic)
         .insert(Collider::capsule(Vec3::new(0.0, 0.7, 0.0), Vec3::splat(0.0), 0.4))
+        .insert(CollisionGroups::new(CLIENT_COLLISION_GROUP, CLIENT_COLLISION_GROUP))
         .insert(GravityScale(0.1))
         .insert(Restitution::coefficient(0.7));
 }
@@ -137,23 +143,31 @@ fn cursor_grab(keys: Res<Input<KeyCode>>, mut windows: ResMut<Windows>) {
 }
 
 pub fn add_collider(commands: &mut Commands, entity: Entity, desc: MeshDescription) {
+    set_collider(commands, entity, create_collider(desc));
+}
+
+//For colliders already built off the main thread
+pub fn set_collider(commands: &mut Commands, entity: Entity, collider: Option<Collider>) {
     //FIXME this seems to not work if the entity did not already have a collider
     // Is rapier caching something? can I add a disabled collider to work around this
-    if let Some(new_collider) = create_collider(desc) {
-        commands.entity(entity).insert(new_collider);
+    if let Some(new_collider) = collider {
+        commands
+            .entity(entity)
+            .insert(new_collider)
+            .insert(CollisionGroups::new(CLIENT_COLLISION_GROUP, CLIENT_COLLISION_GROUP));
     } else {
         commands.entity(entity).remove::<Collider>();
     }
 }
 
 pub fn create_collider(desc: MeshDescription) -> Option<Collider> {
-    let tri_count = desc.vert_indicies.len() / 3;
+    let tri_count = desc.collider_indicies.len() / 3;
     let mut indices = Vec::with_capacity(tri_count);
     for index in 0..tri_count {
         indices.push([
-            desc.vert_indicies[index * 3] as u32,
-            desc.vert_indicies[index * 3 + 1] as u32,
-            desc.vert_indicies[index * 3 + 2] as u32,
+            desc.collider_indicies[index * 3] as u32,
+            desc.collider_indicies[index * 3 + 1] as u32,
+            desc.collider_indicies[index * 3 + 2] as u32,
         ]);
     }
     if tri_count > 0 {
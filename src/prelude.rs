@@ -7,10 +7,28 @@ pub use crate::physics::*;
 pub use crate::*;
 
 pub const CHUNK_SIZE: usize = 16;
-pub const WORLD_SIZE: usize = 2;
+//Chunks are streamed in around the camera and unloaded past UNLOAD_DISTANCE, the gap avoids thrashing at the edge
+pub const VIEW_DISTANCE: i32 = 2;
+pub const UNLOAD_DISTANCE: i32 = VIEW_DISTANCE + 1;
+//Chunks at least this many chunks from the camera are meshed at a half, a quarter and an eighth of the detail
+pub const LOD_DISTANCES: [i32; 3] = [lod_distance(1), lod_distance(2), lod_distance(3)];
+//Spread over VIEW_DISTANCE so lower detail always shows, but the camera's neighbors stay full detail for their colliders
+const fn lod_distance(step: i32) -> i32 {
+    let spread = VIEW_DISTANCE * step / 4 + 1;
+    let nearest = step + 1;
+    if spread > nearest {
+        spread
+    } else {
+        nearest
+    }
+}
+pub const MAX_CHUNK_REQUESTS_PER_FRAME: usize = 16;
+//Seconds a chunk no client is viewing stays loaded on the server before being saved and evicted
+pub const CHUNK_EVICTION_SECONDS: f64 = 30.0;
 pub const MAX_CHUNK_UPDATES_PER_FRAME: usize = 30;
+//Circuits update on a fixed tick so they run at the same speed at any frame rate
+pub const LOGIC_TICK_SECONDS: f64 = 0.05;
 
-//XXX maybe a memory leak because unloaded chunks are never removed
 // Not a very robust design
 #[derive(Default)]
 pub struct LoadedChunks {